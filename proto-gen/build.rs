@@ -0,0 +1,79 @@
+//! Records the versions of the code generators we link for `--version`.
+//! A build script can't tell which versions cargo resolved, a lockfile next to the sources
+//! isn't necessarily the one used, such as with `cargo install` without `--locked`. The
+//! version requirements from the manifest are recorded instead, any semver compatible version
+//! may be linked.
+use std::path::Path;
+
+fn main() {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR is set");
+    // The requirements are in the workspace manifest when building from the repo, and in the
+    // normalized manifest of the package when installed
+    let manifests = Path::new(&manifest_dir)
+        .ancestors()
+        .map(|dir| dir.join("Cargo.toml"))
+        .filter(|manifest| manifest.is_file())
+        .collect::<Vec<_>>();
+    let contents = manifests
+        .iter()
+        .filter_map(|manifest| {
+            println!("cargo:rerun-if-changed={}", manifest.display());
+            std::fs::read_to_string(manifest).ok()
+        })
+        .collect::<Vec<_>>();
+    for (package, var) in [
+        ("tonic-build", "PROTO_GEN_TONIC_BUILD_VERSION"),
+        ("prost-build", "PROTO_GEN_PROST_BUILD_VERSION"),
+    ] {
+        let requirement = contents
+            .iter()
+            .find_map(|content| version_requirement(content, package))
+            .map_or_else(
+                || "unknown".to_string(),
+                |requirement| {
+                    if requirement.starts_with(|c: char| c.is_ascii_digit()) {
+                        format!("^{requirement}")
+                    } else {
+                        requirement.to_string()
+                    }
+                },
+            );
+        println!("cargo:rustc-env={var}={requirement}");
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+/// The version requirement of `package` in a manifest, either `package = "req"`,
+/// `package = { version = "req", .. }` or a `[dependencies.package]` table
+fn version_requirement<'a>(manifest: &'a str, package: &str) -> Option<&'a str> {
+    let inline = format!("{package} = ");
+    let tables = [
+        format!("[dependencies.{package}]"),
+        format!("[workspace.dependencies.{package}]"),
+    ];
+    let mut lines = manifest.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        if let Some(value) = line.strip_prefix(&inline) {
+            if let Some(requirement) = quoted(value).or_else(|| version_key(value)) {
+                return Some(requirement);
+            }
+        } else if tables.iter().any(|table| line == table) {
+            return lines
+                .take_while(|line| !line.starts_with('['))
+                .find_map(version_key);
+        }
+    }
+    None
+}
+
+/// The value of a `version = "req"` key in the text
+fn version_key(text: &str) -> Option<&str> {
+    let (_, rest) = text.split_once("version = ")?;
+    quoted(rest)
+}
+
+fn quoted(value: &str) -> Option<&str> {
+    let rest = value.strip_prefix('"')?;
+    let end = rest.find('"')?;
+    Some(&rest[..end])
+}
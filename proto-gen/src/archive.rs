@@ -0,0 +1,85 @@
+//! Packs generated code into a zip archive instead of writing it into the source tree
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// An output dir to archive, its name, the dir with its generated code and its module file
+pub(crate) struct ArchivedOutput<'a> {
+    pub name: String,
+    pub generated_dir: &'a Path,
+    pub top_mod_content: String,
+}
+
+/// Writes each output as `<name>.rs` and `<name>/...` at the root of the zip `archive`,
+/// entries are sorted and timestamped the same on every run.
+pub(crate) fn write(archive: &Path, outputs: &[ArchivedOutput]) -> Result<(), String> {
+    let file =
+        File::create(archive).map_err(|e| format!("Failed to create archive {archive:?} \n{e}"))?;
+    let mut zip = ZipWriter::new(file);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    for output in outputs {
+        add(
+            &mut zip,
+            options,
+            &format!("{}.rs", output.name),
+            output.top_mod_content.as_bytes(),
+        )?;
+        let mut files = vec![];
+        collect_files(output.generated_dir, &mut files)?;
+        files.sort();
+        for path in files {
+            let relative = path.strip_prefix(output.generated_dir).map_err(|e| {
+                format!(
+                    "Failed to make {path:?} relative to {:?} \n{e}",
+                    output.generated_dir
+                )
+            })?;
+            let name = std::iter::once(output.name.clone())
+                .chain(
+                    relative
+                        .components()
+                        .map(|component| component.as_os_str().to_string_lossy().into_owned()),
+                )
+                .collect::<Vec<_>>()
+                .join("/");
+            let content = fs::read(&path)
+                .map_err(|e| format!("Failed to read generated file {path:?} to archive \n{e}"))?;
+            add(&mut zip, options, &name, &content)?;
+        }
+    }
+    zip.finish()
+        .map_err(|e| format!("Failed to finish archive {archive:?} \n{e}"))?;
+    Ok(())
+}
+
+fn add(
+    zip: &mut ZipWriter<File>,
+    options: SimpleFileOptions,
+    name: &str,
+    content: &[u8],
+) -> Result<(), String> {
+    zip.start_file(name, options)
+        .map_err(|e| format!("Failed to start archive entry {name} \n{e}"))?;
+    zip.write_all(content)
+        .map_err(|e| format!("Failed to write archive entry {name} \n{e}"))
+}
+
+/// All files, not only Rust code, so that additional outputs like `--openapi` are kept
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    for entry in
+        fs::read_dir(dir).map_err(|e| format!("Failed to read dir {dir:?} to archive \n{e}"))?
+    {
+        let path = entry
+            .map_err(|e| format!("Failed to read entry in {dir:?} to archive \n{e}"))?
+            .path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
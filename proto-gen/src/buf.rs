@@ -0,0 +1,103 @@
+//! Lints the protos with `buf` before generating
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::gen::{ProtoWorkspace, Verbosity};
+
+/// Runs `buf lint` on each proto dir containing proto files to generate, a missing `buf`
+/// skips linting.
+/// # Errors
+/// If `buf` could not be run or reports lint failures
+pub fn lint(ws: &ProtoWorkspace, verbosity: Verbosity) -> Result<(), String> {
+    lint_with("buf", &linted_dirs(ws), verbosity)
+}
+
+/// The innermost proto dir of each proto file, so nested dirs aren't linted twice. Dirs only
+/// included for imports, such as dependencies or the well-known types, aren't the
+/// workspace's to fix.
+fn linted_dirs(ws: &ProtoWorkspace) -> Vec<PathBuf> {
+    let innermost = ws
+        .proto_files
+        .iter()
+        .filter_map(|file| {
+            ws.proto_dirs
+                .iter()
+                .filter(|dir| file.starts_with(dir))
+                .max_by_key(|dir| dir.components().count())
+        })
+        .collect::<Vec<_>>();
+    ws.proto_dirs
+        .iter()
+        .filter(|dir| innermost.contains(dir))
+        .cloned()
+        .collect()
+}
+
+fn lint_with(program: &str, proto_dirs: &[PathBuf], verbosity: Verbosity) -> Result<(), String> {
+    for proto_dir in proto_dirs {
+        let out = match Command::new(program).arg("lint").arg(proto_dir).output() {
+            Ok(out) => out,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                if !verbosity.is_quiet() {
+                    println!("Skipping --buf-lint, `{program}` was not found on the path");
+                }
+                return Ok(());
+            }
+            Err(e) => return Err(format!("Failed to run buf lint on {proto_dir:?} \n{e}")),
+        };
+        if !out.status.success() {
+            return Err(format!(
+                "buf lint failed on {proto_dir:?} with {} \n{}{}",
+                out.status,
+                String::from_utf8_lossy(&out.stdout),
+                String::from_utf8_lossy(&out.stderr)
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::buf::{lint_with, linted_dirs};
+    use crate::gen::{ProtoWorkspace, Verbosity};
+    use std::path::PathBuf;
+
+    #[test]
+    fn only_dirs_with_generated_protos_linted() {
+        let ws = ProtoWorkspace {
+            proto_dirs: vec![
+                PathBuf::from("proto"),
+                PathBuf::from("proto/nested"),
+                PathBuf::from("deps"),
+                PathBuf::from("well_known"),
+            ],
+            proto_files: vec![
+                PathBuf::from("proto/my.proto"),
+                PathBuf::from("proto/nested/other.proto"),
+                PathBuf::from("proto/nested/third.proto"),
+            ],
+            tmp_dir: PathBuf::from("tmp"),
+            output_dir: PathBuf::from("proto_types"),
+            package_outputs: vec![],
+            image: None,
+            openapi: None,
+            archive: None,
+            mod_file: None,
+        };
+        assert_eq!(
+            vec![PathBuf::from("proto"), PathBuf::from("proto/nested")],
+            linted_dirs(&ws)
+        );
+    }
+
+    #[test]
+    fn lint_failures_abort_and_missing_buf_skips() {
+        let dirs = [PathBuf::from("proto")];
+        assert!(lint_with("true", &dirs, Verbosity::Quiet).is_ok());
+        let err = lint_with("false", &dirs, Verbosity::Quiet).unwrap_err();
+        assert!(err.contains("buf lint failed"), "{err}");
+        assert!(lint_with("proto-gen-missing-buf", &dirs, Verbosity::Quiet).is_ok());
+    }
+}
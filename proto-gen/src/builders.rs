@@ -0,0 +1,305 @@
+//! Builders with chainable setters for generated messages
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use heck::ToUpperCamelCase;
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, FieldDescriptorProto, FileDescriptorSet};
+
+use crate::conversions::{field_ident, package_of, rust_path};
+use crate::openapi::{is_map_entry, messages_by_name};
+
+/// Code for a `builders` module per package, keyed by the package, with a `<Message>Builder`
+/// for each message matching one of the proto `paths`. Passing `.` targets all messages.
+/// Nested messages get builders named after their parents, like `OuterInnerBuilder`.
+pub(crate) fn generate(fds: &FileDescriptorSet, paths: &[String]) -> BTreeMap<String, String> {
+    let messages = messages_by_name(fds);
+    let mut modules = BTreeMap::<String, String>::new();
+    for file in &fds.file {
+        let proto2 = matches!(file.syntax(), "" | "proto2");
+        let mut pending = file
+            .message_type
+            .iter()
+            .map(|message| (message.name().to_string(), message))
+            .collect::<Vec<_>>();
+        while let Some((relative, message)) = pending.pop() {
+            pending.extend(
+                message
+                    .nested_type
+                    .iter()
+                    .filter(|nested| !is_map_entry(nested))
+                    .map(|nested| (format!("{relative}.{}", nested.name()), nested)),
+            );
+            let proto = if file.package().is_empty() {
+                format!(".{relative}")
+            } else {
+                format!(".{}.{relative}", file.package())
+            };
+            if !paths.iter().any(|path| matches_path(&proto, path)) {
+                continue;
+            }
+            let module = modules.entry(file.package().to_string()).or_default();
+            let ctx = Context {
+                fds,
+                messages: &messages,
+                package: file.package(),
+                proto2,
+            };
+            write_builder(module, &relative, message, &ctx);
+        }
+    }
+    modules
+        .into_iter()
+        .map(|(package, builders)| {
+            (
+                package,
+                format!("/// Builders for the messages of this package\n#[allow(clippy::useless_conversion)]\npub mod builders {{\n{builders}}}\n"),
+            )
+        })
+        .collect()
+}
+
+/// Whether the fully qualified `proto` message is targeted by `path`, like prost matches
+/// the paths of type attributes
+pub(crate) fn matches_path(proto: &str, path: &str) -> bool {
+    if path == "." {
+        return true;
+    }
+    if path.starts_with('.') {
+        proto == path
+            || proto
+                .strip_prefix(path)
+                .is_some_and(|rest| rest.starts_with('.'))
+    } else {
+        proto
+            .strip_suffix(path)
+            .is_some_and(|rest| rest.ends_with('.'))
+    }
+}
+
+/// What the builders of a package are generated with
+struct Context<'a> {
+    fds: &'a FileDescriptorSet,
+    messages: &'a HashMap<String, &'a DescriptorProto>,
+    /// Package the builders are generated into
+    package: &'a str,
+    /// Whether the message's file is proto2, where optional scalars are `Option`s
+    proto2: bool,
+}
+
+fn write_builder(module: &mut String, relative: &str, message: &DescriptorProto, ctx: &Context) {
+    let message_type = rust_path(relative);
+    let builder = format!(
+        "{}Builder",
+        relative
+            .split('.')
+            .map(ToUpperCamelCase::to_upper_camel_case)
+            .collect::<String>()
+    );
+    let _ = write!(
+        module,
+        "    /// Builder for [`{message_type}`]
+    #[derive(Clone, Debug, Default)]
+    pub struct {builder}({message_type});
+
+    impl {message_type} {{
+        /// Starts building this message from its default
+        #[must_use]
+        pub fn builder() -> {builder} {{
+            {builder}::default()
+        }}
+    }}
+
+    impl {builder} {{
+"
+    );
+    for field in &message.field {
+        match field.oneof_index {
+            // Proto3 optional fields are synthetic oneofs, but plain `Option`s in Rust
+            Some(_) if !field.proto3_optional() => {}
+            _ => write_setters(module, field, ctx),
+        }
+    }
+    // Oneofs are set as a whole with the enum prost generates for them
+    let oneof_module = relative
+        .split('.')
+        .map(field_ident)
+        .collect::<Vec<_>>()
+        .join("::");
+    for (index, oneof) in message.oneof_decl.iter().enumerate() {
+        let synthetic = message
+            .field
+            .iter()
+            .all(|field| field.oneof_index != i32::try_from(index).ok() || field.proto3_optional());
+        if synthetic {
+            continue;
+        }
+        let name = field_ident(oneof.name());
+        let _ = write!(
+            module,
+            "        #[must_use]
+        pub fn {name}(mut self, value: super::{oneof_module}::{}) -> Self {{
+            self.0.{name} = Some(value);
+            self
+        }}
+
+",
+            oneof.name().to_upper_camel_case()
+        );
+    }
+    let _ = write!(
+        module,
+        "        #[must_use]
+        pub fn build(self) -> {message_type} {{
+            self.0
+        }}
+    }}
+
+"
+    );
+}
+
+fn write_setters(module: &mut String, field: &FieldDescriptorProto, ctx: &Context) {
+    let name = field_ident(field.name());
+    // Prefixed names don't need to be raw identifiers
+    let plain = name.trim_start_matches("r#");
+    let mut setter = |signature: String, body: String| {
+        let _ = write!(
+            module,
+            "        #[must_use]
+        pub fn {signature} -> Self {{
+            {body};
+            self
+        }}
+
+"
+        );
+    };
+    if field.label() == Label::Repeated {
+        if let Some(entry) = ctx
+            .messages
+            .get(field.type_name())
+            .filter(|m| is_map_entry(m))
+        {
+            let key = entry.field.iter().find(|f| f.number() == 1);
+            let value = entry.field.iter().find(|f| f.number() == 2);
+            if let (Some(key), Some(value)) = (key, value) {
+                let (key_type, key_conversion) = parameter(key, "key", ctx);
+                let (value_type, value_conversion) = parameter(value, "value", ctx);
+                setter(
+                    format!("insert_{plain}(mut self, key: {key_type}, value: {value_type})"),
+                    format!("self.0.{name}.insert({key_conversion}, {value_conversion})"),
+                );
+            }
+            return;
+        }
+        let (value_type, conversion) = parameter(field, "value", ctx);
+        setter(
+            format!("push_{plain}(mut self, value: {value_type})"),
+            format!("self.0.{name}.push({conversion})"),
+        );
+        setter(
+            format!(
+                "{name}(mut self, values: impl IntoIterator<Item = {}>)",
+                element_type(field, ctx)
+            ),
+            if field.r#type() == Type::Enum {
+                format!("self.0.{name}.extend(values.into_iter().map(i32::from))")
+            } else {
+                format!("self.0.{name}.extend(values)")
+            },
+        );
+        return;
+    }
+    let (value_type, conversion) = parameter(field, "value", ctx);
+    let optional = field.r#type() == Type::Message
+        || field.proto3_optional()
+        || (ctx.proto2 && field.label() == Label::Optional);
+    let conversion = if matches!(field.r#type(), Type::Message | Type::Group) {
+        // Into a `Box` for recursive messages, which prost boxes
+        format!("Some({conversion}.into())")
+    } else if optional {
+        format!("Some({conversion})")
+    } else {
+        conversion
+    };
+    setter(
+        format!("{name}(mut self, value: {value_type})"),
+        format!("self.0.{name} = {conversion}"),
+    );
+}
+
+/// Type of a setter parameter for a single value of `field` and how it's converted to the
+/// field's type, enums are taken as the enum
+fn parameter(field: &FieldDescriptorProto, name: &str, ctx: &Context) -> (String, String) {
+    let element = element_type(field, ctx);
+    match field.r#type() {
+        Type::String | Type::Bytes => (
+            format!("impl ::core::convert::Into<{element}>"),
+            format!("{name}.into()"),
+        ),
+        Type::Enum => (element, format!("{name}.into()")),
+        _ => (element, name.to_string()),
+    }
+}
+
+/// Rust type of a single value of `field`, enums are the enum instead of its `i32`
+fn element_type(field: &FieldDescriptorProto, ctx: &Context) -> String {
+    match field.r#type() {
+        Type::Double => "f64".to_string(),
+        Type::Float => "f32".to_string(),
+        Type::Int32 | Type::Sint32 | Type::Sfixed32 => "i32".to_string(),
+        Type::Int64 | Type::Sint64 | Type::Sfixed64 => "i64".to_string(),
+        Type::Uint32 | Type::Fixed32 => "u32".to_string(),
+        Type::Uint64 | Type::Fixed64 => "u64".to_string(),
+        Type::Bool => "bool".to_string(),
+        Type::String => "::prost::alloc::string::String".to_string(),
+        Type::Bytes => "::prost::alloc::vec::Vec<u8>".to_string(),
+        Type::Enum | Type::Message | Type::Group => type_path(field.type_name(), ctx),
+    }
+}
+
+/// Path to a type from a `builders` module, going up to the crate's generated root and down
+/// the package modules, since the type may be in another package
+fn type_path(type_name: &str, ctx: &Context) -> String {
+    let package = package_of(ctx.fds, type_name).unwrap_or_default();
+    let relative = &type_name[1..];
+    let relative = relative
+        .strip_prefix(package)
+        .and_then(|rest| rest.strip_prefix('.'))
+        .unwrap_or(relative);
+    let path = rust_path(relative);
+    if package == ctx.package {
+        return path;
+    }
+    let depth = if ctx.package.is_empty() {
+        0
+    } else {
+        ctx.package.split('.').count()
+    };
+    let mut full = "super::".repeat(depth + 1);
+    for segment in package.split('.').filter(|segment| !segment.is_empty()) {
+        full.push_str(&field_ident(segment));
+        full.push_str("::");
+    }
+    full.push_str(path.trim_start_matches("super::"));
+    full
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builders::matches_path;
+
+    #[test]
+    fn matches_paths_like_prost() {
+        assert!(matches_path(".my_proto.TestMessage", "."));
+        assert!(matches_path(".my_proto.TestMessage", ".my_proto"));
+        assert!(matches_path(
+            ".my_proto.TestMessage",
+            ".my_proto.TestMessage"
+        ));
+        assert!(matches_path(".my_proto.TestMessage", "TestMessage"));
+        assert!(!matches_path(".my_proto.TestMessage", ".my_prot"));
+        assert!(!matches_path(".my_proto.TestMessage", "Message"));
+    }
+}
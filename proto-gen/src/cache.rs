@@ -0,0 +1,258 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use prost::Message;
+use prost_types::FileDescriptorSet;
+
+use crate::gen::{find_protoc, has_ext, module_file, ProtoWorkspace};
+
+/// Fingerprint of everything that affects generation, the proto inputs, the options used
+/// and the current generated output, so that tampering with the output is also caught.
+/// The hash is not guaranteed to be stable across Rust versions, which at worst causes a
+/// cache miss.
+pub(crate) fn fingerprint(ws: &ProtoWorkspace, options: &str) -> Result<u64, String> {
+    let mut hasher = DefaultHasher::new();
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+    options.hash(&mut hasher);
+    ws.output_dir.hash(&mut hasher);
+    ws.package_outputs.hash(&mut hasher);
+    ws.openapi.hash(&mut hasher);
+    ws.mod_file.hash(&mut hasher);
+    hash_protos(&mut hasher, ws)?;
+    let package_output_dirs = ws.package_outputs.iter().map(|(_, dir)| dir);
+    for output_dir in std::iter::once(&ws.output_dir).chain(package_output_dirs) {
+        hash_tree(&mut hasher, output_dir, "rs")?;
+        hash_file(&mut hasher, &module_file(ws, output_dir)?)?;
+    }
+    Ok(hasher.finish())
+}
+
+/// Fingerprint of the proto inputs and of the protoc used, telling whether a file descriptor
+/// set protoc wrote for them is still up to date
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ProtoFingerprint {
+    protos: u64,
+    /// `None` without a protoc to run
+    protoc: Option<u64>,
+}
+
+pub(crate) fn proto_fingerprint(ws: &ProtoWorkspace) -> Result<ProtoFingerprint, String> {
+    let mut hasher = DefaultHasher::new();
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+    hash_protos(&mut hasher, ws)?;
+    let protoc = find_protoc().map(|protoc| {
+        let mut hasher = DefaultHasher::new();
+        protoc.hash(&mut hasher);
+        hasher.finish()
+    });
+    Ok(ProtoFingerprint {
+        protos: hasher.finish(),
+        protoc,
+    })
+}
+
+fn hash_protos(hasher: &mut DefaultHasher, ws: &ProtoWorkspace) -> Result<(), String> {
+    ws.proto_dirs.hash(hasher);
+    ws.proto_files.hash(hasher);
+    for proto_file in ws.proto_files.iter().chain(&ws.image) {
+        hash_file(hasher, proto_file)?;
+    }
+    for proto_dir in &ws.proto_dirs {
+        hash_tree(hasher, proto_dir, "proto")?;
+    }
+    Ok(())
+}
+
+/// Whether the cache file contains the given fingerprint
+pub(crate) fn is_fresh(cache: &Path, fingerprint: u64) -> bool {
+    fs::read_to_string(cache).is_ok_and(|content| content.trim() == format!("{fingerprint:016x}"))
+}
+
+pub(crate) fn store(cache: &Path, fingerprint: u64) -> Result<(), String> {
+    fs::write(cache, format!("{fingerprint:016x}\n"))
+        .map_err(|e| format!("Failed to write cache file {cache:?} \n{e}"))
+}
+
+/// Where the fingerprint of the protos a cached file descriptor set was written for is kept
+fn descriptors_fingerprint_file(descriptors: &Path) -> PathBuf {
+    let mut path = descriptors.as_os_str().to_owned();
+    path.push(".fingerprint");
+    PathBuf::from(path)
+}
+
+/// Whether the file descriptor set at `descriptors` was written for protos with the given
+/// fingerprint. Without a protoc to run, the protoc it was written with doesn't matter.
+pub(crate) fn descriptors_fresh(descriptors: &Path, fingerprint: ProtoFingerprint) -> bool {
+    if !descriptors.is_file() {
+        return false;
+    }
+    let Ok(content) = fs::read_to_string(descriptors_fingerprint_file(descriptors)) else {
+        return false;
+    };
+    let mut lines = content.lines();
+    let protos_fresh = lines.next() == Some(format!("{:016x}", fingerprint.protos).as_str());
+    match fingerprint.protoc {
+        Some(protoc) => protos_fresh && lines.next() == Some(format!("{protoc:016x}").as_str()),
+        None => protos_fresh,
+    }
+}
+
+/// Writes the file descriptor set to `descriptors` along with the fingerprint of its protos
+pub(crate) fn store_descriptors(
+    descriptors: &Path,
+    fds: &FileDescriptorSet,
+    fingerprint: ProtoFingerprint,
+) -> Result<(), String> {
+    fs::write(descriptors, fds.encode_to_vec())
+        .map_err(|e| format!("Failed to write file descriptor set to {descriptors:?} \n{e}"))?;
+    let protoc = fingerprint
+        .protoc
+        .map(|protoc| format!("{protoc:016x}\n"))
+        .unwrap_or_default();
+    let content = format!("{:016x}\n{protoc}", fingerprint.protos);
+    let path = descriptors_fingerprint_file(descriptors);
+    fs::write(&path, content).map_err(|e| format!("Failed to write cache file {path:?} \n{e}"))
+}
+
+fn hash_file(hasher: &mut DefaultHasher, path: &Path) -> Result<(), String> {
+    path.hash(hasher);
+    match fs::read(path) {
+        Ok(content) => content.hash(hasher),
+        // A missing file is a valid state to fingerprint, for example before first generation
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(format!(
+                "Failed to read {path:?} for cache fingerprint \n{e}"
+            ))
+        }
+    }
+    Ok(())
+}
+
+fn hash_tree(hasher: &mut DefaultHasher, dir: &Path, ext: &str) -> Result<(), String> {
+    let mut files = vec![];
+    collect_with_ext(dir, ext, &mut files)?;
+    files.sort();
+    for file in files {
+        hash_file(hasher, &file)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn collect_with_ext(
+    dir: &Path,
+    ext: &str,
+    files: &mut Vec<PathBuf>,
+) -> Result<(), String> {
+    let rd = match fs::read_dir(dir) {
+        Ok(rd) => rd,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(format!(
+                "Failed to read dir {dir:?} for cache fingerprint \n{e}"
+            ))
+        }
+    };
+    for entry in rd {
+        let path = entry
+            .map_err(|e| format!("Failed to read entry in {dir:?} for cache fingerprint \n{e}"))?
+            .path();
+        if path.is_dir() {
+            collect_with_ext(&path, ext, files)?;
+        } else if has_ext(&path, ext) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::{
+        descriptors_fresh, fingerprint, is_fresh, proto_fingerprint, store, store_descriptors,
+        ProtoFingerprint,
+    };
+    use crate::gen::ProtoWorkspace;
+
+    #[test]
+    fn fingerprint_changes_with_inputs_and_options() {
+        let base = tempfile::tempdir().unwrap();
+        let proto_dir = base.path().join("proto");
+        std::fs::create_dir(&proto_dir).unwrap();
+        let proto_file = proto_dir.join("my.proto");
+        std::fs::write(&proto_file, "syntax = \"proto3\";").unwrap();
+        let ws = ProtoWorkspace {
+            proto_dirs: vec![proto_dir],
+            proto_files: vec![proto_file.clone()],
+            tmp_dir: base.path().join("tmp"),
+            output_dir: base.path().join("proto_types"),
+            package_outputs: vec![],
+            image: None,
+            openapi: None,
+            archive: None,
+            mod_file: None,
+        };
+        let first = fingerprint(&ws, "options").unwrap();
+        assert_eq!(first, fingerprint(&ws, "options").unwrap());
+        assert_ne!(first, fingerprint(&ws, "other options").unwrap());
+        std::fs::write(&proto_file, "syntax = \"proto2\";").unwrap();
+        assert_ne!(first, fingerprint(&ws, "options").unwrap());
+    }
+
+    #[test]
+    fn descriptors_fresh_for_their_protos() {
+        let base = tempfile::tempdir().unwrap();
+        let proto_file = base.path().join("my.proto");
+        std::fs::write(&proto_file, "syntax = \"proto3\";").unwrap();
+        let ws = ProtoWorkspace {
+            proto_dirs: vec![],
+            proto_files: vec![proto_file.clone()],
+            tmp_dir: base.path().join("tmp"),
+            output_dir: base.path().join("proto_types"),
+            package_outputs: vec![],
+            image: None,
+            openapi: None,
+            archive: None,
+            mod_file: None,
+        };
+        let descriptors = base.path().join("descriptors.binpb");
+        let first = proto_fingerprint(&ws).unwrap();
+        assert!(!descriptors_fresh(&descriptors, first));
+        store_descriptors(
+            &descriptors,
+            &prost_types::FileDescriptorSet::default(),
+            first,
+        )
+        .unwrap();
+        assert!(descriptors_fresh(&descriptors, first));
+        // Fresh without a protoc to run, but not with another one
+        let without_protoc = ProtoFingerprint {
+            protoc: None,
+            ..first
+        };
+        assert!(descriptors_fresh(&descriptors, without_protoc));
+        let other_protoc = ProtoFingerprint {
+            protoc: Some(first.protoc.unwrap_or_default().wrapping_add(1)),
+            ..first
+        };
+        assert!(!descriptors_fresh(&descriptors, other_protoc));
+        std::fs::write(&proto_file, "syntax = \"proto2\";").unwrap();
+        assert!(!descriptors_fresh(
+            &descriptors,
+            proto_fingerprint(&ws).unwrap()
+        ));
+    }
+
+    #[test]
+    fn fresh_after_store() {
+        let base = tempfile::tempdir().unwrap();
+        let cache = base.path().join("cache");
+        assert!(!is_fresh(&cache, 5));
+        store(&cache, 5).unwrap();
+        assert!(is_fresh(&cache, 5));
+        assert!(!is_fresh(&cache, 6));
+    }
+}
@@ -0,0 +1,74 @@
+//! Compiles generated code in a scratch crate to verify that it builds
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Dependencies of the scratch crate, matching the versions that our `tonic-build`
+/// and `prost-build` generate code for.
+const CHECK_DEPENDENCIES: &str = r#"prost = "0.12"
+prost-types = "0.12"
+tonic = "0.10"
+"#;
+
+/// Dependencies of the scratch crate for `no_std` code, which can't contain tonic services.
+const NO_STD_CHECK_DEPENDENCIES: &str = r#"prost = { version = "0.12", default-features = false, features = ["prost-derive"] }
+prost-types = { version = "0.12", default-features = false }
+"#;
+
+/// Runs `cargo check` on a scratch crate containing the generated code,
+/// `cargo` needs to be on the path and able to resolve the dependencies.
+/// With `no_std` the scratch crate is `#![no_std]`, only `alloc` is available.
+/// With `deny_warnings` any warning in the generated code fails the check.
+/// # Errors
+/// If the scratch crate could not be created or `cargo check` reports errors
+pub fn check_compiles(
+    generated_dir: &Path,
+    top_mod_content: &str,
+    no_std: bool,
+    deny_warnings: bool,
+) -> Result<(), String> {
+    let scratch = tempfile::tempdir()
+        .map_err(|e| format!("Failed to create scratch crate dir to check compilation \n{e}"))?;
+    let src = scratch.path().join("src");
+    fs::create_dir_all(&src)
+        .map_err(|e| format!("Failed to create scratch crate src dir {src:?} \n{e}"))?;
+    let (dependencies, mut lib) = if no_std {
+        (NO_STD_CHECK_DEPENDENCIES, "#![no_std]\n".to_string())
+    } else {
+        (CHECK_DEPENDENCIES, String::new())
+    };
+    if deny_warnings {
+        // Unlike `RUSTFLAGS`, only applies to the generated code and not the dependencies
+        lib.push_str("#![deny(warnings)]\n");
+    }
+    lib.push_str("pub mod generated;\n");
+    fs::write(
+        scratch.path().join("Cargo.toml"),
+        format!(
+            "[package]\nname = \"proto-gen-check\"\nversion = \"0.0.0\"\nedition = \"2021\"\npublish = false\n\n[workspace]\n\n[dependencies]\n{dependencies}"
+        ),
+    )
+    .map_err(|e| format!("Failed to write scratch crate manifest \n{e}"))?;
+    fs::write(src.join("lib.rs"), lib)
+        .map_err(|e| format!("Failed to write scratch crate lib.rs \n{e}"))?;
+    fs::write(src.join("generated.rs"), top_mod_content)
+        .map_err(|e| format!("Failed to write scratch crate module file \n{e}"))?;
+    crate::gen::recurse_copy_clean(generated_dir, src.join("generated"), None)?;
+    let out = Command::new("cargo")
+        .arg("check")
+        .arg("--quiet")
+        .arg("--message-format")
+        .arg("short")
+        .current_dir(scratch.path())
+        .output()
+        .map_err(|e| format!("Failed to run cargo check on generated code \n{e}"))?;
+    if out.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "Generated code failed to compile, cargo check returned {} \n{}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr)
+        ))
+    }
+}
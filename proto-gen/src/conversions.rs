@@ -0,0 +1,239 @@
+//! `From` impls between generated messages and hand written domain types with the same fields
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::path::Path;
+
+use heck::{ToSnakeCase, ToUpperCamelCase};
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, FieldDescriptorProto, FileDescriptorSet};
+
+use crate::openapi::{is_map_entry, messages_by_name};
+
+/// Reads a mapping file with a `<proto message path> = <rust type path>` pair per line,
+/// like `.my_package.MyMessage = crate::domain::MyMessage`. Blank lines and lines starting
+/// with `#` are skipped.
+/// # Errors
+/// If the file can't be read or a line isn't a pair
+pub fn read_mapping(path: &Path) -> Result<Vec<(String, String)>, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read conversions mapping {path:?} \n{e}"))?;
+    parse_mapping(&content).map_err(|e| format!("Invalid conversions mapping {path:?} \n{e}"))
+}
+
+fn parse_mapping(content: &str) -> Result<Vec<(String, String)>, String> {
+    let mut mapping = vec![];
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((proto, rust)) = line.split_once('=') else {
+            return Err(format!(
+                "Line {} is not `<proto message path> = <rust type path>`: {line}",
+                number + 1
+            ));
+        };
+        let proto = proto.trim();
+        // Proto paths are fully qualified, like everywhere else in the options
+        let proto = if proto.starts_with('.') {
+            proto.to_string()
+        } else {
+            format!(".{proto}")
+        };
+        mapping.push((proto, rust.trim().to_string()));
+    }
+    Ok(mapping)
+}
+
+/// Code for a `conversions` module per package, keyed by the package, containing `From` impls
+/// in both directions for each mapped message. Fields are moved over by name, message fields
+/// are converted with `Into`, so their types have to be mapped too.
+pub(crate) fn generate(
+    fds: &FileDescriptorSet,
+    mapping: &[(String, String)],
+) -> Result<BTreeMap<String, String>, String> {
+    let messages = messages_by_name(fds);
+    let mapped = mapping
+        .iter()
+        .map(|(proto, _)| proto.as_str())
+        .collect::<Vec<_>>();
+    let mut modules = BTreeMap::<String, String>::new();
+    for (proto, rust) in mapping {
+        let Some(message) = messages.get(proto) else {
+            return Err(format!(
+                "Message {proto} from the conversions mapping was not found in the protos"
+            ));
+        };
+        let package = package_of(fds, proto).ok_or_else(|| {
+            format!("Failed to find the package of {proto} from the conversions mapping")
+        })?;
+        let relative = &proto[1..];
+        let relative = relative
+            .strip_prefix(package)
+            .and_then(|rest| rest.strip_prefix('.'))
+            .unwrap_or(relative);
+        let proto_type = rust_path(relative);
+        let mut fields = String::new();
+        for field in &message.field {
+            let conversion = field_conversion(proto, field, &messages, &mapped)?;
+            let _ = writeln!(
+                fields,
+                "                {}: {conversion},",
+                field_ident(field.name())
+            );
+        }
+        let module = modules
+            .entry(package.trim_start_matches('.').to_string())
+            .or_default();
+        for (from, to) in [(&proto_type, rust), (rust, &proto_type)] {
+            let _ = write!(
+                module,
+                "    impl ::core::convert::From<{from}> for {to} {{
+        fn from(value: {from}) -> Self {{
+            Self {{
+{fields}            }}
+        }}
+    }}
+"
+            );
+        }
+    }
+    Ok(modules
+        .into_iter()
+        .map(|(package, impls)| {
+            (
+                package,
+                format!(
+                    "/// Conversions to and from domain types\npub mod conversions {{\n{impls}}}\n"
+                ),
+            )
+        })
+        .collect())
+}
+
+/// How a field is converted, the same in both directions
+fn field_conversion(
+    proto: &str,
+    field: &FieldDescriptorProto,
+    messages: &HashMap<String, &DescriptorProto>,
+    mapped: &[&str],
+) -> Result<String, String> {
+    let name = field_ident(field.name());
+    if field.oneof_index.is_some() && !field.proto3_optional() {
+        return Err(format!(
+            "Field {proto}.{} is part of a oneof, which can't be converted by field name",
+            field.name()
+        ));
+    }
+    let require_mapped = |type_name: &str| {
+        if mapped.contains(&type_name) {
+            Ok(())
+        } else {
+            Err(format!(
+                "Field {proto}.{} has message type {type_name}, which is not in the conversions mapping",
+                field.name()
+            ))
+        }
+    };
+    if field.r#type() != Type::Message {
+        return Ok(format!("value.{name}"));
+    }
+    if field.label() == Label::Repeated {
+        if let Some(entry) = messages.get(field.type_name()).filter(|m| is_map_entry(m)) {
+            let value = entry.field.iter().find(|f| f.number() == 2);
+            return match value.filter(|value| value.r#type() == Type::Message) {
+                Some(value) => {
+                    require_mapped(value.type_name())?;
+                    Ok(format!(
+                        "value.{name}.into_iter().map(|(k, v)| (k, v.into())).collect()"
+                    ))
+                }
+                None => Ok(format!("value.{name}")),
+            };
+        }
+        require_mapped(field.type_name())?;
+        return Ok(format!(
+            "value.{name}.into_iter().map(::core::convert::Into::into).collect()"
+        ));
+    }
+    require_mapped(field.type_name())?;
+    Ok(format!("value.{name}.map(::core::convert::Into::into)"))
+}
+
+/// The package of a fully qualified message with a leading `.`
+pub(crate) fn package_of<'a>(fds: &'a FileDescriptorSet, proto: &str) -> Option<&'a str> {
+    fds.file
+        .iter()
+        .map(prost_types::FileDescriptorProto::package)
+        .filter(|package| {
+            package.is_empty()
+                || proto
+                    .strip_prefix('.')
+                    .and_then(|rest| rest.strip_prefix(*package))
+                    .is_some_and(|rest| rest.starts_with('.'))
+        })
+        .max_by_key(|package| package.len())
+}
+
+/// Path to a message relative to a `conversions` module in its package module,
+/// nested messages are in modules named after their parents
+pub(crate) fn rust_path(relative: &str) -> String {
+    let mut segments = relative.split('.').collect::<Vec<_>>();
+    let name = segments.pop().unwrap_or_default();
+    let mut path = "super::".to_string();
+    for parent in segments {
+        path.push_str(&field_ident(parent));
+        path.push_str("::");
+    }
+    path.push_str(&name.to_upper_camel_case());
+    path
+}
+
+/// Snake case like prost names fields and modules, keywords become raw identifiers
+pub(crate) fn field_ident(name: &str) -> String {
+    let ident = name.to_snake_case();
+    match ident.as_str() {
+        "self" | "super" | "extern" | "crate" => ident + "_",
+        "as" | "break" | "const" | "continue" | "else" | "enum" | "false" | "fn" | "for" | "if"
+        | "impl" | "in" | "let" | "loop" | "match" | "mod" | "move" | "mut" | "pub" | "ref"
+        | "return" | "static" | "struct" | "trait" | "true" | "type" | "unsafe" | "use"
+        | "where" | "while" | "dyn" | "abstract" | "become" | "box" | "do" | "final" | "macro"
+        | "override" | "priv" | "typeof" | "unsized" | "virtual" | "yield" | "async" | "await"
+        | "try" => format!("r#{ident}"),
+        _ => ident,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conversions::{field_ident, parse_mapping, rust_path};
+
+    #[test]
+    fn parses_mapping() {
+        let mapping = parse_mapping(
+            "# Domain types\n.my_proto.TestMessage = crate::domain::Test\n\nmy_proto.Other=Other\n",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                (
+                    ".my_proto.TestMessage".to_string(),
+                    "crate::domain::Test".to_string()
+                ),
+                (".my_proto.Other".to_string(), "Other".to_string()),
+            ],
+            mapping
+        );
+        assert!(parse_mapping("my_proto.TestMessage crate::domain::Test").is_err());
+    }
+
+    #[test]
+    fn names_like_prost() {
+        assert_eq!(
+            "super::outer_message::Inner",
+            rust_path("OuterMessage.Inner")
+        );
+        assert_eq!("field_one", field_ident("fieldOne"));
+        assert_eq!("r#type", field_ident("type"));
+    }
+}
@@ -0,0 +1,216 @@
+//! Constructors filling the optional fields of generated messages with their proto defaults
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{
+    EnumDescriptorProto, EnumValueDescriptorProto, FieldDescriptorProto, FileDescriptorSet,
+};
+
+use crate::builders::matches_path;
+use crate::conversions::{field_ident, rust_path};
+use crate::openapi::{is_map_entry, messages_by_name};
+
+/// Code for a `defaults` module per package, keyed by the package, with a `with_defaults()`
+/// constructor for each message matching one of the proto `paths` that has optional scalar
+/// fields. Prost's `Message` derive already implements `Default`, leaving them `None`, so
+/// the constructor is an inherent function instead.
+pub(crate) fn generate(fds: &FileDescriptorSet, paths: &[String]) -> BTreeMap<String, String> {
+    let enums = enums_by_name(fds);
+    let mut modules = BTreeMap::<String, String>::new();
+    for file in &fds.file {
+        let proto2 = matches!(file.syntax(), "" | "proto2");
+        let mut pending = file
+            .message_type
+            .iter()
+            .map(|message| (message.name().to_string(), message))
+            .collect::<Vec<_>>();
+        while let Some((relative, message)) = pending.pop() {
+            pending.extend(
+                message
+                    .nested_type
+                    .iter()
+                    .filter(|nested| !is_map_entry(nested))
+                    .map(|nested| (format!("{relative}.{}", nested.name()), nested)),
+            );
+            let proto = if file.package().is_empty() {
+                format!(".{relative}")
+            } else {
+                format!(".{}.{relative}", file.package())
+            };
+            if !paths.iter().any(|path| matches_path(&proto, path)) {
+                continue;
+            }
+            let mut fields = String::new();
+            for field in &message.field {
+                if is_optional_scalar(field, proto2) {
+                    let _ = writeln!(
+                        fields,
+                        "                {}: Some({}),",
+                        field_ident(field.name()),
+                        default_value(field, &enums)
+                    );
+                }
+            }
+            if fields.is_empty() {
+                continue;
+            }
+            let module = modules.entry(file.package().to_string()).or_default();
+            write_constructor(module, &relative, &fields);
+        }
+    }
+    modules
+        .into_iter()
+        .map(|(package, constructors)| {
+            (
+                package,
+                format!("/// Constructors setting optional fields to their proto defaults\npub mod defaults {{\n{constructors}}}\n"),
+            )
+        })
+        .collect()
+}
+
+fn write_constructor(module: &mut String, relative: &str, fields: &str) {
+    let _ = write!(
+        module,
+        "    impl {} {{
+        /// This message with its optional fields set to their proto defaults instead of `None`
+        #[must_use]
+        pub fn with_defaults() -> Self {{
+            Self {{
+{fields}                ..::core::default::Default::default()
+            }}
+        }}
+    }}
+
+",
+        rust_path(relative)
+    );
+}
+
+/// Fields prost generates as an `Option` of a plain value, proto3 `optional` fields and
+/// proto2 optional fields outside of a oneof
+fn is_optional_scalar(field: &FieldDescriptorProto, proto2: bool) -> bool {
+    let optional = field.proto3_optional()
+        || (proto2 && field.label() == Label::Optional && field.oneof_index.is_none());
+    optional && !matches!(field.r#type(), Type::Message | Type::Group)
+}
+
+/// Enums keyed by their fully qualified name with a leading `.`, as used in field types
+fn enums_by_name(fds: &FileDescriptorSet) -> HashMap<String, &EnumDescriptorProto> {
+    let mut enums = HashMap::new();
+    for file in &fds.file {
+        let prefix = if file.package().is_empty() {
+            ".".to_string()
+        } else {
+            format!(".{}.", file.package())
+        };
+        for enum_type in &file.enum_type {
+            enums.insert(format!("{prefix}{}", enum_type.name()), enum_type);
+        }
+    }
+    for (name, message) in messages_by_name(fds) {
+        for enum_type in &message.enum_type {
+            enums.insert(format!("{name}.{}", enum_type.name()), enum_type);
+        }
+    }
+    enums
+}
+
+/// Rust expression for the proto default of `field`, the explicit proto2 `default` or the
+/// type's zero value. Enums are the number of the default value, since prost stores `i32`s.
+fn default_value(
+    field: &FieldDescriptorProto,
+    enums: &HashMap<String, &EnumDescriptorProto>,
+) -> String {
+    let explicit = field.default_value.as_deref();
+    match field.r#type() {
+        Type::Double => float_value(explicit, "f64"),
+        Type::Float => float_value(explicit, "f32"),
+        Type::Bool => explicit.unwrap_or("false").to_string(),
+        Type::String => explicit.map_or_else(
+            || "::prost::alloc::string::String::new()".to_string(),
+            |value| format!("::prost::alloc::string::String::from({value:?})"),
+        ),
+        Type::Bytes => match explicit.map(unescape_bytes) {
+            Some(bytes) if !bytes.is_empty() => {
+                format!("::prost::alloc::vec::Vec::from({bytes:?})")
+            }
+            _ => "::prost::alloc::vec::Vec::new()".to_string(),
+        },
+        Type::Enum => {
+            let values = enums
+                .get(field.type_name())
+                .map(|enum_type| enum_type.value.as_slice())
+                .unwrap_or_default();
+            explicit
+                .and_then(|name| values.iter().find(|value| value.name() == name))
+                .or(values.first())
+                .map_or(0, EnumValueDescriptorProto::number)
+                .to_string()
+        }
+        _ => explicit.unwrap_or("0").to_string(),
+    }
+}
+
+fn float_value(explicit: Option<&str>, float: &str) -> String {
+    match explicit {
+        None => format!("0.0_{float}"),
+        Some("inf") => format!("{float}::INFINITY"),
+        Some("-inf") => format!("{float}::NEG_INFINITY"),
+        Some("nan") => format!("{float}::NAN"),
+        Some(value) => format!("{value}_{float}"),
+    }
+}
+
+/// Protoc escapes `bytes` defaults like C strings, with octal escapes for non-printable bytes
+fn unescape_bytes(escaped: &str) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut chars = escaped.bytes().peekable();
+    while let Some(byte) = chars.next() {
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        let Some(escape) = chars.next() else {
+            bytes.push(byte);
+            break;
+        };
+        let (radix, mut value, max_digits) = match escape {
+            b'n' => (0, b'\n', 0),
+            b'r' => (0, b'\r', 0),
+            b't' => (0, b'\t', 0),
+            b'x' => (16, 0, 2),
+            b'0'..=b'7' => (8, escape - b'0', 2),
+            other => (0, other, 0),
+        };
+        for _ in 0..max_digits {
+            let Some(digit) = chars
+                .peek()
+                .and_then(|next| char::from(*next).to_digit(radix))
+            else {
+                break;
+            };
+            chars.next();
+            value = value
+                .wrapping_mul(u8::try_from(radix).unwrap_or_default())
+                .wrapping_add(u8::try_from(digit).unwrap_or_default());
+        }
+        bytes.push(value);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::defaults::unescape_bytes;
+
+    #[test]
+    fn bytes_defaults_unescaped() {
+        assert_eq!(b"a\n\"".to_vec(), unescape_bytes(r#"a\n\""#));
+        assert_eq!(
+            vec![0, 1, 0o377, 0xab, b'z'],
+            unescape_bytes(r"\000\1\377\xabz")
+        );
+    }
+}
@@ -0,0 +1,72 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use prost_types::FileDescriptorSet;
+
+use crate::gen::ProtoWorkspace;
+use crate::manifest::output_files;
+
+/// Writes a Makefile style depfile to `depfile`, making all generated files depend on every
+/// proto file in the descriptor set from protoc, which includes transitive imports.
+/// Imports that can't be found in the proto dirs, such as well known types bundled with
+/// protoc, are left out.
+pub(crate) fn write(
+    depfile: &Path,
+    ws: &ProtoWorkspace,
+    fds: &FileDescriptorSet,
+) -> Result<(), String> {
+    let mut protos = ws.proto_files.clone();
+    protos.extend(ws.image.iter().cloned());
+    for name in fds.file.iter().filter_map(|file| file.name.as_deref()) {
+        if let Some(proto) = ws
+            .proto_dirs
+            .iter()
+            .map(|dir| dir.join(name))
+            .find(|proto| proto.is_file())
+        {
+            protos.push(proto);
+        }
+    }
+    protos.sort();
+    protos.dedup();
+    let outputs = output_files(ws)?;
+    fs::write(depfile, format_depfile(&outputs, &protos))
+        .map_err(|e| format!("Failed to write depfile {depfile:?} \n{e}"))
+}
+
+fn format_depfile(outputs: &[PathBuf], protos: &[PathBuf]) -> String {
+    let join = |paths: &[PathBuf]| {
+        paths
+            .iter()
+            .map(|path| escape(path))
+            .collect::<Vec<_>>()
+            .join(" \\\n  ")
+    };
+    format!("{}: \\\n  {}\n", join(outputs), join(protos))
+}
+
+fn escape(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace('$', "$$")
+        .replace(' ', "\\ ")
+        .replace('#', "\\#")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::depfile::format_depfile;
+    use std::path::PathBuf;
+
+    #[test]
+    fn formats_makefile_rule() {
+        let outputs = [
+            PathBuf::from("src/proto_types.rs"),
+            PathBuf::from("src/proto_types/my proto.rs"),
+        ];
+        let protos = [PathBuf::from("proto/my.proto")];
+        assert_eq!(
+            "src/proto_types.rs \\\n  src/proto_types/my\\ proto.rs: \\\n  proto/my.proto\n",
+            format_depfile(&outputs, &protos)
+        );
+    }
+}
@@ -1,18 +1,33 @@
 //! A library that generates Rust code using tonic-build and places that code in a supplied directory
 #![warn(clippy::pedantic)]
-#![allow(clippy::disallowed_types, clippy::disallowed_methods)]
+#![allow(
+    clippy::disallowed_types,
+    clippy::disallowed_methods,
+    clippy::unnecessary_debug_formatting
+)]
 
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::{Debug, Write};
 use std::fs;
-use std::io::ErrorKind;
+use std::io::{BufReader, ErrorKind, IsTerminal, Read};
 use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
+use prost::Message;
+use prost_types::{DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet};
+use regex::Regex;
 use tonic_build::Builder;
 
-/// Generate protos for the provided proto workspace
+use crate::archive::ArchivedOutput;
+use crate::cache::collect_with_ext;
+use crate::ignore::IgnoreRules;
+
+/// Generate protos for the provided proto workspace. Without committing, differences from
+/// the output dirs are only counted in [`GenerationReport::diff`].
 /// # Errors
 /// Miscellaneous errors accessing the filesystem (such as permissions),
 /// and errors coming from `protoc`
@@ -21,66 +36,819 @@ pub fn run_generation(
     opts: Builder,
     config: prost_build::Config,
     gen_opts: &GenOptions,
+) -> Result<GenerationReport, String> {
+    let mut report = GenerationReport::default();
+    let package_tmps = package_tmp_dirs(proto_ws, gen_opts)?;
+    let packages = proto_ws
+        .package_outputs
+        .iter()
+        .zip(&package_tmps)
+        .map(|((package, _), tmp)| (package.as_str(), tmp.path()))
+        .collect::<Vec<_>>();
+    let start = Instant::now();
+    let (top_mods, file_descriptor_set) =
+        generate_workspace(proto_ws, opts, config, gen_opts, &packages)?;
+    report.file_descriptor_set = file_descriptor_set;
+    report.generate_time = start.elapsed();
+    if gen_opts.list {
+        for module in top_mods
+            .into_iter()
+            .flat_map(|(_, module_tree)| module_tree)
+        {
+            print!("{module}");
+            report.modules += module.module_count();
+            report.module_tree.push(module);
+        }
+        return Ok(report);
+    }
+    if gen_opts.stdout {
+        let mut files = vec![];
+        for tmp in std::iter::once(proto_ws.tmp_dir.as_path())
+            .chain(package_tmps.iter().map(tempfile::TempDir::path))
+        {
+            collect_with_ext(tmp, "rs", &mut files)?;
+        }
+        let [file] = files.as_slice() else {
+            return Err(format!(
+                "--stdout only supports a single output module, but {} would be generated",
+                files.len()
+            ));
+        };
+        let mut content = fs::read_to_string(file)
+            .map_err(|e| format!("Failed to read generated file {file:?} \n{e}"))?;
+        if gen_opts.format {
+            content = fmt(&content)?;
+        }
+        print!("{content}");
+        return Ok(report);
+    }
+    if let Some(openapi) = &proto_ws.openapi {
+        write_openapi(proto_ws, openapi, &report.file_descriptor_set, gen_opts)?;
+    }
+    let targets = std::iter::once((proto_ws.output_dir.as_path(), proto_ws.tmp_dir.as_path()))
+        .chain(
+            proto_ws
+                .package_outputs
+                .iter()
+                .zip(&package_tmps)
+                .map(|((_, output_dir), tmp)| (output_dir.as_path(), tmp.path())),
+        );
+    if let Some(archive) = &proto_ws.archive {
+        archive_output(archive, targets, top_mods, gen_opts, &mut report)?;
+        return Ok(report);
+    }
+    if gen_opts.build_script {
+        build_script_output(targets, top_mods, gen_opts, &mut report)?;
+        return Ok(report);
+    }
+    for ((old, new), (top_mod_content, module_tree)) in targets.zip(top_mods) {
+        report.modules += module_tree
+            .iter()
+            .map(ModuleTree::module_count)
+            .sum::<usize>();
+        report.module_tree.extend(module_tree);
+        let mod_file = module_file(proto_ws, old)?;
+        sync_output(old, new, &mod_file, top_mod_content, gen_opts, &mut report)?;
+    }
+    if gen_opts.verbosity.is_verbose() {
+        for module in &report.module_tree {
+            print!("{module}");
+        }
+    }
+    if !gen_opts.verbosity.is_quiet() {
+        println!(
+            "Generated {} modules, protoc took {:?}, rustfmt took {:?}, diffing took {:?}",
+            report.modules, report.generate_time, report.format_time, report.diff_time
+        );
+    }
+    Ok(report)
+}
+
+/// Generate protos for the provided proto workspace into its temp dir and return only the
+/// module file content of its output dir, declaring the top level modules. Nothing is diffed
+/// or written to the output dirs, for tooling that places the generated files itself.
+/// # Errors
+/// Miscellaneous errors accessing the filesystem (such as permissions),
+/// and errors coming from `protoc`
+pub fn top_level_module(
+    proto_ws: &ProtoWorkspace,
+    opts: Builder,
+    config: prost_build::Config,
+    gen_opts: &GenOptions,
+) -> Result<String, String> {
+    let package_tmps = package_tmp_dirs(proto_ws, gen_opts)?;
+    let packages = proto_ws
+        .package_outputs
+        .iter()
+        .zip(&package_tmps)
+        .map(|((package, _), tmp)| (package.as_str(), tmp.path()))
+        .collect::<Vec<_>>();
+    let (top_mods, _) = generate_workspace(proto_ws, opts, config, gen_opts, &packages)?;
+    let (top_mod_content, _) = top_mods.into_iter().next().unwrap_or_default();
+    if gen_opts.format {
+        fmt(&top_mod_content)
+    } else {
+        Ok(top_mod_content)
+    }
+}
+
+/// Packages routed elsewhere get their own temp dir to diff against their output dir
+fn package_tmp_dirs(
+    proto_ws: &ProtoWorkspace,
+    gen_opts: &GenOptions,
+) -> Result<Vec<tempfile::TempDir>, String> {
+    proto_ws
+        .package_outputs
+        .iter()
+        .map(|(package, _)| {
+            working_tempdir(gen_opts.tmp_base.as_deref())
+                .map_err(|e| format!("Failed to create tempdir for package {package} \n{e}"))
+        })
+        .collect()
+}
+
+/// Runs protoc and the code generators into the temp dirs, laying out the generated files
+fn generate_workspace(
+    proto_ws: &ProtoWorkspace,
+    opts: Builder,
+    config: prost_build::Config,
+    gen_opts: &GenOptions,
+    packages: &[(&str, &Path)],
+) -> Result<(TopLevelModules, FileDescriptorSet), String> {
+    gen_opts.emit(GenEvent::CompileStarted);
+    generate_to_tmp(proto_ws, opts, config, gen_opts, packages).map_err(|e| {
+        // The offending proto is easily lost below the workspace
+        format!(
+            "{}Failed to generate protos into temp dir for proto workspace {proto_ws:#?} \n{e}",
+            protoc_error_summary(&e)
+        )
+    })
+}
+
+/// Formats the generated code and packs it into the `archive` instead of the output dirs
+fn archive_output<'a>(
+    archive: &Path,
+    targets: impl Iterator<Item = (&'a Path, &'a Path)>,
+    top_mods: TopLevelModules,
+    gen_opts: &GenOptions,
+    report: &mut GenerationReport,
+) -> Result<(), String> {
+    let mut outputs = vec![];
+    for ((old, new), (top_mod_content, module_tree)) in targets.zip(top_mods) {
+        let top_mod_content = finish_undiffed(new, top_mod_content, module_tree, gen_opts, report)?;
+        outputs.push(ArchivedOutput {
+            name: as_file_name_string(old)?,
+            generated_dir: new,
+            top_mod_content,
+        });
+    }
+    crate::archive::write(archive, &outputs)?;
+    if !gen_opts.verbosity.is_quiet() {
+        println!(
+            "Archived {} modules to {archive:?}, protoc took {:?}, rustfmt took {:?}",
+            report.modules, report.generate_time, report.format_time
+        );
+    }
+    Ok(())
+}
+
+/// Formats the generated code and writes it straight into the output dirs, for build scripts
+/// where nothing needs diffing. Other files in the output dirs are left alone and the module
+/// file is written into each as `mod.rs`, to be pulled in with `include!`.
+fn build_script_output<'a>(
+    targets: impl Iterator<Item = (&'a Path, &'a Path)>,
+    top_mods: TopLevelModules,
+    gen_opts: &GenOptions,
+    report: &mut GenerationReport,
+) -> Result<(), String> {
+    for ((old, new), (top_mod_content, module_tree)) in targets.zip(top_mods) {
+        let top_mod_content = finish_undiffed(new, top_mod_content, module_tree, gen_opts, report)?;
+        copy_into(new, old, gen_opts.file_mode)?;
+        let mod_file = old.join("mod.rs");
+        fs::write(&mod_file, outer_attributes(&top_mod_content))
+            .map_err(|e| format!("Failed to write module file to {mod_file:?} \n{e}"))?;
+        set_file_mode(&mod_file, gen_opts.file_mode)?;
+        gen_opts.emit(GenEvent::Committed(old));
+    }
+    if !gen_opts.verbosity.is_quiet() {
+        println!(
+            "Wrote {} modules for a build script, protoc took {:?}, rustfmt took {:?}",
+            report.modules, report.generate_time, report.format_time
+        );
+    }
+    Ok(())
+}
+
+/// Counts the modules of an output that isn't diffed and formats it if requested,
+/// returning the formatted module file content
+fn finish_undiffed(
+    new: &Path,
+    mut top_mod_content: String,
+    module_tree: Vec<ModuleTree>,
+    gen_opts: &GenOptions,
+    report: &mut GenerationReport,
+) -> Result<String, String> {
+    report.modules += module_tree
+        .iter()
+        .map(ModuleTree::module_count)
+        .sum::<usize>();
+    report.module_tree.extend(module_tree);
+    if gen_opts.format {
+        let start = Instant::now();
+        recurse_fmt(new, gen_opts)?;
+        top_mod_content = fmt(&top_mod_content)?;
+        report.format_time += start.elapsed();
+    }
+    Ok(top_mod_content)
+}
+
+/// `include!` doesn't allow inner attributes, so the module file's are put on each of its
+/// top level module declarations instead
+fn outer_attributes(top_mod_content: &str) -> String {
+    let mut attributes = vec![];
+    let mut content = String::with_capacity(top_mod_content.len());
+    for line in top_mod_content.lines() {
+        if let Some(attribute) = line.strip_prefix("#![") {
+            attributes.push(attribute);
+            continue;
+        }
+        let is_declaration = line
+            .split_once("mod ")
+            .is_some_and(|(visibility, _)| !visibility.starts_with([' ', '/']));
+        if is_declaration {
+            for attribute in &attributes {
+                let _ = writeln!(content, "#[{attribute}");
+            }
+        }
+        let _ = writeln!(content, "{line}");
+    }
+    content
+}
+
+/// Writes the `OpenAPI` description into the tmp dir if it belongs in the output dir,
+/// otherwise straight to its path if committing
+fn write_openapi(
+    ws: &ProtoWorkspace,
+    openapi: &Path,
+    fds: &FileDescriptorSet,
+    gen_opts: &GenOptions,
+) -> Result<(), String> {
+    let path = match openapi.strip_prefix(&ws.output_dir) {
+        Ok(relative) => ws.tmp_dir.join(relative),
+        Err(_) if gen_opts.commit => openapi.to_path_buf(),
+        Err(_) => return Ok(()),
+    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create dir for OpenAPI spec {path:?} \n{e}"))?;
+    }
+    fs::write(&path, crate::openapi::render(fds))
+        .map_err(|e| format!("Failed to write OpenAPI spec {path:?} \n{e}"))
+}
+
+/// Diffs the generated code in `new` against the output dir `old` and its module file
+/// `mod_file`, and writes it there if committing. Otherwise the differences are only counted
+/// in the report.
+fn sync_output(
+    old: &Path,
+    new: &Path,
+    mod_file: &Path,
+    mut top_mod_content: String,
+    gen_opts: &GenOptions,
+    report: &mut GenerationReport,
 ) -> Result<(), String> {
-    let mut top_mod_content = generate_to_tmp(proto_ws, opts, config, gen_opts).map_err(|e| {
-        format!("Failed to generate protos into temp dir for proto workspace {proto_ws:#?} \n{e}")
-    })?;
-    let old = &proto_ws.output_dir;
-    let new = &proto_ws.tmp_dir;
     if gen_opts.format {
-        recurse_fmt(new)?;
+        let start = Instant::now();
+        // Every file, including the module files only declaring child modules, was already
+        // laid out in `new` by `generate_to_tmp`, so all of them are formatted before diffing
+        recurse_fmt(new, gen_opts)?;
         top_mod_content = fmt(&top_mod_content)?;
+        report.format_time += start.elapsed();
+    }
+    // After formatting so that custom sections are kept exactly as written
+    preserve_custom_sections(old, new, mod_file, &mut top_mod_content)?;
+    if gen_opts.check_compiles || gen_opts.fail_on_warnings {
+        crate::check::check_compiles(
+            new,
+            &top_mod_content,
+            gen_opts.no_std,
+            gen_opts.fail_on_warnings,
+        )?;
     }
-    let diff = run_diff(old, new, &top_mod_content)?;
+    let mut ignore = IgnoreRules::from_output_dir(old)?;
+    for pattern in &gen_opts.ignore {
+        ignore.add(pattern);
+    }
+    // A module file inside the output dir, such as the `lib.rs` of a generated crate, is
+    // diffed and written on its own and kept when cleaning the dir
+    if let Ok(relative) = mod_file.strip_prefix(old) {
+        let relative = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        ignore.add(&format!("/{relative}"));
+    }
+    let start = Instant::now();
+    let diff = run_diff(old, new, mod_file, &top_mod_content, gen_opts, &ignore)?;
+    report.diff_time += start.elapsed();
+    report.diff += diff;
     if diff > 0 {
-        println!("Found diff in {diff} protos at {:?}", proto_ws.output_dir);
+        if !gen_opts.verbosity.is_quiet() {
+            println!("Found diff in {diff} protos at {old:?}");
+        }
         if gen_opts.commit {
-            println!("Writing {diff} protos to {:?}", proto_ws.output_dir);
-            recurse_copy_clean(new, old)?;
-            let out_top_name = as_file_name_string(old)?;
-            let out_parent = old.parent().ok_or_else(|| {
-                format!("Failed to find parent for output dir {old:?} to place mod file")
-            })?;
-            let mod_file = out_parent.join(format!("{out_top_name}.rs"));
-            fs::write(&mod_file, top_mod_content.as_bytes())
+            if !gen_opts.verbosity.is_quiet() {
+                println!("Writing {diff} protos to {old:?}");
+            }
+            let splice = !gen_opts.only.is_empty();
+            // Everything not regenerated is kept when splicing, so nothing is orphaned
+            let orphans = if splice {
+                vec![]
+            } else {
+                orphaned_files(old, new, &ignore)?
+            };
+            if !gen_opts.force {
+                let foreign = foreign_files(old, mod_file, &orphans)?;
+                if !foreign.is_empty() {
+                    return Err(format!(
+                        "Refusing to clean output dir {old:?}, it contains files proto-gen didn't generate: {foreign:?}\nPass --force to delete them, or list them in {} to keep them",
+                        crate::ignore::IGNORE_FILE
+                    ));
+                }
+            }
+            // Ignored files are left in place, with their permissions and mtime, instead of
+            // being overwritten by the generated ones
+            remove_ignored(new, &ignore)?;
+            // A symlinked output dir is kept and its target written instead, the module
+            // file still goes next to the link since that's where its `mod` resolves from
+            let target = resolve_symlink(old)?;
+            remove_orphans(&target, &orphans)?;
+            copy_into(new, &target, gen_opts.file_mode)?;
+            gen_opts.emit(GenEvent::Committed(old));
+            if !gen_opts.verbosity.is_quiet() {
+                for orphan in &orphans {
+                    println!("Removed orphaned file {orphan:?}");
+                }
+            }
+            fs::write(mod_file, top_mod_content.as_bytes())
                 .map_err(|e| format!("Failed to write parent module file to {mod_file:?} \n{e}"))?;
-        } else {
-            return Err(format!("Found {diff} diffs at {:?}", proto_ws.output_dir));
+            set_file_mode(mod_file, gen_opts.file_mode)?;
+            gen_opts.emit(GenEvent::Committed(mod_file));
         }
-    } else {
-        println!("Found no diff at {:?}", proto_ws.output_dir);
+    } else if !gen_opts.verbosity.is_quiet() {
+        println!("Found no diff at {old:?}");
     }
     Ok(())
 }
 
+/// Summary of a successful generation run
+#[derive(Debug, Clone, Default)]
+pub struct GenerationReport {
+    /// Number of modules generated, including parent modules without a proto of their own
+    pub modules: usize,
+    /// Number of files that differed from the output dir
+    pub diff: usize,
+    /// Time spent running protoc and laying out the generated files
+    pub generate_time: Duration,
+    /// Time spent running rustfmt, zero if formatting was not requested
+    pub format_time: Duration,
+    /// Time spent diffing against the output dir
+    pub diff_time: Duration,
+    /// The generated top level modules, sorted like their declarations within each output dir
+    pub module_tree: Vec<ModuleTree>,
+    /// Descriptors of every proto file read by protoc, including transitive imports
+    pub file_descriptor_set: FileDescriptorSet,
+}
+
+/// A generated module and its children, mirroring the proto package hierarchy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleTree {
+    /// Name used in the `pub mod` declaration
+    pub name: String,
+    /// Full proto package of the module, such as `imports.nested`
+    pub package: String,
+    /// Child modules, sorted like their declarations
+    pub children: Vec<ModuleTree>,
+    /// The file generated for this package, `None` if the module only declares child modules
+    pub source_file: Option<PathBuf>,
+}
+
+impl ModuleTree {
+    /// Number of modules in this tree, including this one
+    #[must_use]
+    pub fn module_count(&self) -> usize {
+        1 + self
+            .children
+            .iter()
+            .map(ModuleTree::module_count)
+            .sum::<usize>()
+    }
+
+    fn fmt_indented(&self, f: &mut std::fmt::Formatter<'_>, depth: usize) -> std::fmt::Result {
+        write!(f, "{:indent$}{}", "", self.name, indent = depth * 2)?;
+        if let Some(source_file) = &self.source_file {
+            write!(f, " ({})", source_file.display())?;
+        }
+        writeln!(f)?;
+        for child in &self.children {
+            child.fmt_indented(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Prints the tree as an indented outline, one module per line
+impl std::fmt::Display for ModuleTree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
 #[derive(Debug)]
 pub struct ProtoWorkspace {
     pub proto_dirs: Vec<PathBuf>,
     pub proto_files: Vec<PathBuf>,
     pub tmp_dir: PathBuf,
     pub output_dir: PathBuf,
+    /// Top level proto packages placed in their own output dir instead of `output_dir`,
+    /// each output dir gets its own module file
+    pub package_outputs: Vec<(String, PathBuf)>,
+    /// Serialized `FileDescriptorSet`, such as a `buf` image, to generate from instead of
+    /// running protoc on `proto_files`
+    pub image: Option<PathBuf>,
+    /// Where to write an `OpenAPI` description of the services, inside the output dir
+    /// it's diffed and written with the generated code
+    pub openapi: Option<PathBuf>,
+    /// Zip archive to pack the generated code into, instead of diffing and writing it
+    /// to the output dirs
+    pub archive: Option<PathBuf>,
+    /// Where to write the module file of `output_dir` instead of next to it, such as a
+    /// `lib.rs` keeping its own code in custom sections
+    pub mod_file: Option<PathBuf>,
 }
 
-#[allow(clippy::module_name_repetitions)]
-#[derive(Debug)]
+#[allow(clippy::module_name_repetitions, clippy::struct_excessive_bools)]
+#[derive(Debug, Default)]
 pub struct GenOptions {
     pub commit: bool,
+    /// Only print the module tree to stdout, nothing is formatted, diffed or written
+    pub list: bool,
+    /// Only print the module file content of the output dir, nothing is diffed or written
+    pub top_module: bool,
+    /// Print the code of the single generated module to stdout instead of writing it
+    pub stdout: bool,
+    /// Write straight into the output dirs without diffing, with the module file as `mod.rs`
+    /// inside each, for build scripts
+    pub build_script: bool,
+    /// `.gitignore` style patterns limiting generation to the matching proto files, the other
+    /// modules already in the output dirs are kept
+    pub only: Vec<String>,
+    /// `.gitignore` style patterns of proto files whose packages are left out of the
+    /// generated code when all of their files match
+    pub exclude_files: Vec<String>,
+    /// Unix permissions given to the written files, left as created if `None`. The binary
+    /// always passes one, `0o644` unless `--file-mode` is given.
+    pub file_mode: Option<u32>,
     pub format: bool,
+    /// Format both the output dir's and the generated files before diffing them, so code that
+    /// only differs in formatting, such as from another `rustfmt` version, isn't a diff
+    pub ignore_format_diff: bool,
+    /// Record the `rustfmt` version when generating and warn if it differs when validating
+    pub format_check_pinned: bool,
     pub prepend_header: Option<String>,
+    /// Header used instead of `prepend_header` for files that only declare child modules
+    pub module_header: Option<String>,
     pub toplevel_attribute: Option<String>,
+    /// Leaves the default clippy allows out of the toplevel mod
+    pub no_clippy_allows: bool,
+    /// Puts child modules inline in their parent's file instead of in files of their own
+    pub inline_children: bool,
+    /// Lays out each package as a top level module named after its last segment
+    pub flatten_packages: bool,
+    /// Dir to create the temp dirs holding generated code in, the system temp dir if `None`
+    pub tmp_base: Option<PathBuf>,
+    /// Strips the `derive_partial_eq_without_eq` allow prost puts on generated types
+    pub no_partial_eq_allow: bool,
+    /// Allows `missing_docs` in the toplevel mod and the files declaring child modules
+    pub allow_missing_docs: bool,
+    /// Whether any types were given [`DERIVE_EQ_ATTRIBUTE`]
+    pub derive_eq: bool,
+    /// Whether any enums were given [`NON_EXHAUSTIVE_ATTRIBUTE`]
+    pub non_exhaustive_enums: bool,
+    /// Whether any enums were given [`MINIMAL_ENUM_ATTRIBUTE`]
+    pub minimal_enums: bool,
+    /// Whether to put [`GENERATED_MARKER`] at the top of each generated file
+    pub generated_marker: bool,
+    /// Whether to verify that the generated code compiles before diffing
+    pub check_compiles: bool,
+    /// Whether warnings in the generated code fail the compilation check
+    pub fail_on_warnings: bool,
+    /// Whether to run `buf lint` on the proto dirs before generating
+    pub buf_lint: bool,
+    /// Whether the code is generated for `#![no_std]`, checked in a `no_std` crate
+    pub no_std: bool,
+    pub verbosity: Verbosity,
+    /// Cargo feature gating the generated tonic server modules
+    pub server_feature: Option<String>,
+    /// Whether services are left out of generation, when neither clients nor servers are built
+    pub omit_services: bool,
+    /// Whether clients are generated for gRPC-web, which has no client streaming
+    pub grpc_web: bool,
+    /// Codec path replacing the prost codec in the generated clients and servers
+    pub codec_path: Option<String>,
+    /// Cargo feature gating the generated tonic client modules
+    pub client_feature: Option<String>,
+    /// Patterns of files to leave out of diffing and overwriting, in addition to the
+    /// ones in the output dir's [`crate::ignore::IGNORE_FILE`]
+    pub ignore: Vec<String>,
+    /// Whether files in the output dir that aren't in the module tree of the existing module
+    /// file may be deleted
+    pub force: bool,
+    /// Whether to empty the tmp dir before generating into it, which deletes everything in it
+    pub clean_tmp: bool,
+    /// Whether to write package comments as module docs
+    pub module_docs: bool,
+    /// Whether empty files from protoc are kept as empty modules instead of being deleted
+    pub keep_empty_modules: bool,
+    /// Whether tonic service modules are moved into their own file
+    pub split_services: bool,
+    /// Whether to append mock implementations of tonic server traits
+    pub generate_mocks: bool,
+    /// Print a tab separated line per changed file to stdout instead of the messages, such as
+    /// `diff\t<path>`, for scripts
+    pub porcelain: bool,
+    /// Caps how many changed files are printed when diffing, the rest are only counted
+    pub max_diff_print: Option<usize>,
+    pub module_visibility: ModuleVisibility,
+    pub sort_modules: ModuleSort,
+    /// Declares all top level modules inside an inline module with this name,
+    /// their files are placed in a dir of the same name in the output dir
+    pub wrap_module: Option<String>,
+    /// Proto paths with comments disabled, packages under them get no module docs
+    pub disable_comments: Vec<String>,
+    /// Comments are also disabled for proto paths matching any of these
+    pub disable_comments_regex: Vec<Regex>,
+    /// Proto paths to keep comments for, even when disabled by a parent path
+    pub retain_comments: Vec<String>,
+    /// Proto message paths and the Rust types to generate `From` impls between
+    pub conversions: Vec<(String, String)>,
+    /// Proto paths of the messages to generate builders for
+    pub builders: Vec<String>,
+    /// Proto paths of the messages to generate `with_defaults()` constructors for
+    pub default_impl: Vec<String>,
+    /// Case serde renames fields to, used to name the properties in JSON Schemas
+    pub serde_rename_all: Option<String>,
+    /// Whether well-known type fields get serde attributes pointing at generated helpers
+    pub serde_well_known: bool,
+    pub color: ColorChoice,
+    /// Called with progress events, in addition to the printed output
+    pub on_event: Option<EventHandler>,
 }
 
+impl GenOptions {
+    fn emit(&self, event: GenEvent<'_>) {
+        if let Some(on_event) = &self.on_event {
+            (on_event.0)(event);
+        }
+    }
+}
+
+/// Progress of [`run_generation`], for showing it in another UI
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenEvent<'a> {
+    /// Protoc and the code generators are about to run
+    CompileStarted,
+    /// A generated file is about to be formatted
+    Formatting(&'a Path),
+    /// A generated file differs from the output dir, relative to it
+    DiffFound(&'a Path),
+    /// An output dir or module file was written
+    Committed(&'a Path),
+}
+
+/// Callback receiving [`GenEvent`]s
+pub struct EventHandler(pub Box<dyn Fn(GenEvent<'_>) + Send + Sync>);
+
+impl Debug for EventHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EventHandler")
+    }
+}
+
+/// How much progress output to print, errors and diffs are always printed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Verbosity {
+    Quiet,
+    #[default]
+    Normal,
+    Verbose,
+}
+
+impl Verbosity {
+    #[inline]
+    #[must_use]
+    pub fn is_quiet(self) -> bool {
+        self == Verbosity::Quiet
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_verbose(self) -> bool {
+        self == Verbosity::Verbose
+    }
+}
+
+/// Visibility of the generated `mod` declarations, applied at every nesting level
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ModuleVisibility {
+    /// `pub mod`
+    #[default]
+    Pub,
+    /// `pub(crate) mod`
+    PubCrate,
+    /// `mod`
+    Private,
+}
+
+impl ModuleVisibility {
+    /// The declaration of module `name`, including a trailing newline
+    fn declare(self, name: &str) -> String {
+        format!("{}mod {name};\n", self.prefix())
+    }
+
+    fn prefix(self) -> &'static str {
+        match self {
+            ModuleVisibility::Pub => "pub ",
+            ModuleVisibility::PubCrate => "pub(crate) ",
+            ModuleVisibility::Private => "",
+        }
+    }
+}
+
+/// Order of the generated `mod` declarations
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ModuleSort {
+    /// By byte value, uppercase names before lowercase ones
+    #[default]
+    CaseSensitive,
+    /// Ignoring case, names only differing in case are ordered case sensitively
+    CaseInsensitive,
+}
+
+impl ModuleSort {
+    fn compare(self, a: &str, b: &str) -> Ordering {
+        match self {
+            ModuleSort::CaseSensitive => a.cmp(b),
+            ModuleSort::CaseInsensitive => a
+                .to_lowercase()
+                .cmp(&b.to_lowercase())
+                .then_with(|| a.cmp(b)),
+        }
+    }
+}
+
+/// When to color the diff output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// When printing to a terminal and `NO_COLOR` isn't set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub(crate) fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => {
+                std::env::var_os("NO_COLOR").is_none_or(|no_color| no_color.is_empty())
+                    && std::io::stderr().is_terminal()
+            }
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+const RED: &str = "31";
+const GREEN: &str = "32";
+pub(crate) const YELLOW: &str = "33";
+
+/// Wraps `text` in the ANSI escape for the color `code` if `enabled`
+pub(crate) fn paint(text: &str, code: &str, enabled: bool) -> String {
+    if enabled {
+        format!("\x1b[{code}m{text}\x1b[0m")
+    } else {
+        text.to_string()
+    }
+}
+
+/// Marker recognized by tools such as code review bots and rust-analyzer to skip generated files
+pub const GENERATED_MARKER: &str = "// @generated by proto-gen\n";
+
+/// Starts a hand written section in a generated file, kept when regenerating
+pub const CUSTOM_BEGIN_MARKER: &str = "// proto-gen:begin-custom";
+
+/// Ends a hand written section started by [`CUSTOM_BEGIN_MARKER`]
+pub const CUSTOM_END_MARKER: &str = "// proto-gen:end-custom";
+
+/// Type attribute used for types that should derive `Eq` and `Hash`
+pub const DERIVE_EQ_ATTRIBUTE: &str = "#[derive(Eq, Hash)]";
+
+/// Allow prost puts on every type deriving `PartialEq`
+const PARTIAL_EQ_ALLOW: &str = "#[allow(clippy::derive_partial_eq_without_eq)]";
+
+/// Enum attribute used for enums that should be `#[non_exhaustive]`
+pub const NON_EXHAUSTIVE_ATTRIBUTE: &str = "#[non_exhaustive]";
+
+/// Enum attribute marking enums that should be generated without the string name helpers,
+/// an attribute that is never applied since comments don't survive tonic-build's formatting
+pub const MINIMAL_ENUM_ATTRIBUTE: &str = "#[cfg_attr(any(), proto_gen_minimal_enum)]";
+
+/// Prost doesn't document every generated item, which breaks crates denying `missing_docs`
+const ALLOW_MISSING_DOCS: &str = "#![allow(missing_docs)]\n";
+
+/// Field number of `package` in `FileDescriptorProto`, used to find its comments
+const PACKAGE_FIELD_NUMBER: i32 = 2;
+
+type TopLevelModules = Vec<(String, Vec<ModuleTree>)>;
+
 fn generate_to_tmp(
     ws: &ProtoWorkspace,
     opts: Builder,
-    config: prost_build::Config,
+    mut config: prost_build::Config,
     gen_opts: &GenOptions,
-) -> Result<String, String> {
+    packages: &[(&str, &Path)],
+) -> Result<(TopLevelModules, FileDescriptorSet), String> {
+    if gen_opts.clean_tmp {
+        clean_tmp_dir(&ws.tmp_dir)?;
+    }
+    // Kept outside of the tmp dir, which should only contain generated code
+    let descriptor_dir = tempfile::tempdir()
+        .map_err(|e| format!("Failed to create tempdir for file descriptor set \n{e}"))?;
+    let descriptor_path = descriptor_dir.path().join("descriptors.bin");
+    if gen_opts.buf_lint {
+        crate::buf::lint(ws, gen_opts.verbosity)?;
+    }
+    let mut disable_comments = gen_opts.disable_comments.clone();
+    // Without a client or server, services are stripped from the descriptors first, otherwise
+    // prost runs protoc itself
+    let strip_services = gen_opts.omit_services && (ws.image.is_some() || declares_services(ws)?);
+    // Prost reads these descriptors from the path instead of running protoc
+    let prebuilt_descriptors = ws.image.is_some() || strip_services;
+    if let Some(image) = &ws.image {
+        fs::copy(image, &descriptor_path)
+            .map_err(|e| format!("Failed to copy image {image:?} to {descriptor_path:?} \n{e}"))?;
+    } else if prebuilt_descriptors {
+        run_protoc(ws, &descriptor_path)?;
+    }
+    // Prost reads a copy without the services, the ones in the report keep them for
+    // `--openapi` and the cached descriptors
+    let prost_descriptor_path = if strip_services {
+        let without_services = descriptor_dir
+            .path()
+            .join("descriptors_without_services.bin");
+        remove_services(&descriptor_path, &without_services)?;
+        without_services
+    } else {
+        descriptor_path.clone()
+    };
+    if prebuilt_descriptors {
+        config.skip_protoc_run();
+    }
+    let resolve_comments =
+        !gen_opts.disable_comments_regex.is_empty() || !gen_opts.retain_comments.is_empty();
+    if resolve_comments || gen_opts.serde_well_known {
+        // The paths need to be known before generating, so protoc has to run an extra time
+        if !prebuilt_descriptors {
+            run_protoc(ws, &descriptor_path)?;
+        }
+        let fds = read_descriptors(&descriptor_path)?;
+        if resolve_comments {
+            disable_comments = disabled_comment_paths(&fds, gen_opts);
+            // Replaces the literal paths, which are included again unless retaining
+            config.disable_comments(&disable_comments);
+        }
+        if gen_opts.serde_well_known {
+            for (path, attribute) in crate::serde_well_known::field_attributes(&fds) {
+                config.field_attribute(path, attribute);
+            }
+        }
+    }
+    config.file_descriptor_set_path(&prost_descriptor_path);
     let old_out = std::env::var("OUT_DIR");
     std::env::set_var("OUT_DIR", &ws.tmp_dir);
     // Would by nice if we could just get a byte buffer instead of magic env write
     opts.compile_with_config(config, &ws.proto_files, &ws.proto_dirs)
-        .map_err(|e| format!("Failed to compile protos from {:#?} \n{e}", ws.proto_dirs))?;
+        .map_err(|e| {
+            let e = e.to_string();
+            format!(
+                "Failed to compile protos from {:#?} \n{e}{}",
+                ws.proto_dirs,
+                proto2_hint(&e)
+            )
+        })?;
     // Restore the env, cause why not
     if let Ok(old) = old_out {
         std::env::set_var("OUT_DIR", old);
@@ -88,18 +856,561 @@ fn generate_to_tmp(
         std::env::remove_var("OUT_DIR");
     }
 
-    clean_up_file_structure(&ws.tmp_dir, gen_opts)
+    let fds = read_descriptors(&descriptor_path)?;
+    check_generated(ws, &fds, gen_opts)?;
+    if gen_opts.keep_empty_modules {
+        create_empty_modules(ws, &fds)?;
+    }
+    if !gen_opts.conversions.is_empty() {
+        let modules = crate::conversions::generate(&fds, &gen_opts.conversions)?;
+        append_to_packages(ws, modules, "conversions")?;
+    }
+    if !gen_opts.builders.is_empty() {
+        let modules = crate::builders::generate(&fds, &gen_opts.builders);
+        append_to_packages(ws, modules, "builders")?;
+    }
+    if !gen_opts.default_impl.is_empty() {
+        let modules = crate::defaults::generate(&fds, &gen_opts.default_impl);
+        append_to_packages(ws, modules, "defaults")?;
+    }
+    if gen_opts.serde_well_known {
+        let modules = crate::serde_well_known::generate(&fds);
+        append_to_packages(ws, modules, "serde_well_known")?;
+    }
+    let docs = if gen_opts.module_docs {
+        module_docs(&fds, &disable_comments)
+    } else {
+        HashMap::new()
+    };
+    // With `--only`, the modules that aren't regenerated are kept from the output dirs
+    let existing = if gen_opts.only.is_empty() {
+        vec![]
+    } else {
+        std::iter::once(ws.output_dir.as_path())
+            .chain(ws.package_outputs.iter().map(|(_, dir)| dir.as_path()))
+            .collect()
+    };
+    let top_mods = clean_up_file_structure(&ws.tmp_dir, gen_opts, &docs, packages, &existing)?;
+    Ok((top_mods, fds))
 }
 
-fn clean_up_file_structure(out_dir: &Path, gen_opts: &GenOptions) -> Result<String, String> {
+/// Checks the generated code against the descriptors, which contain the services even when
+/// they aren't generated
+fn check_generated(
+    ws: &ProtoWorkspace,
+    fds: &FileDescriptorSet,
+    gen_opts: &GenOptions,
+) -> Result<(), String> {
+    if !gen_opts.exclude_files.is_empty() {
+        exclude_packages(ws, fds, &gen_opts.exclude_files, !gen_opts.omit_services)?;
+    }
+    if gen_opts.flatten_packages {
+        check_flat_references(ws, fds, !gen_opts.omit_services)?;
+    }
+    if gen_opts.grpc_web && !gen_opts.omit_services {
+        check_grpc_web(fds)?;
+    }
+    Ok(())
+}
+
+/// gRPC-web only supports unary and server streaming calls, errors with the methods that
+/// stream from the client
+fn check_grpc_web(fds: &FileDescriptorSet) -> Result<(), String> {
+    let unsupported = fds
+        .file
+        .iter()
+        .flat_map(|file| {
+            file.service.iter().flat_map(move |service| {
+                service
+                    .method
+                    .iter()
+                    .filter(|method| method.client_streaming())
+                    .map(move |method| {
+                        if file.package().is_empty() {
+                            format!("{}.{}", service.name(), method.name())
+                        } else {
+                            format!("{}.{}.{}", file.package(), service.name(), method.name())
+                        }
+                    })
+            })
+        })
+        .collect::<Vec<_>>();
+    if unsupported.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "gRPC-web doesn't support client or bidirectional streaming, used by {}",
+            unsupported.join(", ")
+        ))
+    }
+}
+
+/// Appends the module generated for each package, such as `conversions`, to the file
+/// prost generated for the package
+fn append_to_packages(
+    ws: &ProtoWorkspace,
+    modules: BTreeMap<String, String>,
+    what: &str,
+) -> Result<(), String> {
+    for (package, module) in modules {
+        let file = ws.tmp_dir.join(package_file_name(&package));
+        let mut content = fs::read_to_string(&file)
+            .map_err(|e| format!("Failed to read generated file {file:?} for {what} \n{e}"))?;
+        content.push('\n');
+        content.push_str(&module);
+        fs::write(&file, content)
+            .map_err(|e| format!("Failed to write {what} to {file:?} \n{e}"))?;
+    }
+    Ok(())
+}
+
+/// The file prost generates for `package`, segments are snake cased with keywords escaped,
+/// like `my.MixedCase.type` in `my.mixed_case.r#type.rs`, and protos without a package are
+/// in `_.rs`
+fn package_file_name(package: &str) -> String {
+    prost_build::Module::from_protobuf_package_name(package).to_file_name_or("_")
+}
+
+/// Protoc writes no file for packages without any generated code,
+/// creates an empty one for each package of the given proto files that is missing
+fn create_empty_modules(ws: &ProtoWorkspace, fds: &FileDescriptorSet) -> Result<(), String> {
+    // Everything in an image is generated, unless it's the cached descriptors of the proto files
+    let requested = fds.file.iter().filter(|file| {
+        (ws.image.is_some() && ws.proto_files.is_empty())
+            || ws
+                .proto_files
+                .iter()
+                .any(|proto_file| proto_file.ends_with(file.name()))
+    });
+    for file in requested {
+        if file.package().is_empty() {
+            continue;
+        }
+        let path = ws.tmp_dir.join(package_file_name(file.package()));
+        if !path.exists() {
+            fs::write(&path, "")
+                .map_err(|e| format!("Failed to create empty module file {path:?} \n{e}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Prost refers to types in other packages with `super::` paths following the package
+/// hierarchy, which only resolve after flattening between packages with the same parent
+fn check_flat_references(
+    ws: &ProtoWorkspace,
+    fds: &FileDescriptorSet,
+    services: bool,
+) -> Result<(), String> {
+    for file in &fds.file {
+        let package = file.package();
+        // Only the generated files contain the paths
+        if !ws.tmp_dir.join(package_file_name(package)).is_file() {
+            continue;
+        }
+        for type_name in referenced_types(file, services) {
+            let Some(other) = crate::conversions::package_of(fds, type_name) else {
+                continue;
+            };
+            if other != package && parent_package(other) != parent_package(package) {
+                return Err(format!(
+                    "Can't flatten packages, {:?} refers to {type_name} from package {package:?}, references between packages only resolve when they have the same parent package",
+                    file.name()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The types the fields of `file` and the methods of its `services` if they're generated refer
+/// to that are generated in some package, scalars have no type name and well-known types are
+/// `prost_types` paths
+fn referenced_types(file: &FileDescriptorProto, services: bool) -> Vec<&str> {
+    let mut type_names = vec![];
+    let mut pending = file.message_type.iter().collect::<Vec<_>>();
+    while let Some(message) = pending.pop() {
+        pending.extend(&message.nested_type);
+        type_names.extend(message.field.iter().map(FieldDescriptorProto::type_name));
+    }
+    let methods = file.service.iter().flat_map(|service| &service.method);
+    for method in methods.filter(|_| services) {
+        type_names.extend([method.input_type(), method.output_type()]);
+    }
+    type_names
+        .retain(|type_name| !type_name.is_empty() && !type_name.starts_with(".google.protobuf."));
+    type_names
+}
+
+/// Removes the generated files of the packages whose proto files all match one of the
+/// `.gitignore` style `patterns`, such as protos only in a proto dir to be imported, so they
+/// don't end up in the module tree. Errors if the remaining code refers to their types.
+fn exclude_packages(
+    ws: &ProtoWorkspace,
+    fds: &FileDescriptorSet,
+    patterns: &[String],
+    services: bool,
+) -> Result<(), String> {
+    let mut rules = IgnoreRules::default();
+    for pattern in patterns {
+        rules.add(pattern);
+    }
+    let excluded = fds
+        .file
+        .iter()
+        .map(FileDescriptorProto::package)
+        .filter(|package| {
+            fds.file
+                .iter()
+                .filter(|file| file.package() == *package)
+                .all(|file| rules.matches(Path::new(file.name())))
+        })
+        .collect::<HashSet<_>>();
+    for file in &fds.file {
+        if excluded.contains(file.package())
+            || !ws.tmp_dir.join(package_file_name(file.package())).is_file()
+        {
+            continue;
+        }
+        for type_name in referenced_types(file, services) {
+            if let Some(other) = crate::conversions::package_of(fds, type_name) {
+                if excluded.contains(other) {
+                    return Err(format!(
+                        "Can't exclude the protos of package {other:?}, {:?} refers to {type_name} from it",
+                        file.name()
+                    ));
+                }
+            }
+        }
+    }
+    for package in excluded {
+        let path = ws.tmp_dir.join(package_file_name(package));
+        if path.is_file() {
+            fs::remove_file(&path)
+                .map_err(|e| format!("Failed to remove excluded module file {path:?} \n{e}"))?;
+        }
+    }
+    Ok(())
+}
+
+fn parent_package(package: &str) -> &str {
+    package.rsplit_once('.').map_or("", |(parent, _)| parent)
+}
+
+/// A temp dir for generated code, created under `base` if given so that it can be on the same
+/// file system as the output dir
+pub(crate) fn working_tempdir(base: Option<&Path>) -> std::io::Result<tempfile::TempDir> {
+    match base {
+        Some(base) => tempfile::tempdir_in(base),
+        None => tempfile::tempdir(),
+    }
+}
+
+/// Empties a reused `--tmp-dir` with `--clean-tmp` so that it starts out like a fresh tempdir, files left from an
+/// earlier run would otherwise be laid out as modules
+fn clean_tmp_dir(tmp_dir: &Path) -> Result<(), String> {
+    match fs::remove_dir_all(tmp_dir) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(format!("Failed to clean out tmp dir {tmp_dir:?} \n{e}")),
+    }
+    fs::create_dir_all(tmp_dir).map_err(|e| format!("Failed to create tmp dir {tmp_dir:?} \n{e}"))
+}
+
+/// The location of protoc's first error to summarize on top of the full output, empty if
+/// there's no `file:line:column: message`
+fn protoc_error_summary(protoc_error: &str) -> String {
+    let location = Regex::new(r"(?m)(?:^|\s)(\S+?):(\d+):(\d+): (.+)$")
+        .expect("Protoc error location regex is valid");
+    let summary = location
+        .captures_iter(protoc_error)
+        .find(|captures| !captures[4].starts_with("warning:"))
+        .map_or_else(String::new, |captures| {
+            format!(
+                "Error in {} at line {}, column {}: {}\n\n",
+                &captures[1], &captures[2], &captures[3], &captures[4]
+            )
+        });
+    summary
+}
+
+/// Protoc rejects proto2 only constructs in proto3 files without saying which syntax they
+/// belong to, so name it
+fn proto2_hint(protoc_error: &str) -> &'static str {
+    let required = protoc_error.contains("Required fields are not allowed in proto3");
+    let group = protoc_error.contains("Groups are not supported in proto3 syntax");
+    match (required, group) {
+        (true, true) => "\n`required` fields and `group`s are proto2 constructs, files using them need `syntax = \"proto2\";`",
+        (true, false) => "\n`required` fields are a proto2 construct, files using them need `syntax = \"proto2\";`",
+        (false, true) => "\n`group`s are a proto2 construct, files using them need `syntax = \"proto2\";`",
+        (false, false) => "",
+    }
+}
+
+fn read_descriptors(path: &Path) -> Result<FileDescriptorSet, String> {
+    let bytes =
+        fs::read(path).map_err(|e| format!("Failed to read file descriptor set {path:?} \n{e}"))?;
+    FileDescriptorSet::decode(bytes.as_slice())
+        .map_err(|e| format!("Failed to decode file descriptor set {path:?} \n{e}"))
+}
+
+/// Runs protoc the same way `prost-build` does to write the file descriptor set to `out`.
+/// Prost's command line is rebuilt here since the `Config` doesn't expose it, so arguments
+/// added with `Config::protoc_arg` would have to be passed here as well.
+fn run_protoc(ws: &ProtoWorkspace, out: &Path) -> Result<(), String> {
+    let protoc = find_protoc().ok_or_else(|| {
+        "Could not find protoc, set `PROTOC` to its path or add it to the `PATH`".to_string()
+    })?;
+    let mut cmd = Command::new(&protoc);
+    cmd.arg("--include_imports")
+        .arg("--include_source_info")
+        .arg("-o")
+        .arg(out);
+    for proto_dir in &ws.proto_dirs {
+        cmd.arg("-I").arg(proto_dir);
+    }
+    if let Some(protoc_include) = prost_build::protoc_include_from_env() {
+        cmd.arg("-I").arg(protoc_include);
+    }
+    cmd.args(&ws.proto_files);
+    let output = cmd
+        .output()
+        .map_err(|e| format!("Failed to run protoc at {protoc:?} \n{e}"))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(format!(
+            "Protoc failed to read protos from {:#?} \n{stderr}{}",
+            ws.proto_dirs,
+            proto2_hint(&stderr)
+        ))
+    }
+}
+
+/// Whether any of the proto files to generate declares a service, from their source so that
+/// protoc doesn't have to run first. A commented out service also counts.
+fn declares_services(ws: &ProtoWorkspace) -> Result<bool, String> {
+    let service = Regex::new(r"(?m)^\s*service\s+\w+\s*\{").expect("Service regex is valid");
+    for proto_file in &ws.proto_files {
+        let content = fs::read_to_string(proto_file)
+            .map_err(|e| format!("Failed to read proto file {proto_file:?} \n{e}"))?;
+        if service.is_match(&content) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// The protoc prost runs, from `PROTOC` or else the `PATH`, without panicking when there's
+/// none like `prost_build::protoc_from_env` does
+pub(crate) fn find_protoc() -> Option<PathBuf> {
+    if let Some(protoc) = std::env::var_os("PROTOC") {
+        return Some(PathBuf::from(protoc));
+    }
+    let name = format!("protoc{}", std::env::consts::EXE_SUFFIX);
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(&name))
+        .find(|path| path.is_file())
+}
+
+/// Writes the file descriptor set at `path` without its services to `out`. Without a client
+/// or server to generate, tonic still visits them and prost panics on packages that only
+/// have services.
+fn remove_services(path: &Path, out: &Path) -> Result<(), String> {
+    let mut fds = read_descriptors(path)?;
+    for file in &mut fds.file {
+        file.service.clear();
+    }
+    fs::write(out, fds.encode_to_vec())
+        .map_err(|e| format!("Failed to write file descriptor set {out:?} \n{e}"))
+}
+
+/// Every fully qualified proto path comments can be disabled for, such as `.my.package`,
+/// `.my.package.Message` or `.my.package.Message.field`
+fn proto_paths(fds: &FileDescriptorSet) -> Vec<String> {
+    fn push_message(paths: &mut Vec<String>, parent: &str, message: &DescriptorProto) {
+        let path = format!("{parent}.{}", message.name());
+        paths.extend(
+            message
+                .field
+                .iter()
+                .map(|field| format!("{path}.{}", field.name())),
+        );
+        for nested in &message.nested_type {
+            push_message(paths, &path, nested);
+        }
+        push_enums(paths, &path, &message.enum_type);
+        paths.push(path);
+    }
+    fn push_enums(
+        paths: &mut Vec<String>,
+        parent: &str,
+        enums: &[prost_types::EnumDescriptorProto],
+    ) {
+        for enum_type in enums {
+            let path = format!("{parent}.{}", enum_type.name());
+            paths.extend(
+                enum_type
+                    .value
+                    .iter()
+                    .map(|value| format!("{path}.{}", value.name())),
+            );
+            paths.push(path);
+        }
+    }
+    let mut paths = vec![];
+    for file in &fds.file {
+        let package = if file.package().is_empty() {
+            String::new()
+        } else {
+            format!(".{}", file.package())
+        };
+        for message in &file.message_type {
+            push_message(&mut paths, &package, message);
+        }
+        push_enums(&mut paths, &package, &file.enum_type);
+        for service in &file.service {
+            let path = format!("{package}.{}", service.name());
+            paths.extend(
+                service
+                    .method
+                    .iter()
+                    .map(|method| format!("{path}.{}", method.name())),
+            );
+            paths.push(path);
+        }
+        if !package.is_empty() {
+            paths.push(package);
+        }
+    }
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// The literal `--disable-comments` paths and every proto path matching a regex. With paths
+/// to retain comments for, every disabled proto path is listed instead, except the retained
+/// ones and their parents, since prost disables comments for everything below a path.
+fn disabled_comment_paths(fds: &FileDescriptorSet, gen_opts: &GenOptions) -> Vec<String> {
+    let paths = proto_paths(fds);
+    let matches_regex = |path: &str| {
+        gen_opts
+            .disable_comments_regex
+            .iter()
+            .any(|regex| regex.is_match(path))
+    };
+    if gen_opts.retain_comments.is_empty() {
+        let mut disabled = gen_opts.disable_comments.clone();
+        disabled.extend(paths.into_iter().filter(|path| matches_regex(path)));
+        return disabled;
+    }
+    let mut kept = HashSet::new();
+    for path in &paths {
+        if !gen_opts
+            .retain_comments
+            .iter()
+            .any(|retained| crate::builders::matches_path(path, retained))
+        {
+            continue;
+        }
+        let mut parent = path.as_str();
+        while kept.insert(parent) {
+            match parent.rsplit_once('.') {
+                Some((grandparent, _)) if !grandparent.is_empty() => parent = grandparent,
+                _ => break,
+            }
+        }
+    }
+    paths
+        .iter()
+        .filter(|path| !kept.contains(path.as_str()))
+        .filter(|path| {
+            gen_opts
+                .disable_comments
+                .iter()
+                .any(|disabled| crate::builders::matches_path(path, disabled))
+                || matches_regex(path)
+        })
+        .cloned()
+        .collect()
+}
+
+/// `//!` docs from the leading comments of the `package` statements, by package.
+/// Packages matching a disabled comment path, `.` or a fully qualified package prefix, are skipped.
+fn module_docs(fds: &FileDescriptorSet, disable_comments: &[String]) -> HashMap<String, String> {
+    let mut docs: HashMap<String, String> = HashMap::new();
+    for file in &fds.file {
+        let package = file.package();
+        let package_path = format!(".{package}");
+        let disabled = disable_comments.iter().any(|path| {
+            path == "." || *path == package_path || package_path.starts_with(&format!("{path}."))
+        });
+        if package.is_empty() || disabled {
+            continue;
+        }
+        let Some(comment) = file
+            .source_code_info
+            .as_ref()
+            .and_then(|info| {
+                info.location
+                    .iter()
+                    .find(|location| location.path == [PACKAGE_FIELD_NUMBER])
+            })
+            .and_then(|location| location.leading_comments.as_deref())
+        else {
+            continue;
+        };
+        let mut doc = String::new();
+        for line in comment.trim_end().lines() {
+            let _ = doc.write_fmt(format_args!("//!{line}\n"));
+        }
+        let package_docs = docs.entry(package.to_string()).or_default();
+        // Several files can share a package and its comment
+        if !package_docs.contains(&doc) {
+            if !package_docs.is_empty() {
+                package_docs.push_str("//!\n");
+            }
+            package_docs.push_str(&doc);
+        }
+    }
+    docs
+}
+
+/// Lays out the generated files as modules, returning the top level module content and
+/// modules for `out_dir` followed by each of the `packages` dirs
+fn clean_up_file_structure(
+    out_dir: &Path,
+    gen_opts: &GenOptions,
+    docs: &HashMap<String, String>,
+    packages: &[(&str, &Path)],
+    existing: &[&Path],
+) -> Result<TopLevelModules, String> {
     let rd = fs::read_dir(out_dir)
         .map_err(|e| format!("Failed read output dir {out_dir:?} when cleaning up files \n{e}"))?;
-    let mut out_modules = Module {
-        name: "dummy".to_string(),
-        location: out_dir.to_path_buf(),
-        children: HashMap::new(),
-        file: None,
-    };
+    let mut out_modules = std::iter::once(out_dir)
+        .chain(packages.iter().map(|(_, dir)| *dir))
+        .map(|dir| Module {
+            name: "dummy".to_string(),
+            location: dir.to_path_buf(),
+            children: HashMap::new(),
+            file: None,
+            existing: None,
+            package: None,
+        })
+        .collect::<Vec<Module>>();
+    // Nested one level deeper, the module file declares the wrap module inline
+    if let Some(wrap_module) = &gen_opts.wrap_module {
+        for out_module in &mut out_modules {
+            out_module.location.push(wrap_module);
+            fs::create_dir_all(&out_module.location).map_err(|e| {
+                format!(
+                    "Failed to create dir for wrap module {:?} \n{e}",
+                    out_module.location
+                )
+            })?;
+        }
+    }
     for entry in rd {
         let entry = entry.map_err(|e| {
             format!(
@@ -111,38 +1422,102 @@ fn clean_up_file_structure(out_dir: &Path, gen_opts: &GenOptions) -> Result<Stri
         let metadata = entry.metadata().map_err(|e| format!("Failed to get metadata for entity {file_path:?} in output dir {out_dir:?} when cleaning up files \n{e}"))?;
         if metadata.is_file() {
             // Tonic build 0.7 generates a bunch of empty files for some reason, fixed in 0.8
-            let content = fs::read(&file_path).map_err(|e| {
-                format!("Failed to read generated file at path {file_path:?} \n{e}")
-            })?;
-            if content.is_empty() {
+            if metadata.len() == 0 && !gen_opts.keep_empty_modules {
                 fs::remove_file(&file_path).map_err(|e| {
                     format!("Failed to delete empty file {file_path:?} from temp directory \n{e}")
                 })?;
             } else {
-                out_modules.push_file(out_dir, &file_path)?;
+                let out_module = packages
+                    .iter()
+                    .position(|(package, _)| top_level_package(&file_path) == Some(package))
+                    .map_or(0, |ind| ind + 1);
+                let location = out_modules[out_module].location.clone();
+                if gen_opts.flatten_packages {
+                    out_modules[out_module].push_flat(&location, &file_path)?;
+                } else {
+                    out_modules[out_module].push_file(&location, &file_path)?;
+                }
             }
         }
     }
+    // The regenerated modules are spliced into the ones already in the output dirs
+    for (out_module, output_dir) in out_modules.iter_mut().zip(existing) {
+        let mut output_dir = output_dir.to_path_buf();
+        if let Some(wrap_module) = &gen_opts.wrap_module {
+            output_dir.push(wrap_module);
+        }
+        let location = out_module.location.clone();
+        out_module.splice_existing(&location, &output_dir)?;
+    }
+    out_modules
+        .into_iter()
+        .map(|out_module| dump_top_level_module(out_module, gen_opts, docs))
+        .collect()
+}
+
+/// The top level package of a generated file, `my.package.rs` is in `my`
+fn top_level_package(file_path: &Path) -> Option<&str> {
+    let file_name = file_path.file_stem()?.to_str()?;
+    let top = file_name.split_once('.').map_or(file_name, |(top, _)| top);
+    Some(top.strip_prefix("r#").unwrap_or(top))
+}
+
+/// Writes the modules below `out_modules` into their dirs, returning the content of the module
+/// file declaring them and their module trees
+fn dump_top_level_module(
+    out_modules: Module,
+    gen_opts: &GenOptions,
+    docs: &HashMap<String, String>,
+) -> Result<(String, Vec<ModuleTree>), String> {
     let mut sortable_children = out_modules
         .children
         .into_values()
         .collect::<Vec<Rc<RefCell<Module>>>>();
     // Linting, guh
     let mut top_level_mod = String::new();
-    prepend_header(gen_opts.prepend_header.as_ref(), &mut top_level_mod);
-    top_level_mod.push_str("#![allow(clippy::doc_markdown, clippy::use_self)]\n");
+    prepend_header(gen_opts, &mut top_level_mod);
+    if !gen_opts.no_clippy_allows {
+        top_level_mod.push_str("#![allow(clippy::doc_markdown, clippy::use_self)]\n");
+    }
+    if gen_opts.allow_missing_docs {
+        top_level_mod.push_str(ALLOW_MISSING_DOCS);
+    }
 
     if let Some(toplevel_attribute) = &gen_opts.toplevel_attribute {
         top_level_mod.push_str(toplevel_attribute);
         top_level_mod.push('\n');
     }
 
-    sortable_children.sort_by(|a, b| a.borrow().get_name().cmp(b.borrow().get_name()));
+    sortable_children.sort_by(|a, b| {
+        gen_opts
+            .sort_modules
+            .compare(a.borrow().get_name(), b.borrow().get_name())
+    });
+    let mut modules = vec![];
+    let mut declarations = String::new();
     for module in sortable_children {
-        module.borrow_mut().dump_to_disk(gen_opts)?;
-        let _ = top_level_mod.write_fmt(format_args!("pub mod {};\n", module.borrow().get_name()));
+        modules.push(module.borrow().to_tree("", gen_opts.sort_modules));
+        module.borrow_mut().dump_to_disk(gen_opts, docs, "")?;
+        declarations.push_str(
+            &gen_opts
+                .module_visibility
+                .declare(module.borrow().get_name()),
+        );
+    }
+    if let Some(wrap_module) = &gen_opts.wrap_module {
+        let _ = writeln!(
+            top_level_mod,
+            "{}mod {wrap_module} {{",
+            gen_opts.module_visibility.prefix()
+        );
+        for declaration in declarations.lines() {
+            let _ = writeln!(top_level_mod, "    {declaration}");
+        }
+        top_level_mod.push_str("}\n");
+    } else {
+        top_level_mod.push_str(&declarations);
     }
-    Ok(top_level_mod)
+    Ok((top_level_mod, modules))
 }
 
 #[derive(Debug)]
@@ -151,6 +1526,11 @@ struct Module {
     location: PathBuf,
     children: HashMap<String, Rc<RefCell<Module>>>,
     file: Option<PathBuf>,
+    /// This module's file in the output dir, if it's kept from a previous generation instead
+    /// of being regenerated with `--only`
+    existing: Option<PathBuf>,
+    /// Full proto package of a module flattened to the last segment of its package
+    package: Option<String>,
 }
 
 impl Module {
@@ -169,6 +1549,24 @@ impl Module {
         Ok(())
     }
 
+    /// Pushes a generated file as a child named after the last segment of its package,
+    /// instead of nesting it in a module per segment
+    fn push_flat(&mut self, top_level: &Path, path: &Path) -> Result<(), String> {
+        let package = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| format!("Failed to get package of generated file {path:?}"))?
+            .split('.')
+            .map(|segment| segment.strip_prefix("r#").unwrap_or(segment))
+            .collect::<Vec<_>>();
+        let name = package.last().copied().unwrap_or_default();
+        self.push_recurse(top_level, path, name)?;
+        if let Some(child) = self.children.get(&module_ident(name)) {
+            child.borrow_mut().package = Some(package.join("."));
+        }
+        Ok(())
+    }
+
     fn push_recurse(
         &mut self,
         parent: &Path,
@@ -176,42 +1574,160 @@ impl Module {
         raw_name: &str,
     ) -> Result<(), String> {
         if let Some((cur, rest)) = raw_name.split_once('.') {
-            let new_parent = parent.join(cur);
-            if let Some(child) = self.children.get(cur) {
+            let cur = module_ident(cur);
+            // Raw identifiers are only used in the `mod` declaration, not the path
+            let new_parent = parent.join(cur.strip_prefix("r#").unwrap_or(&cur));
+            if let Some(child) = self.children.get(&cur) {
                 child.borrow_mut().push_recurse(&new_parent, path, rest)?;
             } else {
                 let md = Rc::new(RefCell::new(Module {
-                    name: cur.to_string(),
+                    name: cur.clone(),
                     location: parent.to_path_buf(),
                     children: HashMap::new(),
                     file: None,
+                    existing: None,
+                    package: None,
                 }));
-                self.children.insert(cur.to_string(), md.clone());
+                self.children.insert(cur, md.clone());
                 md.borrow_mut().push_recurse(&new_parent, path, rest)?;
             }
-        } else if let Some(old) = self.children.get(raw_name) {
-            assert!(old.borrow().file.is_none(), "Logic error");
-            old.borrow_mut().file = Some(path.as_ref().to_path_buf());
         } else {
-            self.children.insert(
-                raw_name.to_string(),
-                Rc::new(RefCell::new(Module {
-                    name: raw_name.to_string(),
-                    location: parent.to_path_buf(),
+            let name = module_ident(raw_name);
+            if let Some(old) = self.children.get(&name) {
+                let mut old = old.borrow_mut();
+                // Packages that only differ in characters that aren't valid in module names
+                if let Some(old_file) = &old.file {
+                    return Err(format!(
+                        "Generated files {old_file:?} and {:?} both map to module {name} at {:?}, rename one of their packages",
+                        path.as_ref(),
+                        parent.join(format!("{}.rs", old.proper_file_name()))
+                    ));
+                }
+                old.file = Some(path.as_ref().to_path_buf());
+            } else {
+                self.children.insert(
+                    name.clone(),
+                    Rc::new(RefCell::new(Module {
+                        name,
+                        location: parent.to_path_buf(),
 
-                    children: HashMap::default(),
-                    file: Some(path.as_ref().to_path_buf()),
-                })),
-            );
+                        children: HashMap::default(),
+                        file: Some(path.as_ref().to_path_buf()),
+                        existing: None,
+                        package: None,
+                    })),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the modules with files in `output_dir` that weren't regenerated, so they stay
+    /// declared, `location` is where this module's children are laid out
+    fn splice_existing(&mut self, location: &Path, output_dir: &Path) -> Result<(), String> {
+        let rd = match fs::read_dir(output_dir) {
+            Ok(rd) => rd,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(format!("Failed to read output dir {output_dir:?} \n{e}")),
+        };
+        for entry in rd {
+            let path = entry
+                .map_err(|e| format!("Failed to read entry in output dir {output_dir:?} \n{e}"))?
+                .path();
+            let Some(stem) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .filter(|_| path.is_file() && has_ext(&path, "rs"))
+            else {
+                continue;
+            };
+            // Split service modules are declared by their package's own file
+            if stem.strip_suffix("_service") == Some(self.proper_file_name()) {
+                continue;
+            }
+            let name = module_ident(stem);
+            let child = self
+                .children
+                .entry(name.clone())
+                .or_insert_with(|| {
+                    Rc::new(RefCell::new(Module {
+                        name,
+                        location: location.to_path_buf(),
+                        children: HashMap::new(),
+                        file: None,
+                        existing: None,
+                        package: None,
+                    }))
+                })
+                .clone();
+            let mut child = child.borrow_mut();
+            if child.file.is_none() {
+                child.existing = Some(path.clone());
+            }
+            let child_location = location.join(stem);
+            child.splice_existing(&child_location, &output_dir.join(stem))?;
         }
         Ok(())
     }
 
-    fn dump_to_disk(&self, gen_opts: &GenOptions) -> Result<(), String> {
+    /// Whether neither this module nor any of its children were regenerated
+    fn is_untouched(&self) -> bool {
+        self.file.is_none()
+            && self
+                .children
+                .values()
+                .all(|child| child.borrow().is_untouched())
+    }
+
+    /// Writes the kept file of a module that wasn't regenerated, with declarations added for
+    /// its new child modules
+    fn dump_existing(
+        &self,
+        existing: &Path,
+        gen_opts: &GenOptions,
+        docs: &HashMap<String, String>,
+        package: &str,
+    ) -> Result<(), String> {
+        let content = fs::read_to_string(existing)
+            .map_err(|e| format!("Failed to read kept module file {existing:?} \n{e}"))?;
+        let mut names = self.children.keys().map(String::as_str).collect::<Vec<_>>();
+        names.sort_by(|a, b| gen_opts.sort_modules.compare(a, b));
+        let content = merge_declarations(&content, &names, gen_opts);
+        let dir = self.location.join(self.proper_file_name());
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("Failed to create module directory for {dir:?} \n{e}"))?;
+        let file_location = self
+            .location
+            .join(format!("{}.rs", self.proper_file_name()));
+        fs::write(&file_location, content)
+            .map_err(|e| format!("Failed to write file contents to {file_location:?} \n{e}"))?;
+        for child in self.children.values() {
+            child.borrow().dump_to_disk(gen_opts, docs, package)?;
+        }
+        Ok(())
+    }
+
+    fn dump_to_disk(
+        &self,
+        gen_opts: &GenOptions,
+        docs: &HashMap<String, String>,
+        parent_package: &str,
+    ) -> Result<(), String> {
+        if self.is_untouched() {
+            return Ok(());
+        }
+        let package = self.package(parent_package);
+        if let Some(existing) = &self.existing {
+            return self.dump_existing(existing, gen_opts, docs, &package);
+        }
+        if gen_opts.inline_children && !self.children.is_empty() {
+            return self.dump_inline(gen_opts, docs, &package);
+        }
+        let module_docs = docs.get(&package).map_or("", String::as_str);
         let module_expose_output = if self.children.is_empty() {
             None
         } else {
-            let dir = self.location.join(&self.name);
+            let dir = self.location.join(self.proper_file_name());
             fs::create_dir_all(&dir)
                 .map_err(|e| format!("Failed to create module directory for {dir:?} \n{e}"))?;
             let mut sortable_children = self
@@ -221,16 +1737,23 @@ impl Module {
             sortable_children.sort_by(|a, b| {
                 let a_borrow = a.borrow();
                 let b_borrow = b.borrow();
-                a_borrow.get_name().cmp(b_borrow.get_name())
+                gen_opts
+                    .sort_modules
+                    .compare(a_borrow.get_name(), b_borrow.get_name())
             });
-            let mut output = String::new();
-            prepend_header(gen_opts.prepend_header.as_ref(), &mut output);
+            let mut output = module_docs.to_string();
+            if gen_opts.allow_missing_docs {
+                output.push_str(ALLOW_MISSING_DOCS);
+            }
             for sorted_child in sortable_children {
-                let _ = output.write_fmt(format_args!(
-                    "pub mod {};\n",
-                    sorted_child.borrow().get_name()
-                ));
-                sorted_child.borrow().dump_to_disk(gen_opts)?;
+                output.push_str(
+                    &gen_opts
+                        .module_visibility
+                        .declare(sorted_child.borrow().get_name()),
+                );
+                sorted_child
+                    .borrow()
+                    .dump_to_disk(gen_opts, docs, &package)?;
             }
             Some(output)
         };
@@ -246,13 +1769,18 @@ impl Module {
                     .map_err(|e| format!("Failed to read created file {file:?} \n{e}"))?;
                 module_header.push('\n');
                 module_header.push_str(&file_content);
-                let mut clean = hide_doctests(&module_header);
+                let mut clean =
+                    self.split_services(gen_opts, post_process(&module_header, gen_opts))?;
 
-                prepend_header(gen_opts.prepend_header.as_ref(), &mut clean);
+                prepend_header(gen_opts, &mut clean);
+                single_trailing_newline(&mut clean);
 
                 fs::write(&file_location, clean.as_bytes()).map_err(|e| {
                     format!("Failed to write file contents to {file_location:?} \n{e}")
                 })?;
+                if gen_opts.verbosity.is_verbose() {
+                    println!("Wrote {file_location:?}");
+                }
                 // Don't remove if same file
                 if !is_same_file {
                     fs::remove_file(file).map_err(|e| {
@@ -266,44 +1794,310 @@ impl Module {
                 fs::remove_file(file)
                     .map_err(|e| format!("Failed to remove original file from {file:?} \n{e}"))?;
 
-                let mut clean_content = hide_doctests(&file_content);
+                let mut clean_content = self.split_services(
+                    gen_opts,
+                    post_process(&format!("{module_docs}{file_content}"), gen_opts),
+                )?;
 
-                prepend_header(gen_opts.prepend_header.as_ref(), &mut clean_content);
+                prepend_header(gen_opts, &mut clean_content);
+                single_trailing_newline(&mut clean_content);
 
                 fs::write(&file_location, clean_content.as_bytes()).map_err(|e| {
                     format!("Failed to write file contents to {file_location:?} \n{e}")
                 })?;
+                if gen_opts.verbosity.is_verbose() {
+                    println!("Wrote {file_location:?}");
+                }
             }
         } else if let Some(module_header) = module_expose_output {
-            let mod_file_location = self.location.join(format!("{}.rs", self.name));
-            fs::write(&mod_file_location, module_header.as_bytes()).map_err(|e| {
-                format!("Failed to write module file at {mod_file_location:?} \n{e}")
-            })?;
+            self.write_module_file(gen_opts, module_header)?;
         } else {
-            panic!("Bad code");
+            return Err(format!(
+                "Module {} at {:?} has neither a generated file nor child modules to write",
+                self.name, self.location
+            ));
+        }
+        Ok(())
+    }
+
+    /// Writes a single file for this module and all of its children
+    fn dump_inline(
+        &self,
+        gen_opts: &GenOptions,
+        docs: &HashMap<String, String>,
+        package: &str,
+    ) -> Result<(), String> {
+        let mut content = self.inline_content(gen_opts, docs, package)?;
+        prepend_header(gen_opts, &mut content);
+        single_trailing_newline(&mut content);
+        let file_location = self
+            .location
+            .join(format!("{}.rs", self.proper_file_name()));
+        fs::write(&file_location, content.as_bytes())
+            .map_err(|e| format!("Failed to write file contents to {file_location:?} \n{e}"))?;
+        if gen_opts.verbosity.is_verbose() {
+            println!("Wrote {file_location:?}");
+        }
+        Ok(())
+    }
+
+    /// The content of this module with its child modules inline, removing the generated files
+    /// it's made of
+    fn inline_content(
+        &self,
+        gen_opts: &GenOptions,
+        docs: &HashMap<String, String>,
+        package: &str,
+    ) -> Result<String, String> {
+        let mut content = docs.get(package).cloned().unwrap_or_default();
+        if !self.children.is_empty() {
+            if gen_opts.allow_missing_docs {
+                content.push_str(ALLOW_MISSING_DOCS);
+            }
+            let mut sortable_children = self.children.values().collect::<Vec<_>>();
+            sortable_children.sort_by(|a, b| {
+                gen_opts
+                    .sort_modules
+                    .compare(a.borrow().get_name(), b.borrow().get_name())
+            });
+            for child in sortable_children {
+                let child = child.borrow();
+                let child_content =
+                    child.inline_content(gen_opts, docs, &child.package(package))?;
+                let _ = writeln!(
+                    content,
+                    "{}mod {} {{",
+                    gen_opts.module_visibility.prefix(),
+                    child.get_name()
+                );
+                for line in child_content.lines() {
+                    if !line.is_empty() {
+                        content.push_str("    ");
+                        content.push_str(line);
+                    }
+                    content.push('\n');
+                }
+                content.push_str("}\n");
+            }
+        }
+        if let Some(file) = &self.file {
+            let file_content = fs::read_to_string(file)
+                .map_err(|e| format!("Failed to read created file {file:?} \n{e}"))?;
+            fs::remove_file(file)
+                .map_err(|e| format!("Failed to remove original file from {file:?} \n{e}"))?;
+            if !self.children.is_empty() {
+                content.push('\n');
+            }
+            content.push_str(&post_process(&file_content, gen_opts));
+        }
+        Ok(content)
+    }
+
+    /// Writes the file of a module that only declares its child modules
+    fn write_module_file(
+        &self,
+        gen_opts: &GenOptions,
+        mut module_header: String,
+    ) -> Result<(), String> {
+        prepend_module_header(gen_opts, &mut module_header);
+        single_trailing_newline(&mut module_header);
+        let mod_file_location = self
+            .location
+            .join(format!("{}.rs", self.proper_file_name()));
+        fs::write(&mod_file_location, module_header.as_bytes())
+            .map_err(|e| format!("Failed to write module file at {mod_file_location:?} \n{e}"))?;
+        if gen_opts.verbosity.is_verbose() {
+            println!("Wrote {mod_file_location:?}");
         }
         Ok(())
     }
 
-    #[inline]
-    fn get_name(&self) -> &str {
-        self.name.as_str()
+    /// Moves the tonic service modules out of `content` into a `<name>_service` child module,
+    /// which is re-exported so the service modules keep their paths
+    fn split_services(&self, gen_opts: &GenOptions, content: String) -> Result<String, String> {
+        if !gen_opts.split_services {
+            return Ok(content);
+        }
+        let (rest, services) = split_service_modules(&content);
+        if services.is_empty() {
+            return Ok(content);
+        }
+        let dir = self.location.join(self.proper_file_name());
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("Failed to create module directory for {dir:?} \n{e}"))?;
+        let service_name = format!("{}_service", self.proper_file_name());
+        let mut service_content = services;
+        prepend_header(gen_opts, &mut service_content);
+        single_trailing_newline(&mut service_content);
+        let service_file = dir.join(format!("{service_name}.rs"));
+        fs::write(&service_file, service_content.as_bytes())
+            .map_err(|e| format!("Failed to write service file to {service_file:?} \n{e}"))?;
+        if gen_opts.verbosity.is_verbose() {
+            println!("Wrote {service_file:?}");
+        }
+        // Inner docs have to stay first
+        let docs_end = rest
+            .lines()
+            .take_while(|line| line.starts_with("//!"))
+            .map(|line| line.len() + 1)
+            .sum::<usize>();
+        let (docs, rest) = rest.split_at(docs_end);
+        Ok(format!(
+            "{docs}{}pub use {service_name}::*;\n{rest}",
+            gen_opts.module_visibility.declare(&service_name)
+        ))
+    }
+
+    fn package(&self, parent_package: &str) -> String {
+        if let Some(package) = &self.package {
+            package.clone()
+        } else if parent_package.is_empty() {
+            self.proper_file_name().to_string()
+        } else {
+            format!("{parent_package}.{}", self.proper_file_name())
+        }
+    }
+
+    fn to_tree(&self, parent_package: &str, sort: ModuleSort) -> ModuleTree {
+        let package = self.package(parent_package);
+        let mut children = self
+            .children
+            .values()
+            .map(|child| child.borrow().to_tree(&package, sort))
+            .collect::<Vec<ModuleTree>>();
+        children.sort_by(|a, b| sort.compare(&a.name, &b.name));
+        ModuleTree {
+            name: self.name.clone(),
+            package,
+            children,
+            source_file: self
+                .file
+                .as_ref()
+                .and_then(|file| file.file_name())
+                .map(PathBuf::from),
+        }
+    }
+
+    #[inline]
+    fn get_name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    #[inline]
+    fn proper_file_name(&self) -> &str {
+        if self.name.starts_with("r#") {
+            &self.name[2..]
+        } else {
+            self.name.as_str()
+        }
+    }
+}
+
+/// The module file `content` with its declarations replaced by ones for all of `names`, in
+/// order. Without declarations, they're laid out after the header and module docs like in a
+/// newly generated file.
+fn merge_declarations(content: &str, names: &[&str], gen_opts: &GenOptions) -> String {
+    let is_declaration = |line: &str| {
+        line.split_once("mod ").is_some_and(|(prefix, name)| {
+            !prefix.starts_with([' ', '/'])
+                && name
+                    .strip_suffix(';')
+                    .is_some_and(|name| names.contains(&name))
+        })
+    };
+    let lines = content.lines().collect::<Vec<_>>();
+    let start = lines.iter().position(|line| is_declaration(line));
+    let mut merged = String::with_capacity(content.len());
+    let (start, end) = if let Some(start) = start {
+        let end = start
+            + lines[start..]
+                .iter()
+                .take_while(|line| is_declaration(line))
+                .count();
+        (start, end)
+    } else {
+        let start = lines
+            .iter()
+            .take_while(|line| {
+                line.is_empty() || (line.starts_with("//") && !line.starts_with("///"))
+            })
+            .count();
+        (start, start)
+    };
+    for line in &lines[..start] {
+        let _ = writeln!(merged, "{line}");
+    }
+    if start == end && gen_opts.allow_missing_docs {
+        merged.push_str(ALLOW_MISSING_DOCS);
+    }
+    for name in names {
+        merged.push_str(&gen_opts.module_visibility.declare(name));
+    }
+    if start == end {
+        merged.push('\n');
+    }
+    for line in &lines[end..] {
+        let _ = writeln!(merged, "{line}");
+    }
+    merged
+}
+
+/// Turns a package segment into a valid module name, characters that aren't allowed are
+/// replaced with `_` and a leading digit gets a `_` prefix. Raw identifiers are kept as is.
+pub(crate) fn module_ident(segment: &str) -> String {
+    if segment.starts_with("r#") {
+        return segment.to_string();
+    }
+    let mut ident = segment
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    if ident.is_empty() || ident.starts_with(|c: char| c.is_ascii_digit()) {
+        ident.insert(0, '_');
+    }
+    ident
+}
+
+fn prepend_header(gen_opts: &GenOptions, clean_content: &mut String) {
+    prepend_header_text(gen_opts, gen_opts.prepend_header.as_ref(), clean_content);
+}
+
+/// Files that only declare child modules get the module header if one is supplied
+fn prepend_module_header(gen_opts: &GenOptions, clean_content: &mut String) {
+    let header = gen_opts
+        .module_header
+        .as_ref()
+        .or(gen_opts.prepend_header.as_ref());
+    prepend_header_text(gen_opts, header, clean_content);
+}
+
+fn prepend_header_text(gen_opts: &GenOptions, header: Option<&String>, clean_content: &mut String) {
+    if let Some(header) = header {
+        clean_content.insert_str(0, header);
     }
-
-    #[inline]
-    fn proper_file_name(&self) -> &str {
-        if self.name.starts_with("r#") {
-            &self.name[2..]
-        } else {
-            self.name.as_str()
-        }
+    // Always on top so that tools looking for it find it
+    if gen_opts.generated_marker {
+        clean_content.insert_str(0, GENERATED_MARKER);
     }
 }
 
-fn prepend_header(maybe_prepend_header: Option<&String>, clean_content: &mut String) {
-    if let Some(prepend_header) = maybe_prepend_header {
-        clean_content.insert_str(0, prepend_header);
+/// The module file declaring the modules of `output_dir`, `--mod-file` for the workspace's
+/// output dir and otherwise a file named after the dir in its parent
+pub(crate) fn module_file(ws: &ProtoWorkspace, output_dir: &Path) -> Result<PathBuf, String> {
+    if let Some(mod_file) = ws.mod_file.as_ref().filter(|_| output_dir == ws.output_dir) {
+        return Ok(mod_file.clone());
     }
+    let name = as_file_name_string(output_dir)?;
+    let parent = output_dir.parent().ok_or_else(|| {
+        format!("Failed to find parent for output dir {output_dir:?} to place mod file")
+    })?;
+    Ok(parent.join(format!("{name}.rs")))
 }
 
 fn as_file_name_string(path: impl AsRef<Path>) -> Result<String, String> {
@@ -320,101 +2114,486 @@ fn as_file_name_string(path: impl AsRef<Path>) -> Result<String, String> {
 fn run_diff(
     orig: impl AsRef<Path> + Debug,
     new: impl AsRef<Path> + Debug,
+    old_top_mod_path: &Path,
     new_mod: &str,
+    gen_opts: &GenOptions,
+    ignore: &IgnoreRules,
 ) -> Result<usize, String> {
+    let verbosity = gen_opts.verbosity;
     let orig_root = orig.as_ref();
     let orig_root_file_name = orig_root
         .file_name()
         .ok_or_else(|| format!("Failed to get filename when diffing original path {orig:?}"))?;
     let orig_root_file = orig_root_file_name.to_str()
     .ok_or_else(|| format!("Failed to convert filename {orig_root_file_name:?} when diffing original path {orig:?}"))?;
-    let mut orig_files = collect_files(&orig, orig_root_file)?;
+    let mut orig_files = collect_files(&orig, orig_root_file, verbosity)?;
+    orig_files.retain(|file| !ignore.is_ignored(file));
     let new_root = new.as_ref();
     let new_root_file_name = new_root
         .file_name()
         .ok_or_else(|| format!("Failed to get filename when diffing new path {new:?}"))?;
     let new_root_file = new_root_file_name.to_str()
     .ok_or_else(|| format!("Failed to convert filename {new_root_file_name:?} to utf8 when diffing new path {new:?}"))?;
-    let new_files = collect_files(&new, new_root_file)?;
-    let mut diff = 0;
-    for file in &new_files {
-        if orig_files.remove(file) {
-            let orig_path = orig.as_ref().join(file);
-            let new_path = new.as_ref().join(file);
-            let a = fs::read(&orig_path)
-                .map_err(|e| format!("Failed to read file at {orig_path:?} \n{e}"))?;
-            let b = fs::read(&new_path)
-                .map_err(|e| format!("Failed to read file at {new_path:?} \n{e}"))?;
-            if a != b {
-                eprintln!("Found diff in {file:?}");
-                diff += 1;
+    let new_files = collect_files(&new, new_root_file, verbosity)?
+        .into_iter()
+        .filter(|file| !ignore.is_ignored(file))
+        .collect::<Vec<PathBuf>>();
+    let statuses = map_entries(new_files, |file| {
+        let status = if orig_files.contains(&file) {
+            let orig_path = orig_root.join(&file);
+            // Read back from disk since rustfmt and custom sections rewrite the generated
+            // files after they're laid out
+            let new_path = new_root.join(&file);
+            if files_equal(&orig_path, &new_path)?
+                || (gen_opts.ignore_format_diff && formatted_files_equal(&orig_path, &new_path)?)
+            {
+                FileStatus::Unchanged
+            } else {
+                FileStatus::Changed
             }
         } else {
-            eprintln!("Found new proto at {file:?}");
-            diff += 1;
-        }
+            FileStatus::New
+        };
+        Ok((file, status))
+    })?;
+    let mut compared = statuses.len();
+    let mut changes = vec![];
+    for (file, status) in statuses {
+        orig_files.remove(&file);
+        let change = match status {
+            FileStatus::Unchanged => continue,
+            FileStatus::Changed => Change::Changed,
+            FileStatus::New => Change::New,
+        };
+        changes.push((change, file));
     }
-    let old_top_mod_name = as_file_name_string(&orig)?;
-
-    let old_top_mod_path = orig
-        .as_ref()
-        .parent()
-        .ok_or_else(|| {
-            format!("Failed to diff module file, no parent dir found for out dir {orig_root:?}")
-        })?
-        .join(format!("{old_top_mod_name}.rs"));
-    match fs::read(&old_top_mod_path) {
+    // No longer generated, such as after renaming a package. With `--only`, the protos
+    // outside of the subset aren't generated but still kept.
+    if !gen_opts.only.is_empty() {
+        orig_files.clear();
+    }
+    compared += orig_files.len();
+    changes.extend(orig_files.into_iter().map(|file| (Change::Removed, file)));
+    let mut diff = changes.len();
+    print_changes(&changes, orig_root, gen_opts);
+    let mod_change = match fs::read(old_top_mod_path) {
         Ok(content) => {
-            if content != new_mod.as_bytes() {
-                diff += 1;
-            }
+            let equal = contents_equal(&content, new_mod.as_bytes())
+                || (gen_opts.ignore_format_diff
+                    && formatted_equal(&String::from_utf8_lossy(&content), new_mod)?);
+            (!equal).then_some(Change::Changed)
         }
-        Err(ref e) if e.kind() == ErrorKind::NotFound => diff += 1,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Some(Change::New),
         Err(e) => {
             return Err(format!(
                 "Failed to read old mod file at {old_top_mod_path:?} \n{e}"
             ));
         }
     };
-
-    for _ in orig_files {
+    if let Some(change) = mod_change {
         diff += 1;
+        if gen_opts.porcelain {
+            println!("{}\t{}", change.porcelain(), old_top_mod_path.display());
+        }
+    }
+    // A wrong output dir would otherwise pass quietly with nothing to compare
+    if verbosity.is_verbose() {
+        println!(
+            "Compared {compared} files in {orig_root:?} and the module file {old_top_mod_path:?}"
+        );
     }
     Ok(diff)
 }
 
-fn collect_files(source: impl AsRef<Path> + Debug, root: &str) -> Result<HashSet<PathBuf>, String> {
-    let rd = fs::read_dir(&source);
+/// How a file in the output dir differs from the generated code
+#[derive(Debug, Clone, Copy)]
+enum Change {
+    Changed,
+    New,
+    Removed,
+}
+
+impl Change {
+    fn message(self) -> (&'static str, &'static str) {
+        match self {
+            Self::Changed => ("Found diff in", YELLOW),
+            Self::New => ("Found new proto at", GREEN),
+            Self::Removed => ("Found removed proto at", RED),
+        }
+    }
+
+    /// The first field of the line printed with `--porcelain`
+    fn porcelain(self) -> &'static str {
+        match self {
+            Self::Changed => "diff",
+            Self::New => "new",
+            Self::Removed => "removed",
+        }
+    }
+}
+
+/// Prints the `changes` to the files in the output dir `orig_root`, as a line with the full
+/// path of each with `--porcelain` and otherwise up to `--max-diff-print` messages
+fn print_changes(changes: &[(Change, PathBuf)], orig_root: &Path, gen_opts: &GenOptions) {
+    if gen_opts.porcelain {
+        for (change, file) in changes {
+            println!("{}\t{}", change.porcelain(), orig_root.join(file).display());
+        }
+    } else {
+        let printed = gen_opts
+            .max_diff_print
+            .map_or(changes.len(), |max| max.min(changes.len()));
+        let color = gen_opts.color.enabled();
+        for (change, file) in &changes[..printed] {
+            let (message, code) = change.message();
+            eprintln!("{} {file:?}", paint(message, code, color));
+        }
+        if printed < changes.len() {
+            eprintln!("... and {} more", changes.len() - printed);
+        }
+    }
+    for (_, file) in changes {
+        gen_opts.emit(GenEvent::DiffFound(file));
+    }
+}
+
+/// Carries custom sections over from the files in the output dir `old` and its module file
+/// `old_mod_file` to their newly generated counterparts in `new`, appending them at the end
+/// of the file.
+fn preserve_custom_sections(
+    old: &Path,
+    new: &Path,
+    old_mod_file: &Path,
+    top_mod_content: &mut String,
+) -> Result<(), String> {
+    let read_existing = |path: &Path| match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!(
+            "Failed to read {path:?} to look for custom sections \n{e}"
+        )),
+    };
+    if let Some(old_content) = read_existing(old_mod_file)? {
+        append_custom_sections(top_mod_content, &old_content);
+    }
+    let new_root = as_file_name_string(new)?;
+    for file in collect_files(new, &new_root, Verbosity::Quiet)? {
+        let Some(old_content) = read_existing(&old.join(&file))? else {
+            continue;
+        };
+        if !old_content.contains(CUSTOM_BEGIN_MARKER) {
+            continue;
+        }
+        let new_path = new.join(&file);
+        let mut content = fs::read_to_string(&new_path)
+            .map_err(|e| format!("Failed to read generated file {new_path:?} \n{e}"))?;
+        append_custom_sections(&mut content, &old_content);
+        fs::write(&new_path, content)
+            .map_err(|e| format!("Failed to write custom sections to {new_path:?} \n{e}"))?;
+    }
+    Ok(())
+}
+
+fn append_custom_sections(content: &mut String, old_content: &str) {
+    let mut sections = String::new();
+    let mut in_section = false;
+    // Blank lines in front of a section are kept so that a section at the end of a file
+    // ends up exactly where it was
+    let mut blank_lines = 0;
+    for line in old_content.lines() {
+        let trimmed = line.trim();
+        if trimmed == CUSTOM_BEGIN_MARKER {
+            in_section = true;
+            sections.push_str(&"\n".repeat(blank_lines));
+        }
+        if in_section {
+            let _ = sections.write_fmt(format_args!("{line}\n"));
+        }
+        if trimmed == CUSTOM_END_MARKER {
+            in_section = false;
+        }
+        blank_lines = if trimmed.is_empty() {
+            blank_lines + 1
+        } else {
+            0
+        };
+    }
+    if !sections.is_empty() {
+        if !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push_str(&sections);
+    }
+}
+
+/// Removes the generated files matching the ignore rules, so they aren't copied over the ones
+/// in the output dir
+fn remove_ignored(new: &Path, ignore: &IgnoreRules) -> Result<(), String> {
+    for file in collect_files(new, &as_file_name_string(new)?, Verbosity::Quiet)? {
+        if ignore.is_ignored(&file) {
+            let path = new.join(file);
+            fs::remove_file(&path)
+                .map_err(|e| format!("Failed to remove ignored generated file {path:?} \n{e}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the `orphans` from the output dir `old` along with the dirs they leave empty,
+/// everything else in it is left untouched
+fn remove_orphans(old: &Path, orphans: &[PathBuf]) -> Result<(), String> {
+    for orphan in orphans {
+        let path = old.join(orphan);
+        fs::remove_file(&path)
+            .map_err(|e| format!("Failed to remove orphaned file {path:?} \n{e}"))?;
+        let mut dir = path.parent();
+        while let Some(parent) = dir.filter(|dir| *dir != old) {
+            let empty = fs::read_dir(parent).is_ok_and(|mut entries| entries.next().is_none());
+            if !empty {
+                break;
+            }
+            fs::remove_dir(parent)
+                .map_err(|e| format!("Failed to remove empty dir {parent:?} \n{e}"))?;
+            dir = parent.parent();
+        }
+    }
+    Ok(())
+}
+
+/// Files in the output dir `old` that aren't replaced by the generated code in `new`,
+/// sorted and without ignored files
+fn orphaned_files(old: &Path, new: &Path, ignore: &IgnoreRules) -> Result<Vec<PathBuf>, String> {
+    let new_files = collect_files(new, &as_file_name_string(new)?, Verbosity::Quiet)?;
+    Ok(
+        collect_files(old, &as_file_name_string(old)?, Verbosity::Quiet)?
+            .into_iter()
+            .filter(|file| !new_files.contains(file) && !ignore.is_ignored(file))
+            .collect(),
+    )
+}
+
+/// The `orphans` in the output dir `old` that aren't part of the module tree the existing
+/// `mod_file` declares, deleting them is likely a mistake such as a mistyped output dir
+fn foreign_files(old: &Path, mod_file: &Path, orphans: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let tracked = tracked_files(old, mod_file)?;
+    Ok(orphans
+        .iter()
+        .filter(|file| !tracked.contains(*file))
+        .cloned()
+        .collect())
+}
+
+/// The files of the module tree an earlier generation wrote, following the `mod` declarations
+/// from `mod_file`, relative to the output dir `old`
+fn tracked_files(old: &Path, mod_file: &Path) -> Result<HashSet<PathBuf>, String> {
+    let mut tracked = HashSet::new();
+    let mut pending = vec![(mod_file.to_path_buf(), PathBuf::new())];
+    while let Some((file, dir)) = pending.pop() {
+        let content = match fs::read_to_string(&file) {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(format!("Failed to read module file {file:?} \n{e}")),
+        };
+        for name in declared_modules(&content) {
+            let relative = dir.join(format!("{name}.rs"));
+            if tracked.insert(relative.clone()) {
+                pending.push((old.join(&relative), dir.join(name)));
+            }
+        }
+    }
+    Ok(tracked)
+}
+
+/// The modules declared in their own file, like `pub mod name;`, named like their files
+/// without the `r#` of keywords. Declarations in custom sections are hand written.
+fn declared_modules(content: &str) -> Vec<&str> {
+    let mut modules = vec![];
+    let mut in_section = false;
+    for line in content.lines() {
+        let trimmed = line.trim();
+        if trimmed == CUSTOM_BEGIN_MARKER {
+            in_section = true;
+        } else if trimmed == CUSTOM_END_MARKER {
+            in_section = false;
+        } else if in_section {
+            continue;
+        }
+        let Some((_, name)) = trimmed
+            .strip_suffix(';')
+            .and_then(|declaration| declaration.rsplit_once("mod "))
+        else {
+            continue;
+        };
+        let name = name.trim_start_matches("r#");
+        if name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            modules.push(name);
+        }
+    }
+    modules
+}
+
+enum FileStatus {
+    Unchanged,
+    Changed,
+    New,
+}
+
+/// Maps entries in order, in parallel if the `rayon` feature is enabled
+fn map_entries<T: Send, R: Send>(
+    entries: Vec<T>,
+    f: impl Fn(T) -> Result<R, String> + Send + Sync,
+) -> Result<Vec<R>, String> {
+    #[cfg(feature = "rayon")]
+    {
+        use rayon::iter::{IntoParallelIterator, ParallelIterator};
+        entries.into_par_iter().map(f).collect()
+    }
+    #[cfg(not(feature = "rayon"))]
+    {
+        entries.into_iter().map(f).collect()
+    }
+}
+
+fn files_equal(a: &Path, b: &Path) -> Result<bool, String> {
+    let open = |path: &Path| {
+        let file =
+            fs::File::open(path).map_err(|e| format!("Failed to open file at {path:?} \n{e}"))?;
+        let len = file
+            .metadata()
+            .map_err(|e| format!("Failed to get metadata for file at {path:?} \n{e}"))?
+            .len();
+        Ok::<_, String>((file, len))
+    };
+    let (mut a_file, a_len) = open(a)?;
+    let (mut b_file, b_len) = open(b)?;
+    if a_len != b_len {
+        // Might only differ in trailing newlines, rare enough to read both fully,
+        // through the already open files so that neither is opened twice
+        let read = |file: &mut fs::File, path: &Path, len: u64| {
+            let mut content = Vec::with_capacity(usize::try_from(len).unwrap_or_default());
+            file.read_to_end(&mut content)
+                .map_err(|e| format!("Failed to read file at {path:?} \n{e}"))?;
+            Ok::<_, String>(content)
+        };
+        return Ok(contents_equal(
+            &read(&mut a_file, a, a_len)?,
+            &read(&mut b_file, b, b_len)?,
+        ));
+    }
+    readers_equal(BufReader::new(a_file), BufReader::new(b_file))
+        .map_err(|e| format!("Failed to compare files at {a:?} and {b:?} \n{e}"))
+}
+
+/// Whether the files contain the same code after formatting both
+fn formatted_files_equal(a: &Path, b: &Path) -> Result<bool, String> {
+    let read = |path: &Path| {
+        fs::read_to_string(path).map_err(|e| format!("Failed to read file at {path:?} \n{e}"))
+    };
+    formatted_equal(&read(a)?, &read(b)?)
+}
+
+/// Whether the code is the same after formatting both, code `rustfmt` can't parse never is
+fn formatted_equal(a: &str, b: &str) -> Result<bool, String> {
+    let (a, b) = (fmt(a)?, fmt(b)?);
+    // Nothing is printed for code that fails to parse
+    Ok(!a.is_empty() && !b.is_empty() && contents_equal(a.as_bytes(), b.as_bytes()))
+}
+
+/// Compares contents ignoring any trailing newlines, a lone newline at the end of a file
+/// is easily added or dropped by editors and tools
+fn contents_equal(a: &[u8], b: &[u8]) -> bool {
+    fn trim(content: &[u8]) -> &[u8] {
+        let end = content
+            .iter()
+            .rposition(|&byte| byte != b'\n')
+            .map_or(0, |last| last + 1);
+        &content[..end]
+    }
+    trim(a) == trim(b)
+}
+
+/// Makes `content` end in exactly one newline, empty content is left empty
+fn single_trailing_newline(content: &mut String) {
+    content.truncate(content.trim_end_matches('\n').len());
+    if !content.is_empty() {
+        content.push('\n');
+    }
+}
+
+/// Compares two readers chunk by chunk, stopping at the first difference
+fn readers_equal(mut a: impl Read, mut b: impl Read) -> std::io::Result<bool> {
+    let mut a_buf = [0u8; 8192];
+    let mut b_buf = [0u8; 8192];
+    loop {
+        let read = a.read(&mut a_buf)?;
+        if read == 0 {
+            return Ok(b.read(&mut b_buf)? == 0);
+        }
+        match b.read_exact(&mut b_buf[..read]) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        if a_buf[..read] != b_buf[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Files under `source` relative to `root`, sorted so that anything written or printed from
+/// them doesn't depend on the order the file system lists entries in
+fn collect_files(
+    source: impl AsRef<Path> + Debug,
+    root: &str,
+    verbosity: Verbosity,
+) -> Result<BTreeSet<PathBuf>, String> {
+    let source = source.as_ref();
+    let rd = fs::read_dir(source);
     match rd {
         Ok(rd) => {
-            let mut all_files = HashSet::new();
-            for entry in rd {
-                let entry = entry.map_err(|e| {
-                    format!("Failed to read entry when checking for file diff at {source:?} \n{e}")
-                })?;
+            let entries = rd.collect::<Result<Vec<_>, _>>().map_err(|e| {
+                format!("Failed to read entry when checking for file diff at {source:?} \n{e}")
+            })?;
+            let collected = map_entries(entries, |entry| {
                 let entry_path = entry.path();
                 let metadata = entry.metadata().map_err(|e| format!("Failed to get metadata for entry {entry_path:?} when checking for file diff at {source:?} \n{e}"))?;
                 if metadata.is_file() {
+                    if verbosity.is_verbose() {
+                        println!("Collected {entry_path:?}");
+                    }
                     let pb = path_from_starts_with(root, &entry_path)?;
-                    all_files.insert(pb);
+                    Ok(BTreeSet::from([pb]))
                 } else if metadata.is_dir() {
-                    all_files.extend(collect_files(entry_path, root)?);
+                    collect_files(entry_path, root, verbosity)
                 } else {
-                    return Err(format!("Found something that's neither a file or dir at {entry_path:?} while recursively collecting files at {source:?}"));
+                    Err(format!("Found something that's neither a file or dir at {entry_path:?} while recursively collecting files at {source:?}"))
                 }
-            }
-            Ok(all_files)
+            })?;
+            Ok(collected.into_iter().flatten().collect())
         }
-        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(BTreeSet::new()),
         Err(e) => Err(format!(
             "Got error reading dir {source:?} to check diff \n{e}"
         )),
     }
 }
 
-fn recurse_copy_clean(
+/// Where `dir` points if it's a symlink, otherwise `dir` itself
+fn resolve_symlink(dir: &Path) -> Result<PathBuf, String> {
+    match fs::symlink_metadata(dir) {
+        Ok(metadata) if metadata.file_type().is_symlink() => fs::canonicalize(dir).map_err(|e| {
+            format!(
+                "Failed to resolve symlinked output dir {dir:?}, its target may not exist \n{e}"
+            )
+        }),
+        _ => Ok(dir.to_path_buf()),
+    }
+}
+
+pub(crate) fn recurse_copy_clean(
     source: impl AsRef<Path> + Debug,
     dest: impl AsRef<Path> + Debug,
+    file_mode: Option<u32>,
 ) -> Result<(), String> {
     if dest.as_ref().exists() {
         fs::remove_dir_all(&dest)
@@ -441,13 +2620,35 @@ fn recurse_copy_clean(
     })? {
         let entry =
             entry.map_err(|e| format!("Failed to read entry to copy generated protos \n{e}"))?;
-        recurse_copy_over(dest_top, entry.path())?;
+        recurse_copy_over(dest_top, entry.path(), file_mode)?;
     }
 
     Ok(())
 }
 
-fn recurse_copy_over(dest_top: &Path, entry: impl AsRef<Path> + Debug) -> Result<(), String> {
+/// Copies everything in the dir `new` into `old`, overwriting but not removing files
+fn copy_into(
+    new: &Path,
+    old: impl AsRef<Path> + Debug,
+    file_mode: Option<u32>,
+) -> Result<(), String> {
+    let old = old.as_ref();
+    fs::create_dir_all(old).map_err(|e| format!("Failed to create output dir {old:?} \n{e}"))?;
+    for entry in fs::read_dir(new)
+        .map_err(|e| format!("Failed to read generated dir {new:?} to copy from \n{e}"))?
+    {
+        let entry =
+            entry.map_err(|e| format!("Failed to read entry to copy generated protos \n{e}"))?;
+        recurse_copy_over(old, entry.path(), file_mode)?;
+    }
+    Ok(())
+}
+
+fn recurse_copy_over(
+    dest_top: &Path,
+    entry: impl AsRef<Path> + Debug,
+    file_mode: Option<u32>,
+) -> Result<(), String> {
     let path = entry.as_ref();
     let metadata = path.metadata().map_err(|e| {
         format!("Failed to get metadata for {path:?} to copy to generated protos from \n{e}")
@@ -460,7 +2661,7 @@ fn recurse_copy_over(dest_top: &Path, entry: impl AsRef<Path> + Debug) -> Result
         fs::copy(path, &new_dir).map_err(|e| {
             format!("Failed to copy generated file from {path:?} to {new_dir:?} \n{e}")
         })?;
-        Ok(())
+        set_file_mode(&new_dir, file_mode)
     } else if metadata.is_dir() {
         fs::create_dir_all(&new_dir).map_err(|e| {
             format!("Failed to create dir to place generated proto at {new_dir:?} \n{e}")
@@ -470,7 +2671,7 @@ fn recurse_copy_over(dest_top: &Path, entry: impl AsRef<Path> + Debug) -> Result
         {
             let entry = entry
                 .map_err(|e| format!("Failed to read entry while recursively copying \n{e}"))?;
-            recurse_copy_over(&new_dir, entry.path())?;
+            recurse_copy_over(&new_dir, entry.path(), file_mode)?;
         }
         Ok(())
     } else {
@@ -480,6 +2681,20 @@ fn recurse_copy_over(dest_top: &Path, entry: impl AsRef<Path> + Debug) -> Result
     }
 }
 
+/// Gives a written output file the permissions from `--file-mode`, so they don't depend on
+/// the umask. Permissions are left alone off unix.
+fn set_file_mode(path: &Path, file_mode: Option<u32>) -> Result<(), String> {
+    #[cfg(unix)]
+    if let Some(mode) = file_mode {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .map_err(|e| format!("Failed to set permissions of {path:?} to {mode:o} \n{e}"))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (path, file_mode);
+    Ok(())
+}
+
 #[inline]
 fn path_from_starts_with(root: &str, path: impl AsRef<Path> + Debug) -> Result<PathBuf, String> {
     let mut components = path.as_ref().components().rev();
@@ -501,68 +2716,377 @@ fn path_from_starts_with(root: &str, path: impl AsRef<Path> + Debug) -> Result<P
         "Failed to trim path up to {root} for proto generated file at: {path:?}. Could not find {root}. "
     ));
     }
-    backwards_components.reverse();
-    let pb = backwards_components.into_iter().collect::<PathBuf>();
-    Ok(pb)
+    backwards_components.reverse();
+    let pb = backwards_components.into_iter().collect::<PathBuf>();
+    Ok(pb)
+}
+
+fn recurse_fmt(base: impl AsRef<Path>, gen_opts: &GenOptions) -> Result<(), String> {
+    let path = base.as_ref();
+    for file in
+        fs::read_dir(path).map_err(|e| format!("failed to read_dir for path {path:?} \n{e}"))?
+    {
+        let entry = file.map_err(|e| format!("Failed to read entry in path {path:?} \n{e}"))?;
+        let metadata = entry
+            .metadata()
+            .map_err(|e| format!("Failed to read metadata for entry {entry:?} \n{e}"))?;
+        let path = entry.path();
+        if metadata.is_file() && has_ext(&path, "rs") {
+            gen_opts.emit(GenEvent::Formatting(&path));
+            let out = std::process::Command::new("rustfmt")
+                .arg(&path)
+                .arg("--edition")
+                .arg("2021")
+                .output()
+                .map_err(|e| format!("Failed to format generated code \n{e}"))?;
+            if !out.status.success() {
+                return Err(format!(
+                    "Failed to format, rustfmt returned error status {} with stderr {:?}",
+                    out.status,
+                    String::from_utf8(out.stderr)
+                ));
+            }
+        } else if metadata.is_dir() {
+            recurse_fmt(path, gen_opts)?;
+        }
+    }
+    Ok(())
+}
+
+fn fmt(code: &str) -> Result<String, String> {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut child = std::process::Command::new("rustfmt")
+        .arg("--edition")
+        .arg("2021")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to format, failed to launch rustfmt\n{e}"))?;
+
+    let child_stdin = child.stdin.as_mut().unwrap();
+    child_stdin
+        .write_all(code.as_bytes())
+        .map_err(|e| format!("Failed to format, failed to write data to rustfmt \n{e}"))?;
+    // drop(child_stdin);
+
+    let formatted_code = String::from_utf8(
+        child
+            .wait_with_output()
+            .map_err(|e| format!("Failed to format, rustfmt failed to run \n{e}"))?
+            .stdout,
+    )
+    .map_err(|e| format!("Failed to read formtted generated code \n{e}"))?;
+    Ok(formatted_code)
+}
+
+/// Codec tonic-build uses in the generated clients and servers for prost messages
+const PROST_CODEC_PATH: &str = "tonic::codec::ProstCodec";
+
+fn post_process(content: &str, gen_opts: &GenOptions) -> String {
+    let mut clean = hide_doctests(content);
+    if gen_opts.derive_eq {
+        clean = apply_derive_eq(&clean);
+    }
+    if gen_opts.no_partial_eq_allow {
+        clean = strip_partial_eq_allows(&clean);
+    }
+    if gen_opts.non_exhaustive_enums {
+        clean = restrict_non_exhaustive_to_enums(&clean);
+    }
+    if gen_opts.minimal_enums {
+        clean = strip_enum_str_names(&clean);
+    }
+    if let Some(codec_path) = &gen_opts.codec_path {
+        // Our tonic-build has no `codec_path` for prost services, the codec is always this
+        clean = clean.replace(PROST_CODEC_PATH, codec_path);
+    }
+    if gen_opts.generate_mocks {
+        clean.push_str(&service_mocks(&clean, gen_opts.server_feature.as_deref()));
+    }
+    if gen_opts.server_feature.is_some() || gen_opts.client_feature.is_some() {
+        clean = gate_service_modules(
+            &clean,
+            gen_opts.server_feature.as_deref(),
+            gen_opts.client_feature.as_deref(),
+        );
+    }
+    clean
+}
+
+/// Puts a `#[cfg(feature = "...")]` on the tonic server and client modules. They are
+/// recognized by the doc comment tonic puts above them, since a nested message module
+/// may have the same name as a service module.
+fn gate_service_modules(
+    content: &str,
+    server_feature: Option<&str>,
+    client_feature: Option<&str>,
+) -> String {
+    let mut new_content = String::with_capacity(content.len());
+    let mut pending_feature = None;
+    for line in content.lines() {
+        let trimmed = line.trim();
+        if trimmed == "/// Generated server implementations." {
+            pending_feature = server_feature;
+        } else if trimmed == "/// Generated client implementations." {
+            pending_feature = client_feature;
+        } else if trimmed.starts_with("pub mod ") {
+            if let Some(feature) = pending_feature.take() {
+                let indent = &line[..line.len() - line.trim_start().len()];
+                let _ = new_content
+                    .write_fmt(format_args!("{indent}#[cfg(feature = \"{feature}\")]\n"));
+            }
+        } else if !trimmed.starts_with("#[") {
+            pending_feature = None;
+        }
+        let _ = new_content.write_fmt(format_args!("{line}\n"));
+    }
+    new_content
+}
+
+/// A `#[cfg(test)]` gated `mocks` module with a mock implementation of every tonic server
+/// trait in `content`. Each mock has an optional handler closure per method, methods without
+/// a handler return `Status::unimplemented`.
+fn service_mocks(content: &str, server_feature: Option<&str>) -> String {
+    let mut mocks = String::new();
+    let mut after_server_doc = false;
+    let mut server_mod = None;
+    let mut trait_body: Option<(String, Vec<&str>)> = None;
+    for line in content.lines() {
+        if let Some((trait_name, body)) = trait_body.as_mut() {
+            if line == "    }" {
+                let server_mod = server_mod.unwrap_or_default();
+                mocks.push_str(&mock_impl(server_mod, trait_name, &body.join(" ")));
+                trait_body = None;
+            } else if !line.trim_start().starts_with("///") {
+                body.push(line.trim());
+            }
+        } else if line == "/// Generated server implementations." {
+            after_server_doc = true;
+        } else if after_server_doc && line.starts_with("pub mod ") {
+            server_mod = line
+                .strip_prefix("pub mod ")
+                .and_then(|rest| rest.strip_suffix(" {"));
+            after_server_doc = false;
+        } else if line == "}" {
+            server_mod = None;
+        } else if let Some(rest) = line.strip_prefix("    pub trait ") {
+            if server_mod.is_some() {
+                let trait_name = rest.split(':').next().unwrap_or(rest).trim();
+                trait_body = Some((trait_name.to_string(), vec![]));
+            }
+        }
+    }
+    if mocks.is_empty() {
+        return mocks;
+    }
+    let cfg = server_feature.map_or_else(
+        || "#[cfg(test)]".to_string(),
+        |feature| format!("#[cfg(all(test, feature = \"{feature}\"))]"),
+    );
+    format!(
+        "/// Generated mock service implementations for testing.\n{cfg}\npub mod mocks {{\n{mocks}}}\n"
+    )
+}
+
+/// A mock struct implementing the server trait, `body` is the trait body on one line
+fn mock_impl(server_mod: &str, trait_name: &str, body: &str) -> String {
+    let mut fields = String::new();
+    let mut methods = String::new();
+    let mut streams = HashMap::new();
+    for item in body.split(';').map(str::trim) {
+        if let Some(stream) = item.strip_prefix("type ") {
+            let name = stream.split(':').next().unwrap_or(stream).trim();
+            let item_type = stream
+                .split_once("Item = std::result::Result<")
+                .and_then(|(_, rest)| rest.rsplit_once(", tonic::Status>"))
+                .map_or("()", |(item_type, _)| item_type.trim());
+            let stream_type = format!("tonic::codegen::BoxStream<{item_type}>");
+            let _ = writeln!(methods, "        type {name} = {stream_type};");
+            streams.insert(format!("Self::{name}"), stream_type);
+        } else if let Some(method) = item.strip_prefix("async fn ") {
+            let Some((name, signature)) = method.split_once('(') else {
+                continue;
+            };
+            let Some((params, output)) = signature.rsplit_once(") -> ") else {
+                continue;
+            };
+            let request = params
+                .split_once("request: ")
+                .map_or("", |(_, request)| request)
+                .trim()
+                .trim_end_matches(',')
+                .trim();
+            let output = output.trim();
+            // Associated types can't be named from the struct
+            let handler_output = streams
+                .iter()
+                .fold(output.to_string(), |output, (stream, stream_type)| {
+                    output.replace(stream, stream_type)
+                });
+            let _ = writeln!(
+                fields,
+                "        pub {name}: Option<Box<dyn Fn({request}) -> {handler_output} + Send + Sync>>,"
+            );
+            let _ = writeln!(
+                methods,
+                "        async fn {name}(&self, request: {request}) -> {output} {{\n            match &self.{name} {{\n                Some(handler) => handler(request),\n                None => Err(tonic::Status::unimplemented(\"{name} is not mocked\")),\n            }}\n        }}"
+            );
+        }
+    }
+    format!(
+        "    /// Mock of [`super::{server_mod}::{trait_name}`], methods without a handler return `Status::unimplemented`.\n    #[derive(Default)]\n    #[allow(clippy::type_complexity)]\n    pub struct Mock{trait_name} {{\n{fields}    }}\n    #[tonic::async_trait]\n    impl super::{server_mod}::{trait_name} for Mock{trait_name} {{\n{methods}    }}\n"
+    )
+}
+
+/// Splits the top level tonic server and client modules, recognized by their doc comment,
+/// from the rest of the content. Since they end up one module deeper, their relative paths
+/// get an extra `super::`.
+fn split_service_modules(content: &str) -> (String, String) {
+    let mut rest = String::with_capacity(content.len());
+    let mut services = String::new();
+    let mut in_service = false;
+    for line in content.lines() {
+        if line == "/// Generated server implementations."
+            || line == "/// Generated client implementations."
+        {
+            in_service = true;
+        }
+        if in_service {
+            let _ = writeln!(services, "{}", deepen_super_paths(line));
+            if line == "}" {
+                in_service = false;
+            }
+        } else {
+            let _ = writeln!(rest, "{line}");
+        }
+    }
+    (rest, services)
 }
 
-fn recurse_fmt(base: impl AsRef<Path>) -> Result<(), String> {
-    let path = base.as_ref();
-    for file in
-        fs::read_dir(path).map_err(|e| format!("failed to read_dir for path {path:?} \n{e}"))?
-    {
-        let entry = file.map_err(|e| format!("Failed to read entry in path {path:?} \n{e}"))?;
-        let metadata = entry
-            .metadata()
-            .map_err(|e| format!("Failed to read metadata for entry {entry:?} \n{e}"))?;
-        let path = entry.path();
-        if metadata.is_file() && has_ext(&path, "rs") {
-            let out = std::process::Command::new("rustfmt")
-                .arg(&path)
-                .arg("--edition")
-                .arg("2021")
-                .output()
-                .map_err(|e| format!("Failed to format generated code \n{e}"))?;
-            if !out.status.success() {
-                return Err(format!(
-                    "Failed to format, rustfmt returned error status {} with stderr {:?}",
-                    out.status,
-                    String::from_utf8(out.stderr)
-                ));
-            }
-        } else if metadata.is_dir() {
-            recurse_fmt(path)?;
+/// Adds a `super::` in front of every chain of `super::`
+fn deepen_super_paths(line: &str) -> String {
+    let mut deepened = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find("super::") {
+        let is_chain_start = !rest[..start].ends_with(|c: char| c.is_alphanumeric() || c == '_')
+            && !line[..line.len() - rest.len() + start].ends_with("super::");
+        deepened.push_str(&rest[..start]);
+        if is_chain_start {
+            deepened.push_str("super::");
         }
+        deepened.push_str("super::");
+        rest = &rest[start + "super::".len()..];
     }
-    Ok(())
+    deepened.push_str(rest);
+    deepened
 }
 
-fn fmt(code: &str) -> Result<String, String> {
-    use std::io::Write;
-    use std::process::Stdio;
+/// Types that derive `Eq` don't need the `derive_partial_eq_without_eq` allow that prost adds,
+/// so we strip it. Enums already derive `Eq` and `Hash`, if they got caught by a path
+/// we remove our attribute from them instead, since it would produce conflicting impls.
+fn apply_derive_eq(content: &str) -> String {
+    let mut new_content = String::with_capacity(content.len());
+    let mut pending_derive = None;
+    for line in content.lines() {
+        let trimmed = line.trim();
+        if trimmed == DERIVE_EQ_ATTRIBUTE {
+            pending_derive = Some(new_content.len()..new_content.len() + line.len() + 1);
+        } else if let Some(derive_range) = pending_derive.take() {
+            if trimmed == PARTIAL_EQ_ALLOW {
+                continue;
+            } else if trimmed.starts_with("#[derive(") && trimmed.contains(" Eq,") {
+                new_content.replace_range(derive_range, "");
+            } else if trimmed.starts_with("#[") {
+                pending_derive = Some(derive_range);
+            }
+        }
+        let _ = new_content.write_fmt(format_args!("{line}\n"));
+    }
+    new_content
+}
 
-    let mut child = std::process::Command::new("rustfmt")
-        .arg("--edition")
-        .arg("2021")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .map_err(|e| format!("Failed to format, failed to launch rustfmt\n{e}"))?;
+fn strip_partial_eq_allows(content: &str) -> String {
+    let mut new_content = String::with_capacity(content.len());
+    for line in content
+        .lines()
+        .filter(|line| line.trim() != PARTIAL_EQ_ALLOW)
+    {
+        let _ = writeln!(new_content, "{line}");
+    }
+    new_content
+}
 
-    let child_stdin = child.stdin.as_mut().unwrap();
-    child_stdin
-        .write_all(code.as_bytes())
-        .map_err(|e| format!("Failed to format, failed to write data to rustfmt \n{e}"))?;
-    // drop(child_stdin);
+/// Prost applies enum attributes to both proto enums and oneofs, oneofs are always
+/// exhaustive in the proto definition so we remove our attribute from them.
+fn restrict_non_exhaustive_to_enums(content: &str) -> String {
+    let mut new_content = String::with_capacity(content.len());
+    let mut pending_attribute = None;
+    for line in content.lines() {
+        let trimmed = line.trim();
+        if trimmed == NON_EXHAUSTIVE_ATTRIBUTE {
+            pending_attribute = Some(new_content.len()..new_content.len() + line.len() + 1);
+        } else if let Some(attribute_range) = pending_attribute.take() {
+            if trimmed.starts_with("#[derive(") && trimmed.ends_with("::Oneof)]") {
+                new_content.replace_range(attribute_range, "");
+            } else if trimmed.starts_with("#[") && !trimmed.starts_with("#[derive(") {
+                pending_attribute = Some(attribute_range);
+            }
+        }
+        let _ = new_content.write_fmt(format_args!("{line}\n"));
+    }
+    new_content
+}
 
-    let formatted_code = String::from_utf8(
-        child
-            .wait_with_output()
-            .map_err(|e| format!("Failed to format, rustfmt failed to run \n{e}"))?
-            .stdout,
-    )
-    .map_err(|e| format!("Failed to read formtted generated code \n{e}"))?;
-    Ok(formatted_code)
+/// Removes the `impl` prost puts right after enums marked with [`MINIMAL_ENUM_ATTRIBUTE`],
+/// which only holds `as_str_name` and `from_str_name`. Everything else, like `TryFrom<i32>`,
+/// `From<Enum> for i32`, `is_valid` and `Default`, comes from the `Enumeration` derive and is
+/// needed by the generated messages. Oneofs have no such `impl`, only the marker is removed.
+fn strip_enum_str_names(content: &str) -> String {
+    let mut new_content = String::with_capacity(content.len());
+    let mut marked = false;
+    // The marked enum's indentation and name, once its declaration is found
+    let mut enum_decl: Option<(&str, &str)> = None;
+    // The closing line of the `impl` being skipped
+    let mut skip_until = None;
+    let mut lines = content.lines().peekable();
+    while let Some(line) = lines.next() {
+        let trimmed = line.trim();
+        if let Some(end) = &skip_until {
+            if line == end {
+                skip_until = None;
+            }
+            continue;
+        }
+        if trimmed == MINIMAL_ENUM_ATTRIBUTE {
+            marked = true;
+            continue;
+        }
+        if marked {
+            if trimmed.ends_with("::Oneof)]") {
+                marked = false;
+            } else if let Some(name) = trimmed
+                .strip_prefix("pub enum ")
+                .and_then(|rest| rest.strip_suffix(" {"))
+            {
+                marked = false;
+                enum_decl = Some((&line[..line.len() - line.trim_start().len()], name));
+            }
+        } else if let Some((indent, name)) = enum_decl {
+            if line == format!("{indent}}}")
+                && lines
+                    .peek()
+                    .is_some_and(|next| *next == format!("{indent}impl {name} {{"))
+            {
+                enum_decl = None;
+                lines.next();
+                skip_until = Some(format!("{indent}}}"));
+            }
+        }
+        let _ = new_content.write_fmt(format_args!("{line}\n"));
+    }
+    new_content
 }
 
 /// Rustdoc assumes all comments with 4 or more spaces or three backticks are things it absolutely
@@ -618,8 +3142,32 @@ pub fn has_ext(path: &Path, ext: &str) -> bool {
 
 #[cfg(test)]
 mod tests {
-    use crate::gen::{path_from_starts_with, run_diff};
-    use std::path::Path;
+    use crate::gen::{
+        append_custom_sections, apply_derive_eq, declared_modules, deepen_super_paths,
+        gate_service_modules, merge_declarations, module_ident, orphaned_files, outer_attributes,
+        package_file_name, paint, path_from_starts_with, protoc_error_summary, readers_equal,
+        restrict_non_exhaustive_to_enums, run_diff, service_mocks, single_trailing_newline,
+        split_service_modules, strip_enum_str_names, strip_partial_eq_allows, ColorChoice,
+        EventHandler, GenEvent, GenOptions, Module, ModuleSort, ModuleTree, RED,
+    };
+    use crate::ignore::{IgnoreRules, IGNORE_FILE};
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn module_declarations_outside_custom_sections() {
+        let content = "pub mod a;\npub(crate) mod r#type;\nmod inline {\n}\n// proto-gen:begin-custom\nmod glue;\n// proto-gen:end-custom\n#[cfg(feature = \"client\")]\npub mod b;\n";
+        assert_eq!(vec!["a", "type", "b"], declared_modules(content));
+    }
+
+    #[test]
+    fn sorts_modules_by_case() {
+        let mut names = vec!["bar", "Foo", "foo", "Baz"];
+        names.sort_by(|a, b| ModuleSort::CaseSensitive.compare(a, b));
+        assert_eq!(vec!["Baz", "Foo", "bar", "foo"], names);
+        names.sort_by(|a, b| ModuleSort::CaseInsensitive.compare(a, b));
+        assert_eq!(vec!["bar", "Baz", "Foo", "foo"], names);
+    }
 
     #[test]
     fn can_find_path_from_some_root_exists() {
@@ -642,7 +3190,15 @@ mod tests {
     fn can_diff_both_empty() {
         let empty_temp1 = tempfile::tempdir().unwrap();
         let empty_temp2 = tempfile::tempdir().unwrap();
-        let diff = run_diff(empty_temp1.path(), empty_temp2.path(), "my-mod").unwrap();
+        let diff = run_diff(
+            empty_temp1.path(),
+            empty_temp2.path(),
+            &empty_temp1.path().with_extension("rs"),
+            "my-mod",
+            &GenOptions::default(),
+            &IgnoreRules::default(),
+        )
+        .unwrap();
         // One diff, would write a module file
         assert_eq!(1, diff);
     }
@@ -670,7 +3226,622 @@ mod tests {
         )
         .unwrap();
         std::fs::write(new_mod_dir.join("my_mod.rs"), "!// Content").unwrap();
-        let diff = run_diff(&orig_mod_dir, &new_mod_dir, &expect_top_content).unwrap();
+        let diff = run_diff(
+            &orig_mod_dir,
+            &new_mod_dir,
+            &orig_mod_dir.with_extension("rs"),
+            &expect_top_content,
+            &GenOptions::default(),
+            &IgnoreRules::default(),
+        )
+        .unwrap();
         assert_eq!(0, diff);
     }
+
+    #[test]
+    fn paints_only_when_enabled() {
+        assert_eq!("\x1b[31mremoved\x1b[0m", paint("removed", RED, true));
+        assert_eq!("removed", paint("removed", RED, false));
+        assert!(ColorChoice::Always.enabled());
+        assert!(!ColorChoice::Never.enabled());
+    }
+
+    #[test]
+    fn removed_files_are_orphans() {
+        let orig = tempfile::tempdir().unwrap();
+        let orig_mod_dir = orig.path().join("proto_types");
+        std::fs::create_dir_all(orig_mod_dir.join("renamed")).unwrap();
+        std::fs::write(orig_mod_dir.join("my_mod.rs"), "!// Content").unwrap();
+        std::fs::write(orig_mod_dir.join("renamed.rs"), "pub mod inner;\n").unwrap();
+        std::fs::write(orig_mod_dir.join("renamed").join("inner.rs"), "").unwrap();
+        std::fs::write(orig_mod_dir.join(IGNORE_FILE), "").unwrap();
+        let new = tempfile::tempdir().unwrap();
+        let new_mod_dir = new.path().join("proto_types");
+        std::fs::create_dir(&new_mod_dir).unwrap();
+        std::fs::write(new_mod_dir.join("my_mod.rs"), "!// Content").unwrap();
+        let ignore = IgnoreRules::default();
+        assert_eq!(
+            vec![
+                Path::new("renamed").join("inner.rs"),
+                PathBuf::from("renamed.rs")
+            ],
+            orphaned_files(&orig_mod_dir, &new_mod_dir, &ignore).unwrap()
+        );
+        let found = Arc::new(Mutex::new(vec![]));
+        let gen_opts = GenOptions {
+            on_event: Some(EventHandler(Box::new({
+                let found = found.clone();
+                move |event| {
+                    if let GenEvent::DiffFound(file) = event {
+                        found.lock().unwrap().push(file.to_path_buf());
+                    }
+                }
+            }))),
+            ..GenOptions::default()
+        };
+        // Removed files and the missing module file
+        assert_eq!(
+            3,
+            run_diff(
+                &orig_mod_dir,
+                &new_mod_dir,
+                &orig_mod_dir.with_extension("rs"),
+                "",
+                &gen_opts,
+                &ignore
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            orphaned_files(&orig_mod_dir, &new_mod_dir, &ignore).unwrap(),
+            *found.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn ignored_files_are_not_diffed() {
+        let proto_mod = "proto_types";
+        let top_content = "pub mod my_mod;\n";
+        let orig = tempfile::tempdir().unwrap();
+        let orig_mod_dir = orig.path().join(proto_mod);
+        std::fs::create_dir(&orig_mod_dir).unwrap();
+        std::fs::write(orig_mod_dir.join("my_mod.rs"), "!// Hand edited").unwrap();
+        std::fs::write(orig_mod_dir.join(IGNORE_FILE), "my_mod.rs\n").unwrap();
+        std::fs::write(orig.path().join(format!("{proto_mod}.rs")), top_content).unwrap();
+        let new = tempfile::tempdir().unwrap();
+        let new_mod_dir = new.path().join(proto_mod);
+        std::fs::create_dir(&new_mod_dir).unwrap();
+        std::fs::write(new_mod_dir.join("my_mod.rs"), "!// Content").unwrap();
+        let diff = |ignore: &IgnoreRules| {
+            run_diff(
+                &orig_mod_dir,
+                &new_mod_dir,
+                &orig_mod_dir.with_extension("rs"),
+                top_content,
+                &GenOptions::default(),
+                ignore,
+            )
+            .unwrap()
+        };
+        // The ignore file itself is never diffed
+        assert_eq!(1, diff(&IgnoreRules::default()));
+        assert_eq!(
+            0,
+            diff(&IgnoreRules::from_output_dir(&orig_mod_dir).unwrap())
+        );
+    }
+
+    #[test]
+    fn format_diff_ignored_when_requested() {
+        let proto_mod = "proto_types";
+        let orig = tempfile::tempdir().unwrap();
+        let orig_mod_dir = orig.path().join(proto_mod);
+        std::fs::create_dir(&orig_mod_dir).unwrap();
+        std::fs::write(orig_mod_dir.join("my_mod.rs"), "pub struct A { a: i32 }\n").unwrap();
+        std::fs::write(
+            orig.path().join(format!("{proto_mod}.rs")),
+            "pub  mod my_mod;\n",
+        )
+        .unwrap();
+        let new = tempfile::tempdir().unwrap();
+        let new_mod_dir = new.path().join(proto_mod);
+        std::fs::create_dir(&new_mod_dir).unwrap();
+        let diff = |content: &str, ignore_format_diff: bool| {
+            std::fs::write(new_mod_dir.join("my_mod.rs"), content).unwrap();
+            run_diff(
+                &orig_mod_dir,
+                &new_mod_dir,
+                &orig_mod_dir.with_extension("rs"),
+                "pub mod my_mod;\n",
+                &GenOptions {
+                    ignore_format_diff,
+                    ..GenOptions::default()
+                },
+                &IgnoreRules::default(),
+            )
+            .unwrap()
+        };
+        let reformatted = "pub struct A {\n    a: i32,\n}\n";
+        assert_eq!(2, diff(reformatted, false));
+        assert_eq!(0, diff(reformatted, true));
+        assert_eq!(1, diff("pub struct A {\n    b: i32,\n}\n", true));
+    }
+
+    #[test]
+    fn capped_diff_print_still_counts_every_diff() {
+        let proto_mod = "proto_types";
+        let orig = tempfile::tempdir().unwrap();
+        let orig_mod_dir = orig.path().join(proto_mod);
+        std::fs::create_dir(&orig_mod_dir).unwrap();
+        let new = tempfile::tempdir().unwrap();
+        let new_mod_dir = new.path().join(proto_mod);
+        std::fs::create_dir(&new_mod_dir).unwrap();
+        for name in ["a", "b", "c"] {
+            std::fs::write(new_mod_dir.join(format!("{name}.rs")), "pub struct A;\n").unwrap();
+        }
+        for max_diff_print in [None, Some(0), Some(1), Some(10)] {
+            let diff = run_diff(
+                &orig_mod_dir,
+                &new_mod_dir,
+                &orig_mod_dir.with_extension("rs"),
+                "",
+                &GenOptions {
+                    max_diff_print,
+                    ..GenOptions::default()
+                },
+                &IgnoreRules::default(),
+            )
+            .unwrap();
+            // Three new files and the missing module file
+            assert_eq!(4, diff);
+        }
+    }
+
+    #[test]
+    fn trailing_newlines_are_not_diffed() {
+        let proto_mod = "proto_types";
+        let orig = tempfile::tempdir().unwrap();
+        let orig_mod_dir = orig.path().join(proto_mod);
+        std::fs::create_dir(&orig_mod_dir).unwrap();
+        std::fs::write(orig_mod_dir.join("my_mod.rs"), "pub struct A;\n\n").unwrap();
+        std::fs::write(
+            orig.path().join(format!("{proto_mod}.rs")),
+            "pub mod my_mod;\n\n",
+        )
+        .unwrap();
+        let new = tempfile::tempdir().unwrap();
+        let new_mod_dir = new.path().join(proto_mod);
+        std::fs::create_dir(&new_mod_dir).unwrap();
+        std::fs::write(new_mod_dir.join("my_mod.rs"), "pub struct A;\n").unwrap();
+        let diff = |new_mod: &str| {
+            run_diff(
+                &orig_mod_dir,
+                &new_mod_dir,
+                &orig_mod_dir.with_extension("rs"),
+                new_mod,
+                &GenOptions::default(),
+                &IgnoreRules::default(),
+            )
+            .unwrap()
+        };
+        assert_eq!(0, diff("pub mod my_mod;\n"));
+        std::fs::write(new_mod_dir.join("my_mod.rs"), "pub struct B;\n").unwrap();
+        assert_eq!(1, diff("pub mod my_mod;\n"));
+    }
+
+    #[test]
+    fn single_trailing_newline_is_written() {
+        for content in ["pub struct A;", "pub struct A;\n", "pub struct A;\n\n\n"] {
+            let mut content = content.to_string();
+            single_trailing_newline(&mut content);
+            assert_eq!("pub struct A;\n", content);
+        }
+        let mut empty = "\n\n".to_string();
+        single_trailing_newline(&mut empty);
+        assert_eq!("", empty);
+    }
+
+    #[test]
+    fn readers_equal_compares_content_and_length() {
+        let long = "a".repeat(10_000);
+        let mut long_changed = long.clone();
+        long_changed.push('b');
+        assert!(readers_equal(long.as_bytes(), long.as_bytes()).unwrap());
+        assert!(!readers_equal(long.as_bytes(), long_changed.as_bytes()).unwrap());
+        assert!(!readers_equal(long_changed.as_bytes(), long.as_bytes()).unwrap());
+        assert!(!readers_equal(&b"abc"[..], &b"abd"[..]).unwrap());
+        assert!(readers_equal(&b""[..], &b""[..]).unwrap());
+    }
+
+    #[test]
+    fn service_modules_are_split_out() {
+        let content = r#"#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Req {
+    #[prost(int32, tag = "1")]
+    pub x: i32,
+}
+/// Generated client implementations.
+pub mod greeter_client {
+    pub async fn hello(request: super::Req) -> super::super::other::Resp {}
+}
+/// Generated server implementations.
+#[cfg(feature = "server")]
+pub mod greeter_server {
+    pub trait Greeter {
+        async fn hello(&self, request: tonic::Request<super::Req>);
+    }
+}
+"#;
+        let (rest, services) = split_service_modules(content);
+        assert_eq!(
+            r#"#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Req {
+    #[prost(int32, tag = "1")]
+    pub x: i32,
+}
+"#,
+            rest
+        );
+        assert_eq!(
+            r#"/// Generated client implementations.
+pub mod greeter_client {
+    pub async fn hello(request: super::super::Req) -> super::super::super::other::Resp {}
+}
+/// Generated server implementations.
+#[cfg(feature = "server")]
+pub mod greeter_server {
+    pub trait Greeter {
+        async fn hello(&self, request: tonic::Request<super::super::Req>);
+    }
+}
+"#,
+            services
+        );
+        assert_eq!("my_super::Req", deepen_super_paths("my_super::Req"));
+    }
+
+    #[test]
+    fn mocks_implement_server_traits() {
+        let content = r"/// Generated server implementations.
+pub mod greeter_server {
+    use tonic::codegen::*;
+    /// Generated trait containing gRPC methods that should be implemented for use with GreeterServer.
+    #[async_trait]
+    pub trait Greeter: Send + Sync + 'static {
+        async fn hello(
+            &self,
+            request: tonic::Request<super::Req>,
+        ) -> std::result::Result<tonic::Response<super::Req>, tonic::Status>;
+        /// Server streaming response type for the Sub method.
+        type SubStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::Req, tonic::Status>,
+            >
+            + Send
+            + 'static;
+        async fn sub(
+            &self,
+            request: tonic::Request<super::Req>,
+        ) -> std::result::Result<tonic::Response<Self::SubStream>, tonic::Status>;
+    }
+}
+";
+        let mocks = service_mocks(content, Some("server"));
+        assert!(mocks.contains("#[cfg(all(test, feature = \"server\"))]\npub mod mocks {"));
+        assert!(mocks.contains("pub struct MockGreeter {"));
+        assert!(mocks.contains("impl super::greeter_server::Greeter for MockGreeter {"));
+        assert!(mocks.contains("pub hello: Option<Box<dyn Fn(tonic::Request<super::Req>) -> std::result::Result<tonic::Response<super::Req>, tonic::Status> + Send + Sync>>,"));
+        assert!(mocks.contains("type SubStream = tonic::codegen::BoxStream<super::Req>;"));
+        assert!(mocks.contains("tonic::Response<tonic::codegen::BoxStream<super::Req>>, tonic::Status> + Send + Sync>>,"));
+        assert!(mocks.contains("None => Err(tonic::Status::unimplemented(\"sub is not mocked\")),"));
+        assert_eq!("", service_mocks("pub struct Req {}\n", None));
+    }
+
+    #[test]
+    fn derive_eq_strips_partial_eq_allow() {
+        let content = r"#[derive(Eq, Hash)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MyMessage {}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Untouched {}
+";
+        let expect = r"#[derive(Eq, Hash)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct MyMessage {}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Untouched {}
+";
+        assert_eq!(expect, apply_derive_eq(content));
+    }
+
+    #[test]
+    fn derive_eq_removed_from_enums() {
+        let content = r"    #[derive(Eq, Hash)]
+    #[derive(serde::Serialize)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum MyEnum {}
+";
+        let expect = r"    #[derive(serde::Serialize)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum MyEnum {}
+";
+        assert_eq!(expect, apply_derive_eq(content));
+    }
+
+    #[test]
+    fn non_exhaustive_only_on_enums() {
+        let content = r"    #[non_exhaustive]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum MyEnum {}
+    #[non_exhaustive]
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum MyOneof {}
+";
+        let expect = r"    #[non_exhaustive]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum MyEnum {}
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum MyOneof {}
+";
+        assert_eq!(expect, restrict_non_exhaustive_to_enums(content));
+    }
+
+    #[test]
+    fn protoc_error_location_summarized() {
+        let error = "protoc failed: dep.proto:2:1: warning: Import other.proto is unused.\nmy-proto.proto:7:3: Expected \";\".\n";
+        assert_eq!(
+            "Error in my-proto.proto at line 7, column 3: Expected \";\".\n\n",
+            protoc_error_summary(error)
+        );
+        assert_eq!(
+            "",
+            protoc_error_summary("protoc failed: my-proto.proto: File not found.\n")
+        );
+    }
+
+    #[test]
+    fn partial_eq_allows_stripped() {
+        let content = "#[allow(clippy::derive_partial_eq_without_eq)]\n#[derive(Clone, PartialEq, ::prost::Message)]\npub struct A {}\npub mod a {\n    #[allow(clippy::derive_partial_eq_without_eq)]\n    #[derive(Clone, PartialEq, ::prost::Message)]\n    pub struct B {}\n}\n";
+        let expect = "#[derive(Clone, PartialEq, ::prost::Message)]\npub struct A {}\npub mod a {\n    #[derive(Clone, PartialEq, ::prost::Message)]\n    pub struct B {}\n}\n";
+        assert_eq!(expect, strip_partial_eq_allows(content));
+    }
+
+    #[test]
+    fn inner_attributes_moved_to_declarations() {
+        let content =
+            "// Header\n#![allow(clippy::use_self)]\npub mod a;\npub mod b {\n    pub mod c;\n}\n";
+        let expect = "// Header\n#[allow(clippy::use_self)]\npub mod a;\n#[allow(clippy::use_self)]\npub mod b {\n    pub mod c;\n}\n";
+        assert_eq!(expect, outer_attributes(content));
+    }
+
+    #[test]
+    fn declarations_merged_into_kept_module() {
+        let gen_opts = GenOptions::default();
+        let header = "// Header\n\n//! Docs\npub struct A;\n";
+        assert_eq!(
+            "// Header\n\n//! Docs\npub mod b;\n\npub struct A;\n",
+            merge_declarations(header, &["b"], &gen_opts)
+        );
+        let declared = "pub mod c;\n\npub struct A;\n";
+        assert_eq!(
+            "pub mod b;\npub mod c;\n\npub struct A;\n",
+            merge_declarations(declared, &["b", "c"], &gen_opts)
+        );
+    }
+
+    #[test]
+    fn str_names_stripped_from_minimal_enums() {
+        let content = r#"    #[cfg_attr(any(), proto_gen_minimal_enum)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum MyEnum {
+        A = 0,
+    }
+    impl MyEnum {
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                MyEnum::A => "A",
+            }
+        }
+    }
+    #[cfg_attr(any(), proto_gen_minimal_enum)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum MyOneof {}
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Other {
+        B = 0,
+    }
+    impl Other {}
+"#;
+        let expect = r"    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum MyEnum {
+        A = 0,
+    }
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum MyOneof {}
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Other {
+        B = 0,
+    }
+    impl Other {}
+";
+        assert_eq!(expect, strip_enum_str_names(content));
+    }
+
+    #[test]
+    fn service_modules_gated_behind_features() {
+        let content = r"pub mod greeter {
+    /// Generated client implementations.
+    pub mod greeter_client {
+    }
+    /// Generated server implementations.
+    #[cfg(unix)]
+    pub mod greeter_server {
+    }
+    pub mod greeter_client {
+    }
+}
+";
+        let expect = r#"pub mod greeter {
+    /// Generated client implementations.
+    #[cfg(feature = "client")]
+    pub mod greeter_client {
+    }
+    /// Generated server implementations.
+    #[cfg(unix)]
+    #[cfg(feature = "server")]
+    pub mod greeter_server {
+    }
+    pub mod greeter_client {
+    }
+}
+"#;
+        assert_eq!(
+            expect,
+            gate_service_modules(content, Some("server"), Some("client"))
+        );
+        assert!(!gate_service_modules(content, Some("server"), None).contains("\"client\""));
+    }
+
+    #[test]
+    fn custom_sections_are_appended_once() {
+        let old = "pub mod my_mod;\n\n// proto-gen:begin-custom\nimpl Foo {}\n// proto-gen:end-custom\npub mod other;\n";
+        let mut new = "pub mod my_mod;\n".to_string();
+        append_custom_sections(&mut new, old);
+        assert_eq!(
+            "pub mod my_mod;\n\n// proto-gen:begin-custom\nimpl Foo {}\n// proto-gen:end-custom\n",
+            new
+        );
+        let mut regenerated = "pub mod my_mod;\n".to_string();
+        append_custom_sections(&mut regenerated, &new);
+        assert_eq!(new, regenerated);
+        let mut without_sections = "pub mod my_mod;\n".to_string();
+        append_custom_sections(&mut without_sections, "pub mod my_mod;\n");
+        assert_eq!("pub mod my_mod;\n", without_sections);
+    }
+
+    #[test]
+    fn package_segments_become_module_idents() {
+        assert_eq!("v2", module_ident("v2"));
+        assert_eq!("_2v", module_ident("2v"));
+        assert_eq!("my_pkg", module_ident("my-pkg"));
+        assert_eq!("r#match", module_ident("r#match"));
+        let out_dir = Path::new("out");
+        let mut root = Module {
+            name: "dummy".to_string(),
+            location: out_dir.to_path_buf(),
+            children: std::collections::HashMap::new(),
+            file: None,
+            existing: None,
+            package: None,
+        };
+        root.push_file(out_dir, &out_dir.join("2v.my-pkg.rs"))
+            .unwrap();
+        let tree = root.children["_2v"]
+            .borrow()
+            .to_tree("", ModuleSort::default());
+        assert_eq!("_2v.my_pkg", tree.children[0].package);
+        assert_eq!(
+            out_dir.join("_2v"),
+            root.children["_2v"].borrow().children["my_pkg"]
+                .borrow()
+                .location
+        );
+    }
+
+    #[test]
+    fn module_tree_mirrors_packages() {
+        let out_dir = Path::new("out");
+        let mut root = Module {
+            name: "dummy".to_string(),
+            location: out_dir.to_path_buf(),
+            children: std::collections::HashMap::new(),
+            file: None,
+            existing: None,
+            package: None,
+        };
+        for file in ["my_proto.rs", "imports.nested.rs", "imports.dependency.rs"] {
+            root.push_file(out_dir, &out_dir.join(file)).unwrap();
+        }
+        let mut trees = root
+            .children
+            .values()
+            .map(|child| child.borrow().to_tree("", ModuleSort::default()))
+            .collect::<Vec<_>>();
+        trees.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(4, trees.iter().map(ModuleTree::module_count).sum::<usize>());
+        assert_eq!("imports.nested", trees[0].children[1].package);
+        assert_eq!(None, trees[0].source_file);
+        let outline = trees.iter().map(ToString::to_string).collect::<String>();
+        assert_eq!(
+            "imports\n  dependency (imports.dependency.rs)\n  nested (imports.nested.rs)\nmy_proto (my_proto.rs)\n",
+            outline
+        );
+    }
+
+    #[test]
+    fn colliding_modules_are_an_error() {
+        let out_dir = Path::new("out");
+        let mut root = Module {
+            name: "dummy".to_string(),
+            location: out_dir.to_path_buf(),
+            children: std::collections::HashMap::new(),
+            file: None,
+            existing: None,
+            package: None,
+        };
+        root.push_file(out_dir, &out_dir.join("my_proto.inner.rs"))
+            .unwrap();
+        let error = root
+            .push_file(out_dir, &out_dir.join("my-proto.inner.rs"))
+            .unwrap_err();
+        assert!(error.contains("\"out/my-proto.inner.rs\""), "{error}");
+        assert!(error.contains("\"out/my_proto.inner.rs\""), "{error}");
+        assert!(
+            error.contains("module inner at \"out/my_proto/inner.rs\""),
+            "{error}"
+        );
+    }
+    #[test]
+    fn flattened_packages_named_by_last_segment() {
+        let out_dir = Path::new("out");
+        let mut root = Module {
+            name: "dummy".to_string(),
+            location: out_dir.to_path_buf(),
+            children: std::collections::HashMap::new(),
+            file: None,
+            existing: None,
+            package: None,
+        };
+        root.push_flat(out_dir, &out_dir.join("a.b.c.rs")).unwrap();
+        let tree = root.children["c"]
+            .borrow()
+            .to_tree("", ModuleSort::default());
+        assert_eq!("a.b.c", tree.package);
+        assert!(tree.children.is_empty());
+        let error = root
+            .push_flat(out_dir, &out_dir.join("x.c.rs"))
+            .unwrap_err();
+        assert!(error.contains("both map to module c"), "{error}");
+    }
+
+    #[test]
+    fn package_file_names_match_prost() {
+        assert_eq!(
+            "my.mixed_case.r#type.rs",
+            package_file_name("my.MixedCase.type")
+        );
+        assert_eq!("_.rs", package_file_name(""));
+    }
 }
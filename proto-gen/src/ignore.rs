@@ -0,0 +1,206 @@
+//! Gitignore style patterns for generated files that should be left out of diffing
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Component, Path};
+
+/// Name of the file in an output dir listing patterns to ignore, one per line
+pub const IGNORE_FILE: &str = ".protogenignore";
+
+/// Patterns matched against paths relative to an output dir, later patterns take precedence.
+/// Supports `*`, `?` and `**`, negation with `!`, anchoring with a leading `/`
+/// and matching only directories with a trailing `/`, like `.gitignore`.
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    patterns: Vec<Pattern>,
+}
+
+#[derive(Debug, Clone)]
+struct Pattern {
+    negated: bool,
+    dir_only: bool,
+    segments: Vec<String>,
+}
+
+impl IgnoreRules {
+    /// Parses `.gitignore` style content, blank lines and lines starting with `#` are skipped
+    #[must_use]
+    pub fn parse(content: &str) -> Self {
+        let mut rules = Self::default();
+        for line in content.lines() {
+            rules.add(line);
+        }
+        rules
+    }
+
+    /// Reads the [`IGNORE_FILE`] in `output_dir`, a missing file gives no rules
+    /// # Errors
+    /// If the file exists but can't be read
+    pub fn from_output_dir(output_dir: &Path) -> Result<Self, String> {
+        let path = output_dir.join(IGNORE_FILE);
+        match fs::read_to_string(&path) {
+            Ok(content) => Ok(Self::parse(&content)),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("Failed to read ignore file {path:?} \n{e}")),
+        }
+    }
+
+    /// Adds a single pattern, given the same way as a line in [`IGNORE_FILE`]
+    pub fn add(&mut self, pattern: &str) {
+        let mut pattern = pattern.trim_end();
+        if pattern.is_empty() || pattern.starts_with('#') {
+            return;
+        }
+        let negated = if let Some(rest) = pattern.strip_prefix('!') {
+            pattern = rest;
+            true
+        } else {
+            pattern = pattern.strip_prefix('\\').unwrap_or(pattern);
+            false
+        };
+        let dir_only = if let Some(rest) = pattern.strip_suffix('/') {
+            pattern = rest;
+            true
+        } else {
+            false
+        };
+        // Like gitignore, a pattern without a slash matches at any depth
+        let anchored = pattern.contains('/');
+        let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+        let mut segments = vec![];
+        if !anchored {
+            segments.push("**".to_string());
+        }
+        segments.extend(
+            pattern
+                .split('/')
+                .filter(|segment| !segment.is_empty())
+                .map(str::to_string),
+        );
+        self.patterns.push(Pattern {
+            negated,
+            dir_only,
+            segments,
+        });
+    }
+
+    /// Whether the path relative to the output dir is ignored,
+    /// the [`IGNORE_FILE`] itself always is
+    #[must_use]
+    pub fn is_ignored(&self, relative_path: &Path) -> bool {
+        let components = normal_components(relative_path);
+        components == [IGNORE_FILE] || self.matches_components(&components)
+    }
+
+    /// Whether the relative path matches the patterns
+    #[must_use]
+    pub fn matches(&self, relative_path: &Path) -> bool {
+        self.matches_components(&normal_components(relative_path))
+    }
+
+    fn matches_components(&self, components: &[String]) -> bool {
+        let components = components.iter().map(AsRef::as_ref).collect::<Vec<&str>>();
+        let mut ignored = false;
+        for pattern in &self.patterns {
+            if pattern.matches(&components) {
+                ignored = !pattern.negated;
+            }
+        }
+        ignored
+    }
+}
+
+fn normal_components(path: &Path) -> Vec<String> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect()
+}
+
+impl Pattern {
+    fn matches(&self, components: &[&str]) -> bool {
+        // Matching a parent dir ignores everything in it
+        let parents =
+            (1..components.len()).any(|len| match_segments(&self.segments, &components[..len]));
+        parents || (!self.dir_only && match_segments(&self.segments, components))
+    }
+}
+
+fn match_segments(segments: &[String], components: &[&str]) -> bool {
+    match segments.split_first() {
+        None => components.is_empty(),
+        Some((segment, rest)) if segment == "**" => {
+            (0..=components.len()).any(|skip| match_segments(rest, &components[skip..]))
+        }
+        Some((segment, rest)) => components.split_first().is_some_and(|(component, others)| {
+            wildcard_match(segment.as_bytes(), component.as_bytes()) && match_segments(rest, others)
+        }),
+    }
+}
+
+/// Matches `*` and `?` within a single path component
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => {
+                let Some((star, star_t)) = backtrack else {
+                    return false;
+                };
+                p = star + 1;
+                t = star_t + 1;
+                backtrack = Some((star, star_t + 1));
+            }
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ignore::{wildcard_match, IgnoreRules};
+    use std::path::Path;
+
+    #[test]
+    fn wildcards_match_within_component() {
+        assert!(wildcard_match(b"*.rs", b"my_proto.rs"));
+        assert!(wildcard_match(b"my_?roto.rs", b"my_proto.rs"));
+        assert!(wildcard_match(b"*", b""));
+        assert!(!wildcard_match(b"*.rs", b"my_proto.rs.bak"));
+        assert!(!wildcard_match(b"my_proto", b"my_proto.rs"));
+    }
+
+    #[test]
+    fn gitignore_style_rules() {
+        let rules = IgnoreRules::parse(
+            r"# Hand edited
+patched.rs
+/top.rs
+imports/**/*.rs
+!imports/keep/kept.rs
+legacy/
+",
+        );
+        assert!(rules.is_ignored(Path::new(".protogenignore")));
+        assert!(rules.is_ignored(Path::new("patched.rs")));
+        assert!(rules.is_ignored(Path::new("nested/patched.rs")));
+        assert!(rules.is_ignored(Path::new("top.rs")));
+        assert!(!rules.is_ignored(Path::new("nested/top.rs")));
+        assert!(rules.is_ignored(Path::new("imports/dependency.rs")));
+        assert!(rules.is_ignored(Path::new("imports/keep/other.rs")));
+        assert!(!rules.is_ignored(Path::new("imports/keep/kept.rs")));
+        assert!(rules.is_ignored(Path::new("legacy/old.rs")));
+        assert!(!rules.is_ignored(Path::new("legacy.rs")));
+        assert!(!rules.is_ignored(Path::new("my_proto.rs")));
+    }
+}
@@ -0,0 +1,116 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::gen::{module_file, ProtoWorkspace};
+
+/// Writes a single file that pulls in the generated code of every output dir, so a crate only
+/// needs `mod <file>;`. Each output dir's module file is inlined in a module with a `#[path]`
+/// pointing at the dir, `include!` can't be used since generated files declare child modules.
+pub(crate) fn write(include_file: &Path, ws: &ProtoWorkspace) -> Result<(), String> {
+    let include_dir = include_file
+        .parent()
+        .ok_or_else(|| format!("Failed to find parent for include file {include_file:?}"))?;
+    let package_output_dirs = ws.package_outputs.iter().map(|(_, dir)| dir);
+    let mut content = String::new();
+    for output_dir in std::iter::once(&ws.output_dir).chain(package_output_dirs) {
+        let name = output_dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| format!("Failed to get module name of output dir {output_dir:?}"))?;
+        let mod_file = module_file(ws, output_dir)?;
+        let mod_content = fs::read_to_string(&mod_file).map_err(|e| {
+            format!("Failed to read module file {mod_file:?} for include file \n{e}")
+        })?;
+        content.push_str(&wrap_module(
+            name,
+            &relative_path(include_dir, output_dir),
+            &mod_content,
+        ));
+    }
+    fs::write(include_file, content)
+        .map_err(|e| format!("Failed to write include file {include_file:?} \n{e}"))
+}
+
+/// The module file `content` of an output dir in an inline module whose children are found
+/// at `path`, with the modules re-exported from the including file
+fn wrap_module(name: &str, path: &Path, content: &str) -> String {
+    // Forward slashes work on all platforms and need no escaping
+    let path = path
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    let mut wrapped = format!("#[path = \"{path}\"]\nmod {name} {{\n");
+    for line in content.lines() {
+        if !line.is_empty() {
+            wrapped.push_str("    ");
+            wrapped.push_str(line);
+        }
+        wrapped.push('\n');
+    }
+    let _ = writeln!(wrapped, "}}\npub use self::{name}::*;");
+    wrapped
+}
+
+/// Path to `to` relative to the dir `from`, both absolute
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from = from.components().collect::<Vec<_>>();
+    let to = to.components().collect::<Vec<_>>();
+    let common = from
+        .iter()
+        .zip(&to)
+        .take_while(|(from, to)| from == to)
+        .count();
+    let mut relative = PathBuf::new();
+    for _ in common..from.len() {
+        relative.push(Component::ParentDir);
+    }
+    for component in &to[common..] {
+        relative.push(component);
+    }
+    relative
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::include_file::{relative_path, wrap_module};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn relative_paths_between_dirs() {
+        assert_eq!(
+            PathBuf::from("proto_types"),
+            relative_path(
+                Path::new("/project/src"),
+                Path::new("/project/src/proto_types")
+            )
+        );
+        assert_eq!(
+            PathBuf::from("../gen/proto_types"),
+            relative_path(
+                Path::new("/project/src"),
+                Path::new("/project/gen/proto_types")
+            )
+        );
+    }
+
+    #[test]
+    fn module_file_wrapped_with_path() {
+        let content =
+            "#![allow(clippy::doc_markdown, clippy::use_self)]\npub mod a;\n\npub mod b;\n";
+        let expect = r#"#[path = "../gen/proto_types"]
+mod proto_types {
+    #![allow(clippy::doc_markdown, clippy::use_self)]
+    pub mod a;
+
+    pub mod b;
+}
+pub use self::proto_types::*;
+"#;
+        assert_eq!(
+            expect,
+            wrap_module("proto_types", Path::new("../gen/proto_types"), content)
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Minimal JSON document building for the additional non-Rust outputs
+use std::fmt::Write;
+
+/// A JSON value, object keys keep their insertion order so output is deterministic
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Bool(bool),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn str(value: impl Into<String>) -> Self {
+        Self::Str(value.into())
+    }
+
+    pub fn obj<K: Into<String>>(entries: impl IntoIterator<Item = (K, Json)>) -> Self {
+        Self::Obj(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.into(), value))
+                .collect(),
+        )
+    }
+
+    /// Pretty prints with two space indentation and a trailing newline
+    #[must_use]
+    pub fn pretty(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out, 0);
+        out.push('\n');
+        out
+    }
+
+    fn write(&self, out: &mut String, depth: usize) {
+        let indent = |out: &mut String, depth: usize| {
+            out.push('\n');
+            out.extend(std::iter::repeat_n("  ", depth));
+        };
+        match self {
+            Json::Bool(value) => {
+                let _ = write!(out, "{value}");
+            }
+            Json::Str(value) => write_str(out, value),
+            Json::Arr(values) if values.is_empty() => out.push_str("[]"),
+            Json::Arr(values) => {
+                out.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    indent(out, depth + 1);
+                    value.write(out, depth + 1);
+                }
+                indent(out, depth);
+                out.push(']');
+            }
+            Json::Obj(entries) if entries.is_empty() => out.push_str("{}"),
+            Json::Obj(entries) => {
+                out.push('{');
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    indent(out, depth + 1);
+                    write_str(out, key);
+                    out.push_str(": ");
+                    value.write(out, depth + 1);
+                }
+                indent(out, depth);
+                out.push('}');
+            }
+        }
+    }
+}
+
+fn write_str(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::json::Json;
+
+    #[test]
+    fn pretty_prints_escaped() {
+        let json = Json::obj([
+            ("name", Json::str("say \"hi\"\n")),
+            ("empty", Json::Arr(vec![])),
+            (
+                "list",
+                Json::Arr(vec![Json::Bool(true), Json::obj::<String>([])]),
+            ),
+        ]);
+        assert_eq!(
+            r#"{
+  "name": "say \"hi\"\n",
+  "empty": [],
+  "list": [
+    true,
+    {}
+  ]
+}
+"#,
+            json.pretty()
+        );
+    }
+}
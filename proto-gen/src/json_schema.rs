@@ -0,0 +1,248 @@
+//! JSON Schema for the JSON that `--derive-serde` produces for the generated messages
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, FieldDescriptorProto, FileDescriptorSet};
+
+use crate::json::Json;
+use crate::openapi::{is_map_entry, messages_by_name};
+
+/// Writes `<package>.<Message>.schema.json` to `dir` for each top level message.
+/// The schemas describe `serde_json` output of the generated structs, with field names
+/// renamed by `rename_all` like `--serde-rename-all`. This differs from the proto3 JSON mapping,
+/// enums are their `i32` values, 64 bit integers are numbers and bytes are arrays of numbers.
+pub(crate) fn write(
+    dir: &Path,
+    fds: &FileDescriptorSet,
+    rename_all: Option<&str>,
+) -> Result<(), String> {
+    fs::create_dir_all(dir)
+        .map_err(|e| format!("Failed to create JSON Schema dir {dir:?} \n{e}"))?;
+    let messages = messages_by_name(fds);
+    for file in &fds.file {
+        for message in &file.message_type {
+            let name = if file.package().is_empty() {
+                message.name().to_string()
+            } else {
+                format!("{}.{}", file.package(), message.name())
+            };
+            let path = dir.join(format!("{name}.schema.json"));
+            fs::write(&path, render(&name, &messages, rename_all).pretty())
+                .map_err(|e| format!("Failed to write JSON Schema {path:?} \n{e}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Schema referencing the message `name` in `$defs`, along with every message it uses
+fn render(
+    name: &str,
+    messages: &HashMap<String, &DescriptorProto>,
+    rename_all: Option<&str>,
+) -> Json {
+    let mut defs = BTreeMap::new();
+    let mut pending = vec![format!(".{name}")];
+    while let Some(next) = pending.pop() {
+        if defs.contains_key(&next) {
+            continue;
+        }
+        let Some(message) = messages.get(&next) else {
+            continue;
+        };
+        pending.extend(
+            message
+                .field
+                .iter()
+                .filter(|field| field.r#type() == Type::Message)
+                .map(|field| field.type_name().to_string()),
+        );
+        let schema = if is_map_entry(message) {
+            None
+        } else {
+            Some(message_schema(message, messages, rename_all))
+        };
+        defs.insert(next, schema);
+    }
+    Json::obj([
+        (
+            "$schema",
+            Json::str("https://json-schema.org/draft/2020-12/schema"),
+        ),
+        ("title", Json::str(name)),
+        ("$ref", def_ref(name)),
+        (
+            "$defs",
+            Json::obj(defs.into_iter().filter_map(|(name, schema)| {
+                Some((name.trim_start_matches('.').to_string(), schema?))
+            })),
+        ),
+    ])
+}
+
+fn message_schema(
+    message: &DescriptorProto,
+    messages: &HashMap<String, &DescriptorProto>,
+    rename_all: Option<&str>,
+) -> Json {
+    let mut properties = vec![];
+    let mut oneofs = vec![vec![]; message.oneof_decl.len()];
+    for field in &message.field {
+        match field.oneof_index {
+            // Proto3 optional fields are synthetic oneofs, but plain `Option`s in Rust
+            Some(index) if !field.proto3_optional() => {
+                if let Some(variants) = oneofs.get_mut(usize::try_from(index).unwrap_or(usize::MAX))
+                {
+                    variants.push(field);
+                }
+            }
+            _ => properties.push((
+                rename(field.name(), rename_all, false),
+                field_schema(field, messages),
+            )),
+        }
+    }
+    for (oneof, variants) in message.oneof_decl.iter().zip(oneofs) {
+        if variants.is_empty() {
+            continue;
+        }
+        // Oneofs are externally tagged `Option<Enum>`s
+        let mut any_of = vec![Json::obj([("type", Json::str("null"))])];
+        any_of.extend(variants.into_iter().map(|field| {
+            let variant = rename(field.name(), rename_all, true);
+            Json::obj([
+                ("type", Json::str("object")),
+                (
+                    "properties",
+                    Json::obj([(variant.clone(), single_schema(field))]),
+                ),
+                ("required", Json::Arr(vec![Json::Str(variant)])),
+                ("additionalProperties", Json::Bool(false)),
+            ])
+        }));
+        properties.push((
+            rename(oneof.name(), rename_all, false),
+            Json::obj([("anyOf", Json::Arr(any_of))]),
+        ));
+    }
+    Json::obj([
+        ("type", Json::str("object")),
+        ("properties", Json::Obj(properties)),
+    ])
+}
+
+fn field_schema(
+    field: &FieldDescriptorProto,
+    messages: &HashMap<String, &DescriptorProto>,
+) -> Json {
+    if field.label() == Label::Repeated {
+        let map_value = messages
+            .get(field.type_name())
+            .filter(|entry| is_map_entry(entry))
+            .and_then(|entry| entry.field.iter().find(|f| f.number() == 2));
+        let (json_type, key) = match map_value {
+            Some(value) => ("object", ("additionalProperties", single_schema(value))),
+            None => ("array", ("items", single_schema(field))),
+        };
+        return Json::obj([("type", Json::str(json_type)), key]);
+    }
+    // Singular messages and proto3 optional fields are `Option`s
+    if field.r#type() == Type::Message || field.proto3_optional() {
+        return Json::obj([(
+            "anyOf",
+            Json::Arr(vec![
+                single_schema(field),
+                Json::obj([("type", Json::str("null"))]),
+            ]),
+        )]);
+    }
+    single_schema(field)
+}
+
+fn single_schema(field: &FieldDescriptorProto) -> Json {
+    let json_type = match field.r#type() {
+        Type::Message | Type::Group => return def_ref(field.type_name()),
+        Type::Double | Type::Float => "number",
+        Type::Bool => "boolean",
+        Type::String => "string",
+        Type::Bytes => {
+            return Json::obj([
+                ("type", Json::str("array")),
+                ("items", Json::obj([("type", Json::str("integer"))])),
+            ])
+        }
+        // Including enums, which are their `i32` values in the generated structs
+        _ => "integer",
+    };
+    Json::obj([("type", Json::str(json_type))])
+}
+
+fn def_ref(type_name: &str) -> Json {
+    Json::obj([(
+        "$ref",
+        Json::str(format!("#/$defs/{}", type_name.trim_start_matches('.'))),
+    )])
+}
+
+/// Renames a proto field or oneof variant the way serde's `rename_all` does with the
+/// Rust name prost generates, snake case for fields and pascal case for variants
+fn rename(name: &str, rename_all: Option<&str>, variant: bool) -> String {
+    let words = name
+        .split('_')
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>();
+    let capitalized = || {
+        words
+            .iter()
+            .map(|word| {
+                let mut chars = word.chars();
+                chars.next().map_or_else(String::new, |first| {
+                    first.to_uppercase().chain(chars).collect()
+                })
+            })
+            .collect::<Vec<_>>()
+    };
+    let rust_name = if variant {
+        capitalized().concat()
+    } else {
+        words.join("_")
+    };
+    match rename_all {
+        Some("lowercase") => rust_name.to_lowercase(),
+        Some("UPPERCASE") => rust_name.to_uppercase(),
+        Some("PascalCase") => capitalized().concat(),
+        Some("camelCase") => {
+            let pascal = capitalized().concat();
+            let mut chars = pascal.chars();
+            chars.next().map_or_else(String::new, |first| {
+                first.to_lowercase().chain(chars).collect()
+            })
+        }
+        Some("snake_case") => words.join("_"),
+        Some("SCREAMING_SNAKE_CASE") => words.join("_").to_uppercase(),
+        Some("kebab-case") => words.join("-"),
+        Some("SCREAMING-KEBAB-CASE") => words.join("-").to_uppercase(),
+        _ => rust_name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::json_schema::rename;
+
+    #[test]
+    fn renames_like_serde() {
+        assert_eq!("field_one", rename("field_one", None, false));
+        assert_eq!("FieldOne", rename("field_one", None, true));
+        assert_eq!("fieldOne", rename("field_one", Some("camelCase"), false));
+        assert_eq!("fieldOne", rename("field_one", Some("camelCase"), true));
+        assert_eq!("fieldone", rename("field_one", Some("lowercase"), true));
+        assert_eq!("field_one", rename("field_one", Some("lowercase"), false));
+        assert_eq!(
+            "FIELD-ONE",
+            rename("field_one", Some("SCREAMING-KEBAB-CASE"), false)
+        );
+    }
+}
@@ -1,25 +1,65 @@
 //! A Runner that extends proto-gen with a cli for code generation without direct build dependencies
 #![warn(clippy::pedantic)]
+#![allow(clippy::unnecessary_debug_formatting)]
 
+mod archive;
+mod buf;
+mod builders;
+mod cache;
+mod check;
+mod conversions;
+mod defaults;
+mod depfile;
 mod gen;
+mod ignore;
+mod include_file;
+mod json;
+mod json_schema;
 mod kv;
+mod manifest;
+mod openapi;
+mod rustfmt_pin;
+mod serde_well_known;
+mod source_map;
+mod watch;
+mod well_known;
 
-use gen::GenOptions;
+use gen::{
+    ColorChoice, EventHandler, GenEvent, GenOptions, GenerationReport, ModuleSort,
+    ModuleVisibility, Verbosity,
+};
+use ignore::IgnoreRules;
 use kv::KvValueParser;
 
+use std::collections::HashSet;
 use std::fmt::Debug;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
+use clap::builder::FalseyValueParser;
 use clap::Args;
+use clap::CommandFactory;
 use clap::Parser;
 use clap::Subcommand;
+use clap_complete::Shell;
+use regex::Regex;
 use tonic_build::Builder;
 
 use gen::ProtoWorkspace;
 
+/// Printed by `--version`, along with the version requirements of the code generators that
+/// decide the output
+const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\ntonic-build ",
+    env!("PROTO_GEN_TONIC_BUILD_VERSION"),
+    "\nprost-build ",
+    env!("PROTO_GEN_PROST_BUILD_VERSION"),
+);
+
 /// A simple runner that generates and moved rust-files form protos tonic-build into a workspace.
+#[allow(clippy::struct_excessive_bools)]
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, long_version = LONG_VERSION, about, long_about = None)]
 struct Opts {
     #[command(subcommand)]
     routine: Routine,
@@ -28,23 +68,216 @@ struct Opts {
     tonic: TonicOpts,
 
     /// Use `rustfmt` on the code after generation, `rustfmt` needs to be on the path.
-    #[clap(short, long)]
+    #[clap(short, long, env = "PROTO_GEN_FORMAT", value_parser = FalseyValueParser::new())]
     format: bool,
 
+    /// Format both the committed and the generated code with `rustfmt` before diffing, so that
+    /// only semantic differences count, such as when the output was formatted by another
+    /// `rustfmt` version. `rustfmt` needs to be on the path.
+    #[clap(long)]
+    ignore_format_diff: bool,
+
+    /// Record the `rustfmt` version used by `generate` in `<output dir>.rustfmt-version` next to
+    /// the module file, `validate` warns if the local version differs so that formatting
+    /// changes aren't mistaken for proto changes.
+    #[clap(long, requires = "format")]
+    format_check_pinned: bool,
+
     /// Prepend header indicating tool version in generated source files.
-    #[clap(short, long, default_value_t = false)]
+    #[clap(
+        short,
+        long,
+        default_value_t = false,
+        env = "PROTO_GEN_PREPEND_HEADER",
+        value_parser = FalseyValueParser::new()
+    )]
     prepend_header: bool,
 
     /// Prepend header file in generated source files.
     #[clap(long)]
     prepend_header_file: Option<PathBuf>,
 
+    /// Header file prepended instead of the regular header in generated files that only declare
+    /// child modules.
+    #[clap(long)]
+    module_header_file: Option<PathBuf>,
+
+    /// File mapping proto messages to hand written Rust types with the same field names, one
+    /// `<proto message path> = <rust type path>` per line. `From` impls in both directions are
+    /// generated into a `conversions` module in the package module of each message.
+    #[clap(long)]
+    conversions: Option<PathBuf>,
+
     /// Toplevel mod attribute to add.
     #[clap(long)]
     toplevel_attribute: Option<String>,
+
+    /// Leave out the `#![allow(clippy::doc_markdown, clippy::use_self)]` otherwise added to
+    /// the toplevel mod, `--toplevel-attribute` can be used to add your own allows instead.
+    #[clap(long)]
+    no_clippy_allows: bool,
+
+    /// Leave out the `#[allow(clippy::derive_partial_eq_without_eq)]` prost puts on generated
+    /// types. Clippy may then warn about types lacking `Eq` that could derive it, which
+    /// `--derive-eq` can add.
+    #[clap(long)]
+    no_partial_eq_allow: bool,
+
+    /// Add `#![allow(missing_docs)]` to the toplevel mod and to the generated files that declare
+    /// child modules, for crates that deny `missing_docs`.
+    #[clap(long)]
+    allow_missing_docs: bool,
+
+    /// Prepend a `// @generated` marker to all generated source files, above any other header.
+    #[clap(long)]
+    generated_marker: bool,
+
+    /// Verify that the generated code compiles by running `cargo check` on it in a scratch crate
+    /// depending on `prost`, `prost-types` and `tonic`. Requires `cargo` on the path and access
+    /// to those dependencies.
+    #[clap(long)]
+    check_compiles: bool,
+
+    /// Like `--check-compiles`, but also fail if compiling the generated code gives any
+    /// warnings, which are printed.
+    #[clap(long)]
+    fail_on_warnings: bool,
+
+    /// Run `buf lint` on each of the proto dirs containing `--proto-files` before generating
+    /// and abort with its output on failure. Skipped if `buf` is not on the path.
+    #[clap(long)]
+    buf_lint: bool,
+
+    /// Print per-file progress.
+    #[clap(short, long, conflicts_with = "quiet")]
+    verbose: bool,
+
+    /// Print the code to stdout instead of writing the output dir, nothing is diffed.
+    /// Fails if more than one module would be generated.
+    #[clap(long, conflicts_with = "verbose")]
+    stdout: bool,
+
+    /// Write the code straight into `--output-dir`, such as `OUT_DIR` in a build script, with
+    /// the module file as `mod.rs` inside it, nothing is diffed or removed. Pull it in with
+    /// `include!(concat!(env!("OUT_DIR"), "/mod.rs"))`, toplevel attributes are put on each
+    /// module declaration since `include!` doesn't allow inner attributes.
+    #[clap(long, conflicts_with = "stdout")]
+    build_script: bool,
+
+    /// Permissions of the written files as octal, so they don't depend on the umask.
+    /// Only applies on unix, where `644` is always applied unless another mode is given.
+    #[clap(long, value_parser = parse_file_mode, default_value = "644")]
+    file_mode: u32,
+
+    /// Create the temporary directories for generated code in this dir instead of the system
+    /// temp dir, such as one on the same file system as `--output-dir` to speed up copying
+    /// the code over. An explicit `--tmp-dir` is used as is.
+    #[clap(long)]
+    tmp_base: Option<PathBuf>,
+
+    /// Print at most this many changed files when diffing, followed by a count of the rest.
+    #[clap(long)]
+    max_diff_print: Option<usize>,
+
+    /// Print a stable line per changed file to stdout instead of any progress or diff messages,
+    /// `diff`, `new` or `removed` followed by a tab and the path, and `ok` once the command
+    /// succeeded, for scripts. Errors are still printed to stderr.
+    #[clap(long, conflicts_with_all = ["verbose", "max_diff_print", "stdout"])]
+    porcelain: bool,
+
+    /// When to color the changed files printed when diffing. `auto` colors when printing
+    /// to a terminal, unless the `NO_COLOR` environment variable is set.
+    #[clap(long, value_enum, default_value_t)]
+    color: ColorChoice,
+
+    /// Exit code of `validate` when the generated code differs from the output dir.
+    /// Other errors, such as from protoc or the filesystem, always exit with 1.
+    #[clap(long, default_value_t = 1)]
+    drift_exit_code: i32,
+
+    /// Only print diffs and errors.
+    #[clap(short, long)]
+    quiet: bool,
+
+    /// Leave files matching this `.gitignore` style pattern, relative to the output dir,
+    /// out of the diff. Ignored files are neither diffed nor overwritten.
+    #[clap(long)]
+    ignore: Vec<String>,
+
+    /// Only generate the proto files matching this `.gitignore` style pattern, relative to
+    /// their proto dir, along with the other files of their packages and the protos they
+    /// import. The other modules already in the output dirs are kept as they are, new modules
+    /// get declared in their parents.
+    #[clap(long)]
+    only: Vec<String>,
+
+    /// Leave out the packages whose proto files all match this `.gitignore` style pattern,
+    /// relative to their proto dir, such as protos that are only imported. Fails if the
+    /// remaining code refers to their types.
+    #[clap(long = "exclude-file")]
+    exclude_files: Vec<String>,
+
+    /// Let `generate` delete files in the output dir that proto-gen didn't generate, the ones
+    /// not declared by the module tree of the existing module file. Without it, generation fails
+    /// instead of wiping an output dir with hand written files.
+    #[clap(long)]
+    force: bool,
+
+    /// Empty the `--tmp-dir` before generating into it, so files left from an earlier run
+    /// don't end up as modules. Everything in it is deleted.
+    #[clap(long)]
+    clean_tmp: bool,
+
+    /// Write the leading comment of each proto file's `package` statement as `//!` docs at the
+    /// top of the package's module file. Skipped for packages covered by `--disable-comments`.
+    #[clap(long)]
+    module_docs: bool,
+
+    /// Keep packages without any generated code as empty module files, instead of leaving
+    /// them out of the module tree.
+    #[clap(long)]
+    keep_empty_modules: bool,
+
+    /// Move generated tonic client and server modules into a `<package>_service` child module
+    /// of each package, re-exported from the package module so their paths stay the same.
+    #[clap(long)]
+    split_services: bool,
+
+    /// Put child modules inline in their parent's file, as `pub mod child { ... }`, instead of
+    /// in files of their own. Each top level module still gets its own file.
+    #[clap(long, conflicts_with_all = ["split_services", "only"])]
+    inline_children: bool,
+
+    /// Lay out every package as a top level module named after the last segment of its
+    /// package, so `a.b.c` becomes `c`, instead of nesting a module per package segment.
+    /// Packages whose last segments are the same are an error, as are references to types in
+    /// packages that don't share the same parent package, since prost's paths wouldn't resolve.
+    #[clap(long, conflicts_with = "only")]
+    flatten_packages: bool,
+
+    /// Add a `#[cfg(test)]` gated `mocks` module to each package with services, with a mock
+    /// implementation of each tonic server trait that takes a handler closure per method.
+    /// Requires `--build-server`.
+    #[clap(long, requires = "build_server")]
+    generate_mocks: bool,
+
+    /// Visibility of the generated module declarations, at every nesting level.
+    #[clap(long, value_enum, default_value_t)]
+    module_visibility: ModuleVisibility,
+
+    /// Order of the generated module declarations, `case-insensitive` puts `bar` before `Foo`.
+    #[clap(long, value_enum, default_value_t)]
+    sort_modules: ModuleSort,
+
+    /// Declare all top level modules inside an inline module with this name, without
+    /// changing proto packages. Their files are placed in a dir of the same name in the
+    /// output dir.
+    #[clap(long, value_parser = parse_module_name)]
+    wrap_module: Option<String>,
 }
 
-#[derive(Args, Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Args, Debug, Clone, Default)]
 struct TonicOpts {
     /// Whether to build server code.
     #[clap(short = 's', long)]
@@ -58,14 +291,49 @@ struct TonicOpts {
     #[clap(long)]
     generate_transport: bool,
 
+    /// Generate clients for use over gRPC-web, for example with `tonic_web::GrpcWebClientLayer`.
+    /// gRPC-web has no client or bidirectional streaming, so generation fails if a service
+    /// uses them. The tonic transport isn't available in browsers and can't be generated.
+    #[clap(long, requires = "build_client", conflicts_with = "generate_transport")]
+    grpc_web: bool,
+
+    /// Print `cargo:rerun-if-changed` directives for the proto files and dirs,
+    /// for when proto-gen is run from a build script.
+    #[clap(long)]
+    emit_rerun_if_changed: bool,
+
     /// Disable comments based on proto path. Passing '.' disables all comments.
     #[clap(short, long)]
     disable_comments: Vec<String>,
 
+    /// Disable comments for all proto paths matching a regex, such as `^\.my\.package\.`.
+    /// Paths are fully qualified, like `.my.package.Message.field`.
+    #[clap(long, value_parser = Regex::new)]
+    disable_comments_regex: Vec<Regex>,
+
+    /// Keep comments for a proto path and everything below it, even when disabled by a parent
+    /// path, such as with `--disable-comments .`. The comments of its parents are kept too,
+    /// since disabling them would also disable the retained path.
+    #[clap(long)]
+    retain_comments: Vec<String>,
+
     /// Output maps as `BTreeMap` instead of `HashMap`. Passing '.' makes all maps `BTreeMap`.
     #[clap(short, long = "btree-map")]
     btree_maps: Vec<String>,
 
+    /// Box the message fields matching this path, such as `.my.package.Message.field`, to
+    /// shrink the message containing them. Can be given multiple times.
+    #[clap(long)]
+    boxed: Vec<String>,
+
+    /// Generate code that builds in `#![no_std]` crates with `alloc`, by making all maps
+    /// `BTreeMap`. The crate needs to depend on `prost` without default features.
+    /// The code only refers to `alloc` through `::prost::alloc`, so the top-level module
+    /// needs no attributes and the crate no `extern crate alloc`.
+    /// Tonic services need `std` and can't be built.
+    #[clap(long, conflicts_with_all = ["build_server", "build_client"])]
+    no_std: bool,
+
     /// Type attributes to add.
     #[clap(long = "type-attribute", value_parser=KvValueParser)]
     type_attributes: Vec<(String, String)>,
@@ -74,6 +342,10 @@ struct TonicOpts {
     #[clap(long = "enum-attribute", value_parser=KvValueParser)]
     enum_attributes: Vec<(String, String)>,
 
+    /// Message attributes to add, unlike `--type-attribute` they are not added to enums.
+    #[clap(long = "message-attribute", value_parser=KvValueParser)]
+    message_attributes: Vec<(String, String)>,
+
     /// Client mod attributes to add.
     #[clap(long = "client-attribute", value_parser=KvValueParser)]
     client_attributes: Vec<(String, String)>,
@@ -81,6 +353,83 @@ struct TonicOpts {
     /// Server mod attributes to add.
     #[clap(long = "server-attribute", value_parser=KvValueParser)]
     server_attributes: Vec<(String, String)>,
+
+    /// Only compile the generated server modules with this cargo feature enabled.
+    #[clap(long)]
+    server_feature: Option<String>,
+
+    /// Codec used by the generated clients and servers instead of `tonic::codec::ProstCodec`,
+    /// like `crate::codec::JsonCodec`. It's instantiated with `Default` for each method, so it
+    /// has to be a `<T, U>` generic type implementing `tonic::codec::Codec` with `Encode = T`
+    /// and `Decode = U` for the request and response messages, like `ProstCodec`.
+    #[clap(long)]
+    codec_path: Option<String>,
+
+    /// Only compile the generated client modules with this cargo feature enabled.
+    #[clap(long)]
+    client_feature: Option<String>,
+
+    /// Derive `Eq` and `Hash` for types based on proto path. Passing '.' targets all types.
+    /// Fails to compile if a targeted message contains `float` or `double` fields, or `map`
+    /// fields that aren't `BTreeMap`s from `--btree-map`, as `HashMap` doesn't implement `Hash`.
+    #[clap(long)]
+    derive_eq: Vec<String>,
+
+    /// Derive `arbitrary::Arbitrary` in tests for types based on proto path, for fuzzing.
+    /// Passing '.' targets all types. Requires the `arbitrary` crate with the `derive` feature.
+    #[clap(long)]
+    derive_arbitrary: Vec<String>,
+
+    /// Generate a builder with chainable setters for messages based on proto path, into a
+    /// `builders` module in the package module. Passing '.' targets all messages.
+    /// Built with `Message::builder().field(value).build()`.
+    #[clap(long)]
+    builders: Vec<String>,
+
+    /// Generate a `with_defaults()` constructor for messages based on proto path, setting
+    /// their optional scalar fields to their proto defaults instead of `None`, into a `defaults`
+    /// module in the package module. Passing '.' targets all messages. Prost already derives
+    /// `Default` for messages, so this is an inherent constructor rather than an `impl Default`.
+    #[clap(long)]
+    default_impl: Vec<String>,
+
+    /// Derive `serde::Serialize` and `serde::Deserialize` for all types.
+    /// Shorthand for `--type-attribute .:#[derive(serde::Serialize, serde::Deserialize)]`.
+    #[clap(long)]
+    derive_serde: bool,
+
+    /// Add `#[serde(rename_all = "<case>")]` to all types, requires `--derive-serde`.
+    /// It renames the fields of messages, but also the variants of enums and oneofs,
+    /// so `DarkRed` serializes as `"darkRed"` with `camelCase`.
+    #[clap(long, requires = "derive_serde")]
+    serde_rename_all: Option<String>,
+
+    /// Serialize `google.protobuf.Timestamp` fields as RFC 3339 strings and
+    /// `google.protobuf.Duration` fields as ISO 8601 strings in seconds, like `PT1.5S`, requires
+    /// `--derive-serde`. The fields get `#[serde(with)]` attributes pointing at a
+    /// `serde_well_known` helper module generated into their package module.
+    #[clap(long, requires = "derive_serde")]
+    serde_well_known: bool,
+
+    /// Keep the enum name prefix on generated enum variants instead of stripping it.
+    #[clap(long)]
+    retain_enum_prefix: bool,
+
+    /// Mark generated enums `#[non_exhaustive]` based on proto path. Passing '.' targets all enums.
+    /// Only applies to proto enums, not oneofs. The generated `TryFrom<i32>` impls are unaffected,
+    /// values unknown to this version still produce an error.
+    #[clap(long)]
+    non_exhaustive_enums: Vec<String>,
+
+    /// Generate enums without the `as_str_name` and `from_str_name` helpers based on proto path.
+    /// Passing '.' targets all enums. The `TryFrom<i32>`, `From<Enum> for i32`, `is_valid` and
+    /// `Default` impls come from the `Enumeration` derive and are kept, messages rely on them.
+    #[clap(long)]
+    minimal_enums: Vec<String>,
+
+    /// Module name for protos without a package, defaults to `_` which isn't a valid module name.
+    #[clap(long)]
+    default_package_filename: Option<String>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -96,13 +445,39 @@ enum Routine {
     Generate {
         #[clap(flatten)]
         workspace: WorkspaceOpts,
+
+        /// Keep running after generating, regenerating whenever a proto file in
+        /// `--proto-dirs` or `--proto-files`, the `--from-image` image or the
+        /// `--proto-files-from` list changes.
+        #[clap(long)]
+        watch: bool,
+    },
+
+    /// Generate Rust code for proto files into the temp dir and print the resulting
+    /// module tree to stdout, without touching the output dir.
+    List {
+        #[clap(flatten)]
+        workspace: WorkspaceOpts,
+
+        /// Print the content of the module file declaring the top level modules instead of
+        /// the module tree, for placing the generated files elsewhere.
+        #[clap(long)]
+        top_module: bool,
+    },
+
+    /// Print a shell completion script to stdout.
+    #[command(hide = true)]
+    Completions {
+        #[clap(value_enum)]
+        shell: Shell,
     },
 }
 
-#[derive(Debug, Args, Clone)]
+#[derive(Debug, Args, Clone, Default)]
 struct WorkspaceOpts {
     /// Directories containing proto files to source (Ex. Dependencies),
-    /// needs to include any directory containing files to be included in generation.
+    /// the directories containing the files to be included in generation are added automatically.
+    /// Without any, the comma separated directories in `PROTO_GEN_PROTO_DIRS` are used.
     #[clap(short = 'd', long)]
     proto_dirs: Vec<PathBuf>,
 
@@ -110,66 +485,357 @@ struct WorkspaceOpts {
     #[clap(short = 'f', long)]
     proto_files: Vec<PathBuf>,
 
+    /// Read additional files to be included in generation from this file, one path per line.
+    /// Blank lines and lines starting with `#` are skipped.
+    #[clap(long, conflicts_with = "from_image")]
+    proto_files_from: Option<PathBuf>,
+
+    /// Generate from a serialized `FileDescriptorSet`, such as an image from `buf build`,
+    /// instead of running protoc. Every file in the image is generated.
+    #[clap(long, conflicts_with_all = ["proto_files", "proto_dirs"])]
+    from_image: Option<PathBuf>,
+
+    /// Write the file descriptor set protoc produces to this file, with a fingerprint of the
+    /// proto files next to it in `<file>.fingerprint`. While the protos are unchanged, later
+    /// runs generate from it instead of running protoc, such as `validate` in a pre-commit hook.
+    #[clap(long, conflicts_with = "from_image")]
+    descriptor_set_path: Option<PathBuf>,
+
     /// Temporary working directory, if left blank, `tempfile` is used to create a temporary
-    /// directory.
+    /// directory. It's kept afterwards, to look at the intermediate output, and only emptied
+    /// before generating with `--clean-tmp`.
     #[clap(short, long)]
     tmp_dir: Option<PathBuf>,
 
     /// Where to place output files. Will get cleaned up (all contents deleted).
-    /// A module file will be placed in the parent of this directory.
-    #[clap(short, long)]
+    /// A module file will be placed in the parent of this directory. If it's a symlink, the
+    /// link is kept and its target cleaned up instead, the module file goes next to the link.
+    #[clap(short, long, env = "PROTO_GEN_OUTPUT_DIR")]
     output_dir: PathBuf,
+
+    /// File storing a hash of the proto inputs, options and generated output,
+    /// generation is skipped if nothing changed since the last successful run.
+    #[clap(long)]
+    cache: Option<PathBuf>,
+
+    /// Place a top level proto package in its own output dir instead of `--output-dir`,
+    /// given as `<package>:<dir>`. Each dir gets its own module file in its parent.
+    #[clap(long = "package-output", value_parser=KvValueParser)]
+    package_outputs: Vec<(String, String)>,
+
+    /// Write a Makefile style depfile listing the generated files and every proto file
+    /// read during generation, including transitive imports.
+    #[clap(long)]
+    depfile: Option<PathBuf>,
+
+    /// Write the paths of all generated files to this file after a successful `generate`,
+    /// one per line and sorted. Paths are relative to the directory containing the module
+    /// file of `--output-dir`.
+    #[clap(long)]
+    manifest: Option<PathBuf>,
+
+    /// Write a JSON object mapping each generated package file to the proto files that declare
+    /// its package after a successful `generate`. Paths are relative like in `--manifest`.
+    #[clap(long)]
+    source_map: Option<PathBuf>,
+
+    /// Write a file that includes all generated code after a successful `generate`, so a crate
+    /// only needs a single `mod` for it. The module files of `--output-dir` and each
+    /// `--package-output` are inlined with a `#[path]` to their dirs and their modules
+    /// re-exported, in the same order as the module files.
+    #[clap(long)]
+    include_file: Option<PathBuf>,
+
+    /// Write an `OpenAPI` 3 description of the gRPC services to this file, with each RPC as a
+    /// `POST /<package>.<Service>/<Method>` endpoint taking and returning JSON. A path inside
+    /// `--output-dir` is diffed along with the generated code, otherwise it's only written
+    /// by `generate`.
+    #[clap(long)]
+    openapi: Option<PathBuf>,
+
+    /// Write a JSON Schema for each top level message to this dir after a successful
+    /// `generate`, named `<package>.<Message>.schema.json`. The schemas describe the JSON
+    /// from `--derive-serde` with `serde_json`, honoring `--serde-rename-all`.
+    #[clap(long)]
+    json_schema: Option<PathBuf>,
+
+    /// Pack the generated code into this zip archive instead of writing it into `--output-dir`,
+    /// nothing is diffed. The archive contains the module file and the output dir under their
+    /// names, as they would be placed in the output dir's parent.
+    #[clap(long, conflicts_with_all = ["cache", "depfile", "manifest", "source_map", "include_file"])]
+    archive: Option<PathBuf>,
+
+    /// Write the module file of `--output-dir` to this path instead of next to the dir, and
+    /// diff against it in `validate`. Its declarations resolve from where it's placed, such as
+    /// the `lib.rs` of a crate generated with `--output-dir src`, which is then kept when
+    /// cleaning the dir. Custom sections in it are kept like in the default module file.
+    #[clap(long, conflicts_with = "archive")]
+    mod_file: Option<PathBuf>,
+
+    /// Add an include dir with the bundled well-known types `google/protobuf/timestamp.proto`,
+    /// `duration.proto`, `empty.proto` and `wrappers.proto`, for protoc installs without them.
+    #[clap(long)]
+    with_well_known_includes: bool,
+
+    /// Use `--proto-dirs` and `--proto-files` as given instead of making them absolute
+    /// before passing them to protoc.
+    #[clap(long)]
+    no_canonicalize: bool,
 }
 
-fn main() -> Result<(), i32> {
+fn main() {
     let opts: Opts = Opts::parse();
-    run_with_opts(opts)
+    if let Err(code) = run_with_opts(opts) {
+        std::process::exit(code);
+    }
 }
 
 fn run_with_opts(opts: Opts) -> Result<(), i32> {
+    let (mut ws, commit, watch, list, top_module) = match opts.routine {
+        Routine::Validate { workspace } => (workspace, false, false, false, false),
+        Routine::Generate { workspace, watch } => (workspace, true, watch, false, false),
+        Routine::List {
+            workspace,
+            top_module,
+        } => (workspace, false, false, true, top_module),
+        Routine::Completions { shell } => {
+            let mut cmd = Opts::command();
+            let name = cmd.get_name().to_string();
+            clap_complete::generate(shell, &mut cmd, name, &mut std::io::stdout());
+            return Ok(());
+        }
+    };
+    if ws.proto_dirs.is_empty() {
+        ws.proto_dirs = proto_dirs_from_env();
+    }
+    let gen_opts = GenOptions {
+        commit,
+        list,
+        top_module,
+        stdout: opts.stdout,
+        build_script: opts.build_script,
+        file_mode: Some(opts.file_mode),
+        format: opts.format,
+        ignore_format_diff: opts.ignore_format_diff,
+        format_check_pinned: opts.format_check_pinned,
+        prepend_header: prepend_header(opts.prepend_header, opts.prepend_header_file)?,
+        module_header: opts
+            .module_header_file
+            .map(|file| read_header_file(&file))
+            .transpose()?,
+        toplevel_attribute: opts.toplevel_attribute,
+        no_clippy_allows: opts.no_clippy_allows,
+        inline_children: opts.inline_children,
+        flatten_packages: opts.flatten_packages,
+        tmp_base: opts.tmp_base,
+        no_partial_eq_allow: opts.no_partial_eq_allow,
+        allow_missing_docs: opts.allow_missing_docs,
+        derive_eq: !opts.tonic.derive_eq.is_empty(),
+        non_exhaustive_enums: !opts.tonic.non_exhaustive_enums.is_empty(),
+        minimal_enums: !opts.tonic.minimal_enums.is_empty(),
+        generated_marker: opts.generated_marker,
+        check_compiles: opts.check_compiles,
+        fail_on_warnings: opts.fail_on_warnings,
+        buf_lint: opts.buf_lint,
+        no_std: opts.tonic.no_std,
+        verbosity: verbosity(opts.quiet || opts.porcelain, opts.verbose),
+        server_feature: opts.tonic.server_feature.clone(),
+        codec_path: opts.tonic.codec_path.clone(),
+        grpc_web: opts.tonic.grpc_web,
+        omit_services: !opts.tonic.build_client && !opts.tonic.build_server,
+        client_feature: opts.tonic.client_feature.clone(),
+        ignore: opts.ignore,
+        only: opts.only,
+        exclude_files: opts.exclude_files,
+        force: opts.force,
+        clean_tmp: opts.clean_tmp,
+        module_docs: opts.module_docs,
+        keep_empty_modules: opts.keep_empty_modules,
+        split_services: opts.split_services,
+        generate_mocks: opts.generate_mocks,
+        porcelain: opts.porcelain,
+        max_diff_print: opts.max_diff_print,
+        module_visibility: opts.module_visibility,
+        sort_modules: opts.sort_modules,
+        wrap_module: opts.wrap_module,
+        disable_comments: opts.tonic.disable_comments.clone(),
+        disable_comments_regex: opts.tonic.disable_comments_regex.clone(),
+        retain_comments: opts.tonic.retain_comments.clone(),
+        serde_rename_all: opts.tonic.serde_rename_all.clone(),
+        serde_well_known: opts.tonic.serde_well_known,
+        builders: opts.tonic.builders.clone(),
+        default_impl: opts.tonic.default_impl.clone(),
+        conversions: read_conversions(opts.conversions.as_deref())?,
+        color: opts.color,
+        on_event: opts.verbose.then(print_progress),
+    };
+    let options_fingerprint = format!("{:?}{gen_opts:?}", opts.tonic);
+    let generate = || {
+        run_ws(
+            ws.clone(),
+            configure_tonic(&opts.tonic),
+            configure_prost(&opts.tonic),
+            &gen_opts,
+            &options_fingerprint,
+        )
+    };
+    report_outcome(generate(), opts.porcelain, opts.drift_exit_code)?;
+    if watch {
+        // Only generating can be watched, which never leaves a diff behind
+        watch_workspace(&ws, gen_opts.verbosity, || generate().map(|_| ()))?;
+    }
+    Ok(())
+}
+
+/// Prints how a run went, the number of diffs found when validating or an error, returning
+/// the exit code to fail with
+fn report_outcome(
+    outcome: Result<usize, String>,
+    porcelain: bool,
+    drift_exit_code: i32,
+) -> Result<(), i32> {
+    match outcome {
+        Ok(0) if porcelain => println!("ok"),
+        Ok(0) => {}
+        Ok(_) if porcelain => return Err(drift_exit_code),
+        Ok(diff) => {
+            eprintln!("Found {diff} diffs against the generated code");
+            return Err(drift_exit_code);
+        }
+        Err(err) => {
+            eprintln!("Failed to run command \n{err}");
+            return Err(1);
+        }
+    }
+    Ok(())
+}
+
+/// The directories in `PROTO_GEN_PROTO_DIRS`, split on commas there only as a path given
+/// with `--proto-dirs` may contain one
+fn proto_dirs_from_env() -> Vec<PathBuf> {
+    std::env::var("PROTO_GEN_PROTO_DIRS")
+        .map(|dirs| {
+            dirs.split(',')
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Regenerates whenever one of the inputs of the workspace changes
+fn watch_workspace(
+    ws: &WorkspaceOpts,
+    verbosity: Verbosity,
+    regenerate: impl Fn() -> Result<(), String>,
+) -> Result<(), i32> {
+    let mut watched = ws.proto_dirs.clone();
+    watched.extend(ws.proto_files.iter().cloned());
+    watched.extend(ws.from_image.iter().cloned());
+    watched.extend(ws.proto_files_from.iter().cloned());
+    watch::watch_protos(&watched, verbosity, regenerate).map_err(|err| {
+        eprintln!("Failed to watch protos \n{err}");
+        1
+    })
+}
+
+fn parse_file_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("{mode:?} is not an octal file mode such as 644"))
+}
+
+fn parse_module_name(name: &str) -> Result<String, String> {
+    let mut chars = name.chars();
+    let valid_start = chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_');
+    if valid_start && name != "_" && chars.all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(name.to_string())
+    } else {
+        Err(format!("{name:?} is not a valid module name"))
+    }
+}
+
+fn configure_tonic(tonic: &TonicOpts) -> Builder {
     let mut bldr = tonic_build::configure()
-        .build_client(opts.tonic.build_client)
-        .build_server(opts.tonic.build_server)
-        .build_transport(opts.tonic.generate_transport)
+        .build_client(tonic.build_client)
+        .build_server(tonic.build_server)
+        .build_transport(tonic.generate_transport)
         // this is only when being used from build scripts
-        .emit_rerun_if_changed(false);
+        .emit_rerun_if_changed(tonic.emit_rerun_if_changed);
 
-    for (k, v) in opts.tonic.type_attributes {
+    for (k, v) in &tonic.type_attributes {
         bldr = bldr.type_attribute(k, v);
     }
 
-    for (k, v) in opts.tonic.enum_attributes {
+    for (k, v) in &tonic.enum_attributes {
         bldr = bldr.enum_attribute(k, v);
     }
 
-    for (k, v) in opts.tonic.client_attributes {
+    for (k, v) in &tonic.message_attributes {
+        bldr = bldr.message_attribute(k, v);
+    }
+
+    for (k, v) in &tonic.client_attributes {
         bldr = bldr.client_mod_attribute(k, v);
     }
 
-    for (k, v) in opts.tonic.server_attributes {
+    for (k, v) in &tonic.server_attributes {
         bldr = bldr.server_mod_attribute(k, v);
     }
 
+    for path in &tonic.derive_arbitrary {
+        bldr = bldr.type_attribute(path, "#[cfg_attr(test, derive(arbitrary::Arbitrary))]");
+    }
+
+    for path in &tonic.derive_eq {
+        bldr = bldr.type_attribute(path, gen::DERIVE_EQ_ATTRIBUTE);
+    }
+
+    for path in &tonic.non_exhaustive_enums {
+        bldr = bldr.enum_attribute(path, gen::NON_EXHAUSTIVE_ATTRIBUTE);
+    }
+
+    for path in &tonic.minimal_enums {
+        bldr = bldr.enum_attribute(path, gen::MINIMAL_ENUM_ATTRIBUTE);
+    }
+
+    if tonic.derive_serde {
+        bldr = bldr.type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]");
+        if let Some(case) = &tonic.serde_rename_all {
+            bldr = bldr.type_attribute(".", format!("#[serde(rename_all = \"{case}\")]"));
+        }
+    }
+
+    bldr
+}
+
+fn configure_prost(tonic: &TonicOpts) -> prost_build::Config {
     let mut config = prost_build::Config::new();
-    config.disable_comments(opts.tonic.disable_comments);
+    config.disable_comments(&tonic.disable_comments);
+
+    if tonic.no_std {
+        // `HashMap` is only available in std
+        config.btree_map(["."]);
+    } else {
+        config.btree_map(&tonic.btree_maps);
+    }
 
-    config.btree_map(opts.tonic.btree_maps);
+    for path in &tonic.boxed {
+        config.boxed(path);
+    }
 
-    let (ws, commit) = match opts.routine {
-        Routine::Validate { workspace } => (workspace, false),
-        Routine::Generate { workspace } => (workspace, true),
-    };
-    let gen_opts = GenOptions {
-        commit,
-        format: opts.format,
-        prepend_header: prepend_header(opts.prepend_header, opts.prepend_header_file)?,
-        toplevel_attribute: opts.toplevel_attribute,
-    };
-    if let Err(err) = run_ws(ws, bldr, config, &gen_opts) {
-        eprintln!("Failed to run command \n{err}");
-        return Err(1);
+    if tonic.retain_enum_prefix {
+        config.retain_enum_prefix();
     }
-    Ok(())
+
+    if let Some(default_package_filename) = &tonic.default_package_filename {
+        config.default_package_filename(default_package_filename);
+    }
+
+    config
 }
 
 fn prepend_header(
@@ -186,10 +852,7 @@ fn prepend_header(
     }
 
     if let Some(prepend_header_file) = prepend_header_file {
-        let content = std::fs::read_to_string(&prepend_header_file).map_err(|e| {
-            eprintln!("Failed to read header file {prepend_header_file:?}: {e}");
-            1
-        })?;
+        let content = read_header_file(&prepend_header_file)?;
 
         maybe_header
             .get_or_insert_with(String::new)
@@ -199,62 +862,420 @@ fn prepend_header(
     Ok(maybe_header)
 }
 
+fn read_header_file(header_file: &Path) -> Result<String, i32> {
+    std::fs::read_to_string(header_file).map_err(|e| {
+        eprintln!("Failed to read header file {header_file:?}: {e}");
+        1
+    })
+}
+
+fn read_conversions(conversions: Option<&Path>) -> Result<Vec<(String, String)>, i32> {
+    conversions
+        .map(conversions::read_mapping)
+        .transpose()
+        .map(Option::unwrap_or_default)
+        .map_err(|e| {
+            eprintln!("{e}");
+            1
+        })
+}
+
+fn verbosity(quiet: bool, verbose: bool) -> Verbosity {
+    if quiet {
+        Verbosity::Quiet
+    } else if verbose {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    }
+}
+
+/// Prints the progress that isn't printed by default, for `--verbose`
+fn print_progress() -> EventHandler {
+    EventHandler(Box::new(|event| match event {
+        GenEvent::CompileStarted => println!("Running protoc and code generators"),
+        GenEvent::Formatting(file) => println!("Formatting {file:?}"),
+        // Diffs are always printed
+        GenEvent::DiffFound(_) => {}
+        GenEvent::Committed(path) => println!("Committed {path:?}"),
+    }))
+}
+
+/// Protoc errors for missing inputs are hard to decipher, so they're checked upfront
+fn check_inputs(opts: &WorkspaceOpts) -> Result<(), String> {
+    if let Some(image) = &opts.from_image {
+        if !image.is_file() {
+            return Err(format!(
+                "Image {image:?} from --from-image is not an existing file"
+            ));
+        }
+    } else if opts.proto_files.is_empty() {
+        return Err("--proto-files needs at least one file to generate".to_string());
+    }
+    if let Some(missing) = opts.proto_files.iter().find(|file| !file.is_file()) {
+        return Err(format!(
+            "Proto file {missing:?} from --proto-files is not an existing file"
+        ));
+    }
+    if let Some(missing) = opts.proto_dirs.iter().find(|dir| !dir.is_dir()) {
+        return Err(format!(
+            "Proto dir {missing:?} from --proto-dirs is not an existing directory"
+        ));
+    }
+    Ok(())
+}
+
+/// Limits the proto files to the ones matching `--only`, relative to the proto dir they're in
+fn select_only(ws: &mut ProtoWorkspace, only: &[String]) -> Result<(), String> {
+    if only.is_empty() {
+        return Ok(());
+    }
+    if ws.image.is_some() {
+        return Err("--only can't select protos from an image given with --from-image".to_string());
+    }
+    let mut rules = IgnoreRules::default();
+    for pattern in only {
+        rules.add(pattern);
+    }
+    let proto_dirs = &ws.proto_dirs;
+    let packages = ws
+        .proto_files
+        .iter()
+        .map(|file| proto_package(file).map(|package| (file.clone(), package)))
+        .collect::<Result<Vec<_>, _>>()?;
+    // Code is generated per package, so a package is regenerated from all of its files,
+    // otherwise the types of the unselected files would be dropped from its module
+    let selected = packages
+        .iter()
+        .filter(|(file, _)| {
+            let relative = proto_dirs
+                .iter()
+                .find_map(|dir| file.strip_prefix(dir).ok())
+                .unwrap_or(file);
+            rules.matches(relative)
+        })
+        .map(|(_, package)| package.as_str())
+        .collect::<HashSet<_>>();
+    if selected.is_empty() {
+        return Err(format!("No proto files match --only {only:?}"));
+    }
+    ws.proto_files = packages
+        .iter()
+        .filter(|(_, package)| selected.contains(package.as_str()))
+        .map(|(file, _)| file.clone())
+        .collect();
+    Ok(())
+}
+
+/// The package a proto file declares, empty without a `package` statement
+fn proto_package(file: &Path) -> Result<String, String> {
+    let package = Regex::new(r"(?m)^\s*package\s+([\w.]+)\s*;").expect("Package regex is valid");
+    let content = std::fs::read_to_string(file)
+        .map_err(|e| format!("Failed to read proto file {file:?} \n{e}"))?;
+    Ok(package
+        .captures(&content)
+        .map(|captures| captures[1].to_string())
+        .unwrap_or_default())
+}
+
+/// Generates, or validates without committing, returning how many files differ from the
+/// output dirs when validating
 fn run_ws(
-    opts: WorkspaceOpts,
+    mut opts: WorkspaceOpts,
     bldr: Builder,
     config: prost_build::Config,
     gen_opts: &GenOptions,
-) -> Result<(), String> {
-    if opts.proto_files.is_empty() {
-        return Err("--proto-files needs at least one file to generate".to_string());
+    options_fingerprint: &str,
+) -> Result<usize, String> {
+    if let Some(list) = &opts.proto_files_from {
+        opts.proto_files.extend(read_proto_files(list)?);
     }
-    if let Some(tmp) = opts.tmp_dir {
-        gen::run_generation(
-            &ProtoWorkspace {
-                proto_dirs: opts.proto_dirs,
-                proto_files: opts.proto_files,
-                tmp_dir: tmp,
-                output_dir: opts.output_dir,
-            },
-            bldr,
-            config,
-            gen_opts,
-        )
+    check_inputs(&opts)?;
+    // Relative paths resolve differently depending on the cwd, which can change protoc's output
+    let (proto_dirs, proto_files) = if opts.no_canonicalize {
+        (opts.proto_dirs, opts.proto_files)
+    } else {
+        canonicalize_inputs(&opts.proto_dirs, &opts.proto_files)?
+    };
+    // Deleted on drop
+    let tmp_holder;
+    let tmp_dir = if let Some(tmp) = opts.tmp_dir {
+        tmp
     } else {
-        // Deleted on drop
-        let tmp = tempfile::tempdir().map_err(|e| format!("Failed to create tempdir \n{e}"))?;
-        gen::run_generation(
-            &ProtoWorkspace {
-                proto_dirs: opts.proto_dirs,
-                proto_files: opts.proto_files,
-                tmp_dir: tmp.path().to_path_buf(),
-                output_dir: opts.output_dir,
-            },
-            bldr,
-            config,
+        tmp_holder = gen::working_tempdir(gen_opts.tmp_base.as_deref())
+            .map_err(|e| format!("Failed to create tempdir \n{e}"))?;
+        tmp_holder.path().to_path_buf()
+    };
+    let cwd = std::env::current_dir()
+        .map_err(|e| format!("Failed to get current dir to resolve output dirs \n{e}"))?;
+    let mut ws = ProtoWorkspace {
+        proto_dirs: include_dirs(proto_dirs, &proto_files),
+        proto_files,
+        tmp_dir,
+        output_dir: resolve_output_dir(&cwd, &opts.output_dir),
+        package_outputs: opts
+            .package_outputs
+            .into_iter()
+            .map(|(package, dir)| (package, resolve_output_dir(&cwd, Path::new(&dir))))
+            .collect(),
+        image: opts.from_image,
+        openapi: opts
+            .openapi
+            .map(|openapi| resolve_output_dir(&cwd, &openapi)),
+        archive: opts.archive,
+        mod_file: opts
+            .mod_file
+            .map(|mod_file| resolve_output_dir(&cwd, &mod_file)),
+    };
+    select_only(&mut ws, &gen_opts.only)?;
+    // Deleted on drop
+    let well_known = opts
+        .with_well_known_includes
+        .then(well_known::write_includes)
+        .transpose()?;
+    let well_known = well_known.as_ref().map(tempfile::TempDir::path);
+    let stale_descriptors = opts
+        .descriptor_set_path
+        .as_deref()
+        .map(|path| use_cached_descriptors(path, &mut ws, gen_opts.verbosity))
+        .transpose()?
+        .flatten();
+    if gen_opts.list || gen_opts.stdout || gen_opts.build_script || ws.archive.is_some() {
+        run_undiffed(&mut ws, well_known, bldr, config, gen_opts)?;
+        return Ok(0);
+    }
+    if let Some(cache) = &opts.cache {
+        if cache::is_fresh(cache, cache::fingerprint(&ws, options_fingerprint)?) {
+            if !gen_opts.verbosity.is_quiet() {
+                println!("Inputs unchanged according to cache at {cache:?}, skipping generation");
+            }
+            return Ok(0);
+        }
+    }
+    let report = run_generation_with(
+        &mut ws,
+        well_known,
+        bldr,
+        config,
+        gen_opts,
+        gen::run_generation,
+    )?;
+    if let (Some(path), Some(fingerprint)) = (&opts.descriptor_set_path, stale_descriptors) {
+        cache::store_descriptors(path, &report.file_descriptor_set, fingerprint)?;
+    }
+    if gen_opts.format_check_pinned {
+        rustfmt_pin::pin(&ws, gen_opts, report.diff)?;
+    }
+    if !gen_opts.commit && report.diff > 0 {
+        return Ok(report.diff);
+    }
+    if let Some(depfile) = &opts.depfile {
+        depfile::write(depfile, &ws, &report.file_descriptor_set)?;
+    }
+    if gen_opts.commit {
+        let include_file = opts
+            .include_file
+            .map(|include_file| resolve_output_dir(&cwd, &include_file));
+        write_companion_files(
+            opts.manifest.as_deref(),
+            opts.source_map.as_deref(),
+            include_file.as_deref(),
+            opts.json_schema.as_deref(),
+            &ws,
+            &report,
             gen_opts,
-        )
+        )?;
     }
+    if let Some(cache) = &opts.cache {
+        cache::store(cache, cache::fingerprint(&ws, options_fingerprint)?)?;
+    }
+    Ok(0)
 }
 
-#[cfg(all(test, feature = "protoc-tests"))]
-mod tests {
-    use super::*;
-    use std::path::Path;
-    use tempfile::TempDir;
-
-    struct SimpleTestCfg {
-        _keep_alive_project_base: TempDir,
-        tonic: TonicOpts,
-        workspace: WorkspaceOpts,
+/// Generates from the file descriptor set at `path` instead of running protoc if it was
+/// written for the current protos, otherwise returns the fingerprint to store it with
+fn use_cached_descriptors(
+    path: &Path,
+    ws: &mut ProtoWorkspace,
+    verbosity: Verbosity,
+) -> Result<Option<cache::ProtoFingerprint>, String> {
+    let fingerprint = cache::proto_fingerprint(ws)?;
+    if !cache::descriptors_fresh(path, fingerprint) {
+        return Ok(Some(fingerprint));
     }
+    if verbosity.is_verbose() {
+        println!("Protos unchanged since {path:?} was written, generating from it without protoc");
+    }
+    ws.image = Some(path.to_path_buf());
+    Ok(None)
+}
 
-    fn create_simple_test_cfg(tmp_dir: Option<PathBuf>) -> SimpleTestCfg {
-        let project_base = tempfile::tempdir().unwrap();
-        let src = project_base.path().join("src");
-        let proto_files_dir = project_base.path().join("proto");
-        let my_proto = proto_files_dir.join("my-proto.proto");
-        let ex_proto_content = r#"syntax = "proto3";
+/// Writes the files `generate` produces next to the generated code, once it's in place
+fn write_companion_files(
+    manifest: Option<&Path>,
+    source_map: Option<&Path>,
+    include_file: Option<&Path>,
+    json_schema: Option<&Path>,
+    ws: &ProtoWorkspace,
+    report: &GenerationReport,
+    gen_opts: &GenOptions,
+) -> Result<(), String> {
+    if let Some(manifest) = manifest {
+        manifest::write(manifest, ws)?;
+    }
+    if let Some(source_map) = source_map {
+        source_map::write(
+            source_map,
+            ws,
+            &report.file_descriptor_set,
+            gen_opts.wrap_module.as_deref(),
+            gen_opts.flatten_packages,
+        )?;
+    }
+    if let Some(include_file) = include_file {
+        include_file::write(include_file, ws)?;
+    }
+    if let Some(json_schema) = json_schema {
+        json_schema::write(
+            json_schema,
+            &report.file_descriptor_set,
+            gen_opts.serde_rename_all.as_deref(),
+        )?;
+    }
+    Ok(())
+}
+
+fn canonicalize_inputs(
+    proto_dirs: &[PathBuf],
+    proto_files: &[PathBuf],
+) -> Result<(Vec<PathBuf>, Vec<PathBuf>), String> {
+    Ok((
+        canonicalize_all(proto_dirs)?,
+        canonicalize_all(proto_files)?,
+    ))
+}
+
+/// Runs generation for the options that print the generated code or write it somewhere
+/// other than the output dir, which aren't diffed or cached
+fn run_undiffed(
+    ws: &mut ProtoWorkspace,
+    includes: Option<&Path>,
+    bldr: Builder,
+    config: prost_build::Config,
+    gen_opts: &GenOptions,
+) -> Result<(), String> {
+    if gen_opts.build_script && ws.mod_file.is_some() {
+        return Err(
+            "--mod-file can't be used with --build-script, which writes the module file as mod.rs in the output dir"
+                .to_string(),
+        );
+    }
+    if gen_opts.top_module {
+        let content =
+            run_generation_with(ws, includes, bldr, config, gen_opts, gen::top_level_module)?;
+        print!("{content}");
+    } else {
+        run_generation_with(ws, includes, bldr, config, gen_opts, gen::run_generation)?;
+    }
+    Ok(())
+}
+
+/// Runs generation with `includes` as an extra proto dir, it's left out of the workspace
+/// otherwise since its temporary path would end up in the cache fingerprint and depfile
+fn run_generation_with<T>(
+    ws: &mut ProtoWorkspace,
+    includes: Option<&Path>,
+    bldr: Builder,
+    config: prost_build::Config,
+    gen_opts: &GenOptions,
+    generate: fn(&ProtoWorkspace, Builder, prost_build::Config, &GenOptions) -> Result<T, String>,
+) -> Result<T, String> {
+    if let Some(includes) = includes {
+        ws.proto_dirs.push(includes.to_path_buf());
+    }
+    let result = generate(ws, bldr, config, gen_opts);
+    if includes.is_some() {
+        ws.proto_dirs.pop();
+    }
+    result
+}
+
+/// The given proto dirs followed by the parent dirs of the proto files, without duplicates,
+/// protoc needs every file to be generated to be inside an include dir.
+fn include_dirs(proto_dirs: Vec<PathBuf>, proto_files: &[PathBuf]) -> Vec<PathBuf> {
+    let parents = proto_files.iter().filter_map(|file| {
+        file.parent().map(|parent| {
+            if parent.as_os_str().is_empty() {
+                PathBuf::from(".")
+            } else {
+                parent.to_path_buf()
+            }
+        })
+    });
+    let mut dirs: Vec<PathBuf> = Vec::with_capacity(proto_dirs.len());
+    for dir in proto_dirs.into_iter().chain(parents) {
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+    dirs
+}
+
+/// Reads newline separated proto paths, skipping blank lines and `#` comments
+fn read_proto_files(list: &Path) -> Result<Vec<PathBuf>, String> {
+    let content = std::fs::read_to_string(list)
+        .map_err(|e| format!("Failed to read proto files list {list:?} \n{e}"))?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect())
+}
+
+fn canonicalize_all(paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    paths
+        .iter()
+        .map(|path| {
+            path.canonicalize()
+                .map_err(|e| format!("Failed to canonicalize {path:?} \n{e}"))
+        })
+        .collect()
+}
+
+/// Makes an output dir absolute without requiring it to exist, so that the module file
+/// can be placed in its parent even for output dirs such as `generated` or `.`.
+fn resolve_output_dir(cwd: &Path, output_dir: &Path) -> PathBuf {
+    let mut resolved = PathBuf::new();
+    for component in cwd.join(output_dir).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            other => resolved.push(other),
+        }
+    }
+    resolved
+}
+
+#[cfg(all(test, feature = "protoc-tests"))]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    struct SimpleTestCfg {
+        _keep_alive_project_base: TempDir,
+        tonic: TonicOpts,
+        workspace: WorkspaceOpts,
+    }
+
+    fn create_simple_test_cfg(tmp_dir: Option<PathBuf>) -> SimpleTestCfg {
+        let project_base = tempfile::tempdir().unwrap();
+        let src = project_base.path().join("src");
+        let proto_files_dir = project_base.path().join("proto");
+        let my_proto = proto_files_dir.join("my-proto.proto");
+        let ex_proto_content = r#"syntax = "proto3";
 
 package my_proto;
 
@@ -272,22 +1293,13 @@ message TestMessage {
         std::fs::create_dir_all(&proto_files_dir).unwrap();
         std::fs::write(&my_proto, ex_proto_content).unwrap();
         let proto_types_dir = src.join("proto_types");
-        let tonic = TonicOpts {
-            build_server: false,
-            build_client: false,
-            generate_transport: false,
-            disable_comments: vec![],
-            type_attributes: vec![],
-            enum_attributes: vec![],
-            btree_maps: vec![],
-            client_attributes: vec![],
-            server_attributes: vec![],
-        };
+        let tonic = TonicOpts::default();
         let workspace = WorkspaceOpts {
             proto_dirs: vec![proto_files_dir],
             proto_files: vec![my_proto],
             tmp_dir,
             output_dir: proto_types_dir,
+            ..WorkspaceOpts::default()
         };
         SimpleTestCfg {
             _keep_alive_project_base: project_base,
@@ -296,43 +1308,81 @@ message TestMessage {
         }
     }
 
+    fn test_opts(tonic: TonicOpts, format: bool, routine: Routine) -> Opts {
+        Opts {
+            routine,
+            tonic,
+            format,
+            ignore_format_diff: false,
+            format_check_pinned: false,
+            prepend_header: true,
+            prepend_header_file: None,
+            module_header_file: None,
+            conversions: None,
+            toplevel_attribute: None,
+            no_clippy_allows: false,
+            inline_children: false,
+            flatten_packages: false,
+            tmp_base: None,
+            no_partial_eq_allow: false,
+            allow_missing_docs: false,
+            generated_marker: false,
+            check_compiles: false,
+            fail_on_warnings: false,
+            buf_lint: false,
+            verbose: false,
+            stdout: false,
+            build_script: false,
+            file_mode: 0o644,
+            porcelain: false,
+            max_diff_print: None,
+            color: ColorChoice::Never,
+            force: false,
+            clean_tmp: false,
+            drift_exit_code: 1,
+            quiet: false,
+            ignore: vec![],
+            only: vec![],
+            exclude_files: vec![],
+            module_docs: false,
+            keep_empty_modules: false,
+            split_services: false,
+            generate_mocks: false,
+            module_visibility: ModuleVisibility::Pub,
+            sort_modules: ModuleSort::CaseSensitive,
+            wrap_module: None,
+        }
+    }
+
     #[test]
     fn full_generate_single_file_project() {
         let test_cfg = create_simple_test_cfg(None);
-        let opts = Opts {
-            tonic: test_cfg.tonic.clone(),
-            format: true,
-            routine: Routine::Generate {
+        let opts = test_opts(
+            test_cfg.tonic.clone(),
+            true,
+            Routine::Generate {
                 workspace: test_cfg.workspace.clone(),
+                watch: false,
             },
-            prepend_header: true,
-            prepend_header_file: None,
-            toplevel_attribute: None,
-        };
+        );
         // Generate
         run_with_opts(opts).unwrap();
-        let opts = Opts {
-            tonic: test_cfg.tonic.clone(),
-            format: true,
-            routine: Routine::Validate {
+        let opts = test_opts(
+            test_cfg.tonic.clone(),
+            true,
+            Routine::Validate {
                 workspace: test_cfg.workspace.clone(),
             },
-            prepend_header: true,
-            prepend_header_file: None,
-            toplevel_attribute: None,
-        };
+        );
         // Validate it's the same after generation
         run_with_opts(opts).unwrap();
-        let opts = Opts {
-            tonic: test_cfg.tonic.clone(),
-            format: false,
-            routine: Routine::Validate {
+        let opts = test_opts(
+            test_cfg.tonic.clone(),
+            false,
+            Routine::Validate {
                 workspace: test_cfg.workspace,
             },
-            prepend_header: true,
-            prepend_header_file: None,
-            toplevel_attribute: None,
-        };
+        );
         // Validate it's not the same if specifying no fmt
         match run_with_opts(opts) {
             Ok(()) => panic!("Expected fail on diff"),
@@ -346,21 +1396,73 @@ message TestMessage {
     fn full_generate_single_file_project_does_not_remove_explicit_temp() {
         let my_output_tmp = tempfile::tempdir().unwrap();
         let test_cfg = create_simple_test_cfg(Some(my_output_tmp.path().to_path_buf()));
-        let opts = Opts {
-            tonic: test_cfg.tonic.clone(),
-            format: false,
-            routine: Routine::Generate {
+        let opts = test_opts(
+            test_cfg.tonic.clone(),
+            false,
+            Routine::Generate {
                 workspace: test_cfg.workspace,
+                watch: false,
             },
-            prepend_header: true,
-            prepend_header_file: None,
-            toplevel_attribute: None,
-        };
+        );
         // Generate
         run_with_opts(opts).unwrap();
         assert_exists_not_empty(&my_output_tmp.path().join("my_proto.rs"));
     }
 
+    #[test]
+    fn temp_dirs_created_in_tmp_base() {
+        let test_cfg = create_simple_test_cfg(None);
+        let tmp_base = tempfile::tempdir().unwrap();
+        let generate = |tmp_base: PathBuf| {
+            run_with_opts(Opts {
+                tmp_base: Some(tmp_base),
+                ..test_opts(
+                    test_cfg.tonic.clone(),
+                    false,
+                    Routine::Generate {
+                        workspace: test_cfg.workspace.clone(),
+                        watch: false,
+                    },
+                )
+            })
+        };
+        generate(tmp_base.path().join("missing")).unwrap_err();
+        generate(tmp_base.path().to_path_buf()).unwrap();
+        assert_exists_not_empty(&test_cfg.workspace.output_dir.join("my_proto.rs"));
+        // Removed again once generation is done
+        assert_eq!(0, std::fs::read_dir(tmp_base.path()).unwrap().count());
+    }
+
+    #[test]
+    fn reused_temp_emptied_with_clean_tmp() {
+        let my_output_tmp = tempfile::tempdir().unwrap();
+        let stale = my_output_tmp.path().join("stale.rs");
+        std::fs::write(&stale, "pub struct Stale;\n").unwrap();
+        let test_cfg = create_simple_test_cfg(Some(my_output_tmp.path().to_path_buf()));
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        let generate = |clean_tmp| {
+            run_with_opts(Opts {
+                clean_tmp,
+                ..test_opts(
+                    test_cfg.tonic.clone(),
+                    false,
+                    Routine::Generate {
+                        workspace: test_cfg.workspace.clone(),
+                        watch: false,
+                    },
+                )
+            })
+        };
+        generate(true).unwrap();
+        assert!(!stale.exists());
+        assert!(!proto_types_dir.join("stale.rs").exists());
+        assert_exists_not_empty(&proto_types_dir.join("my_proto.rs"));
+        // Not deleted without asking for it, the tmp dir could be anything
+        std::fs::write(&stale, "pub struct Stale;\n").unwrap();
+        generate(false).unwrap();
+        assert!(stale.exists());
+    }
+
     #[test]
     fn full_generate_nested_project() {
         let project_base = tempfile::tempdir().unwrap();
@@ -373,11 +1475,15 @@ package my_proto;
 
 import "imports/dependency.proto";
 import "imports/nested/nested_one.proto";
+import "imports/keyword/keyword.proto";
+import "imports/mixed/mixed_case.proto";
 
 message MyNestedMessage {
   int32 some_field = 1;
   imports.dependency.Dependency dependency = 2;
   imports.nested.NestedOne nested_one = 3;
+  imports.type.Keyword keyword = 4;
+  imports.MixedCase.Mixed mixed = 5;
 }
 
 // My comment
@@ -424,37 +1530,2757 @@ message NestedTransitiveMsg {
             nested_trns,
         )
         .unwrap();
+        write_edge_case_packages(&dep_dir);
         let proto_types_dir = src.join("proto_types");
-        let tonic = TonicOpts {
-            build_server: false,
-            build_client: false,
-            generate_transport: false,
-            disable_comments: vec![],
-            type_attributes: vec![],
-            enum_attributes: vec![],
-            btree_maps: vec![],
-            client_attributes: vec![],
-            server_attributes: vec![],
-        };
+        let tonic = TonicOpts::default();
         let workspace = WorkspaceOpts {
             proto_dirs: vec![proto_files_dir, dep_dir, nested_dep_proto_dir],
             proto_files: vec![my_proto],
             tmp_dir: None,
             output_dir: proto_types_dir.clone(),
+            ..WorkspaceOpts::default()
         };
-        let opts = Opts {
+        let opts = test_opts(
             tonic,
-            format: false,
-            routine: Routine::Generate { workspace },
-            prepend_header: true,
-            prepend_header_file: None,
-            toplevel_attribute: None,
-        };
+            false,
+            Routine::Generate {
+                workspace,
+                watch: false,
+            },
+        );
         run_with_opts(opts).unwrap();
         assert_exists_not_empty(&proto_types_dir.join("my_proto.rs"));
         assert_exists_not_empty(&proto_types_dir.join("imports.rs"));
         assert_exists_not_empty(&proto_types_dir.join("imports").join("dependency.rs"));
         assert_exists_not_empty(&proto_types_dir.join("imports").join("nested.rs"));
+        let imports_mod = std::fs::read_to_string(proto_types_dir.join("imports.rs")).unwrap();
+        assert!(imports_mod.contains("pub mod r#type;\n"), "{imports_mod}");
+        assert!(
+            imports_mod.contains("pub mod mixed_case;\n"),
+            "{imports_mod}"
+        );
+        let type_mod =
+            std::fs::read_to_string(proto_types_dir.join("imports").join("type.rs")).unwrap();
+        assert!(type_mod.contains("pub struct Keyword {"), "{type_mod}");
+        let mixed_mod =
+            std::fs::read_to_string(proto_types_dir.join("imports").join("mixed_case.rs")).unwrap();
+        assert!(mixed_mod.contains("pub struct Mixed {"), "{mixed_mod}");
+        assert!(!proto_types_dir.join("imports").join("r#type.rs").exists());
+        assert!(!proto_types_dir
+            .join("imports")
+            .join("MixedCase.rs")
+            .exists());
+    }
+
+    /// Protos in `dir` with a package segment that's a Rust keyword and one in mixed case
+    fn write_edge_case_packages(dir: &Path) {
+        let keyword_dir = dir.join("keyword");
+        std::fs::create_dir_all(&keyword_dir).unwrap();
+        let keyword = r#"syntax = "proto3";
+package imports.type;
+
+message Keyword {
+  int32 my_keyword_field = 1;
+}
+"#;
+        std::fs::write(keyword_dir.join("keyword.proto"), keyword).unwrap();
+        let mixed_dir = dir.join("mixed");
+        std::fs::create_dir_all(&mixed_dir).unwrap();
+        let mixed = r#"syntax = "proto3";
+package imports.MixedCase;
+
+message Mixed {
+  int32 my_mixed_field = 1;
+}
+"#;
+        std::fs::write(mixed_dir.join("mixed_case.proto"), mixed).unwrap();
+    }
+
+    #[test]
+    fn excluded_protos_not_generated() {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        let vendor_dir = proto_files_dir.join("vendor");
+        std::fs::create_dir_all(&vendor_dir).unwrap();
+        std::fs::write(
+            vendor_dir.join("unused.proto"),
+            "syntax = \"proto3\";\n\npackage vendor.unused;\n\nmessage Unused {\n  int32 field = 1;\n}\n",
+        )
+        .unwrap();
+        std::fs::write(
+            vendor_dir.join("used.proto"),
+            "syntax = \"proto3\";\n\npackage vendor.used;\n\nmessage Used {\n  int32 field = 1;\n}\n",
+        )
+        .unwrap();
+        let my_proto = proto_files_dir.join("my.proto");
+        std::fs::write(
+            &my_proto,
+            r#"syntax = "proto3";
+
+package my_proto;
+
+import "vendor/unused.proto";
+import "vendor/used.proto";
+
+message Mine {
+  vendor.used.Used used = 1;
+}
+"#,
+        )
+        .unwrap();
+        let proto_types_dir = project_base.path().join("src").join("proto_types");
+        let workspace = WorkspaceOpts {
+            proto_dirs: vec![proto_files_dir],
+            proto_files: vec![my_proto],
+            output_dir: proto_types_dir.clone(),
+            ..WorkspaceOpts::default()
+        };
+        let opts = |exclude: &str| Opts {
+            exclude_files: vec![exclude.to_string()],
+            ..test_opts(
+                TonicOpts::default(),
+                false,
+                Routine::Generate {
+                    workspace: workspace.clone(),
+                    watch: false,
+                },
+            )
+        };
+        run_with_opts(opts("vendor/unused.proto")).unwrap();
+        assert_exists_not_empty(&proto_types_dir.join("my_proto.rs"));
+        assert_exists_not_empty(&proto_types_dir.join("vendor").join("used.rs"));
+        assert!(!proto_types_dir.join("vendor").join("unused.rs").exists());
+        let vendor_mod = std::fs::read_to_string(proto_types_dir.join("vendor.rs")).unwrap();
+        assert!(!vendor_mod.contains("pub mod unused;"), "{vendor_mod}");
+        // The generated code for `my_proto` wouldn't compile without `vendor.used`
+        assert!(run_with_opts(opts("vendor/*")).is_err());
+    }
+
+    #[test]
+    fn porcelain_keeps_drift_exit_code() {
+        let test_cfg = create_simple_test_cfg(None);
+        let opts = |routine| Opts {
+            porcelain: true,
+            drift_exit_code: 3,
+            ..test_opts(test_cfg.tonic.clone(), false, routine)
+        };
+        let validate = || {
+            run_with_opts(opts(Routine::Validate {
+                workspace: test_cfg.workspace.clone(),
+            }))
+        };
+        assert_eq!(Err(3), validate());
+        run_with_opts(opts(Routine::Generate {
+            workspace: test_cfg.workspace.clone(),
+            watch: false,
+        }))
+        .unwrap();
+        validate().unwrap();
+    }
+
+    #[test]
+    fn proto_files_read_from_list() {
+        let mut simple = create_simple_test_cfg(None);
+        let my_proto = simple.workspace.proto_files.remove(0);
+        let list = my_proto.with_file_name("protos.txt");
+        std::fs::write(
+            &list,
+            format!("# Generated list\n\n{}\n", my_proto.display()),
+        )
+        .unwrap();
+        simple.workspace.proto_files_from = Some(list);
+        let proto_types_dir = simple.workspace.output_dir.clone();
+        let opts = test_opts(
+            simple.tonic,
+            false,
+            Routine::Generate {
+                workspace: simple.workspace,
+                watch: false,
+            },
+        );
+        run_with_opts(opts).unwrap();
+        assert_exists_not_empty(&proto_types_dir.join("my_proto.rs"));
+    }
+
+    #[test]
+    fn nested_module_files_are_formatted() {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        std::fs::create_dir_all(&proto_files_dir).unwrap();
+        let mut proto_files = vec![];
+        for package in ["dependency", "nested"] {
+            let proto = proto_files_dir.join(format!("{package}.proto"));
+            std::fs::write(
+                &proto,
+                format!("syntax = \"proto3\";\n\n// The {package} package\npackage imports.{package};\n\nmessage Msg {{\n  int32 field = 1;\n}}\n"),
+            )
+            .unwrap();
+            proto_files.push(proto);
+        }
+        let proto_types_dir = project_base.path().join("src").join("proto_types");
+        let opts = Opts {
+            module_docs: true,
+            ..test_opts(
+                TonicOpts::default(),
+                true,
+                Routine::Generate {
+                    workspace: WorkspaceOpts {
+                        proto_dirs: vec![proto_files_dir],
+                        proto_files,
+                        output_dir: proto_types_dir.clone(),
+                        ..WorkspaceOpts::default()
+                    },
+                    watch: false,
+                },
+            )
+        };
+        run_with_opts(opts).unwrap();
+        let imports = proto_types_dir.join("imports.rs");
+        assert!(std::fs::read_to_string(&imports)
+            .unwrap()
+            .contains("pub mod dependency;"));
+        let check = std::process::Command::new("rustfmt")
+            .arg("--check")
+            .arg("--edition")
+            .arg("2021")
+            .arg(&imports)
+            .output()
+            .unwrap();
+        assert!(
+            check.status.success(),
+            "{}",
+            String::from_utf8_lossy(&check.stdout)
+        );
+    }
+
+    #[test]
+    fn package_output_places_package_in_own_dir() {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        std::fs::create_dir_all(&proto_files_dir).unwrap();
+        let alpha_proto = proto_files_dir.join("alpha.proto");
+        std::fs::write(
+            &alpha_proto,
+            "syntax = \"proto3\";\n\npackage alpha;\n\nmessage Alpha {\n  int32 field = 1;\n}\n",
+        )
+        .unwrap();
+        let beta_proto = proto_files_dir.join("beta.proto");
+        std::fs::write(
+            &beta_proto,
+            "syntax = \"proto3\";\n\npackage beta.inner;\n\nmessage Beta {\n  int32 field = 1;\n}\n",
+        )
+        .unwrap();
+        let src = project_base.path().join("src");
+        let proto_types_dir = src.join("proto_types");
+        let beta_types_dir = src.join("beta_types");
+        let workspace = WorkspaceOpts {
+            proto_dirs: vec![proto_files_dir],
+            proto_files: vec![alpha_proto, beta_proto],
+            output_dir: proto_types_dir.clone(),
+            package_outputs: vec![(
+                "beta".to_string(),
+                beta_types_dir.to_str().unwrap().to_string(),
+            )],
+            ..WorkspaceOpts::default()
+        };
+        let opts = test_opts(
+            TonicOpts::default(),
+            false,
+            Routine::Generate {
+                workspace: workspace.clone(),
+                watch: false,
+            },
+        );
+        run_with_opts(opts).unwrap();
+        assert_exists_not_empty(&proto_types_dir.join("alpha.rs"));
+        assert!(!proto_types_dir.join("beta.rs").exists());
+        assert_exists_not_empty(&beta_types_dir.join("beta").join("inner.rs"));
+        let top_mod = std::fs::read_to_string(src.join("proto_types.rs")).unwrap();
+        assert!(top_mod.contains("pub mod alpha;"));
+        assert!(!top_mod.contains("pub mod beta;"));
+        let beta_mod = std::fs::read_to_string(src.join("beta_types.rs")).unwrap();
+        assert!(beta_mod.contains("pub mod beta;"));
+        assert!(!beta_mod.contains("pub mod alpha;"));
+        let opts = test_opts(TonicOpts::default(), false, Routine::Validate { workspace });
+        // Each output dir is diffed on its own
+        run_with_opts(opts).unwrap();
+    }
+
+    #[test]
+    fn depfile_lists_outputs_and_transitive_imports() {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        std::fs::create_dir_all(proto_files_dir.join("imports")).unwrap();
+        let my_proto = proto_files_dir.join("my.proto");
+        std::fs::write(
+            &my_proto,
+            "syntax = \"proto3\";\n\npackage my;\n\nimport \"imports/dep.proto\";\n\nmessage My {\n  imports.Dep dep = 1;\n}\n",
+        )
+        .unwrap();
+        let dep_proto = proto_files_dir.join("imports").join("dep.proto");
+        std::fs::write(
+            &dep_proto,
+            "syntax = \"proto3\";\n\npackage imports;\n\nmessage Dep {\n  int32 field = 1;\n}\n",
+        )
+        .unwrap();
+        let proto_types_dir = project_base.path().join("src").join("proto_types");
+        let depfile = project_base.path().join("proto_types.d");
+        let opts = test_opts(
+            TonicOpts::default(),
+            false,
+            Routine::Generate {
+                workspace: WorkspaceOpts {
+                    proto_dirs: vec![proto_files_dir],
+                    proto_files: vec![my_proto.clone()],
+                    output_dir: proto_types_dir.clone(),
+                    depfile: Some(depfile.clone()),
+                    ..WorkspaceOpts::default()
+                },
+                watch: false,
+            },
+        );
+        run_with_opts(opts).unwrap();
+        let content = std::fs::read_to_string(depfile).unwrap();
+        let (outputs, protos) = content.split_once(": ").unwrap();
+        assert!(outputs.contains(proto_types_dir.join("my.rs").to_str().unwrap()));
+        assert!(outputs.contains(proto_types_dir.join("imports.rs").to_str().unwrap()));
+        assert!(outputs.contains(proto_types_dir.with_extension("rs").to_str().unwrap()));
+        assert!(protos.contains(my_proto.to_str().unwrap()));
+        assert!(protos.contains(dep_proto.to_str().unwrap()));
+    }
+
+    #[test]
+    fn source_map_points_at_protos() {
+        let test_cfg = create_simple_test_cfg(None);
+        let source_map = test_cfg
+            .workspace
+            .output_dir
+            .with_file_name("proto_types.sources.json");
+        run_with_opts(test_opts(
+            test_cfg.tonic.clone(),
+            false,
+            Routine::Generate {
+                workspace: WorkspaceOpts {
+                    source_map: Some(source_map.clone()),
+                    ..test_cfg.workspace.clone()
+                },
+                watch: false,
+            },
+        ))
+        .unwrap();
+        assert_eq!(
+            "{\n  \"proto_types/my_proto.rs\": [\n    \"my-proto.proto\"\n  ]\n}\n",
+            std::fs::read_to_string(source_map).unwrap()
+        );
+    }
+
+    #[test]
+    fn inline_children_in_parent_file() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        let proto_dir = test_cfg.workspace.proto_dirs[0].clone();
+        let protos = [
+            ("outer.proto", "package outer;\n\nmessage Outer {}\n"),
+            (
+                "outer-inner.proto",
+                "package outer.inner;\n\nimport \"outer.proto\";\n\nmessage Inner {\n  outer.Outer outer = 1;\n}\n",
+            ),
+            ("shared-a.proto", "package shared.a;\n\nmessage A {}\n"),
+        ];
+        let mut workspace = test_cfg.workspace.clone();
+        for (name, content) in protos {
+            let path = proto_dir.join(name);
+            std::fs::write(&path, format!("syntax = \"proto3\";\n\n{content}")).unwrap();
+            workspace.proto_files.push(path);
+        }
+        let run = |routine: Routine| {
+            run_with_opts(Opts {
+                inline_children: true,
+                ..test_opts(test_cfg.tonic.clone(), true, routine)
+            })
+        };
+        run(Routine::Generate {
+            workspace: workspace.clone(),
+            watch: false,
+        })
+        .unwrap();
+        let outer = std::fs::read_to_string(proto_types_dir.join("outer.rs")).unwrap();
+        assert!(outer.contains("pub mod inner {\n"), "{outer}");
+        assert!(
+            outer.contains("pub outer: ::core::option::Option<super::Outer>"),
+            "{outer}"
+        );
+        assert!(outer.contains("pub struct Outer {}"), "{outer}");
+        let shared = std::fs::read_to_string(proto_types_dir.join("shared.rs")).unwrap();
+        assert!(shared.contains("pub mod a {\n"), "{shared}");
+        assert!(!proto_types_dir.join("outer").exists());
+        assert!(!proto_types_dir.join("shared").exists());
+        assert_exists_not_empty(&proto_types_dir.join("my_proto.rs"));
+        run(Routine::Validate { workspace }).unwrap();
+    }
+
+    #[test]
+    fn flattened_packages_are_top_level_modules() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        let proto_dir = test_cfg.workspace.proto_dirs[0].clone();
+        let protos = [
+            ("c.proto", "// Docs of c\npackage a.b.c;\n\nmessage C {}\n"),
+            (
+                "d.proto",
+                "package a.b.d;\n\nimport \"c.proto\";\n\nmessage D {\n  a.b.c.C c = 1;\n}\n",
+            ),
+        ];
+        let mut workspace = test_cfg.workspace.clone();
+        for (name, content) in protos {
+            let path = proto_dir.join(name);
+            std::fs::write(&path, format!("syntax = \"proto3\";\n\n{content}")).unwrap();
+            workspace.proto_files.push(path);
+        }
+        let run = |workspace: &WorkspaceOpts| {
+            run_with_opts(Opts {
+                flatten_packages: true,
+                module_docs: true,
+                ..test_opts(
+                    test_cfg.tonic.clone(),
+                    true,
+                    Routine::Generate {
+                        workspace: workspace.clone(),
+                        watch: false,
+                    },
+                )
+            })
+        };
+        run(&workspace).unwrap();
+        let top_mod = std::fs::read_to_string(proto_types_dir.with_extension("rs")).unwrap();
+        assert!(
+            top_mod.ends_with("pub mod c;\npub mod d;\npub mod my_proto;\n"),
+            "{top_mod}"
+        );
+        let c = std::fs::read_to_string(proto_types_dir.join("c.rs")).unwrap();
+        assert!(c.contains("//! Docs of c\n"), "{c}");
+        assert!(c.contains("pub struct C {}"), "{c}");
+        let d = std::fs::read_to_string(proto_types_dir.join("d.rs")).unwrap();
+        assert!(d.contains("::core::option::Option<super::c::C>"), "{d}");
+        assert!(!proto_types_dir.join("a").exists());
+        // Packages ending in the same segment would be the same module
+        let other = proto_dir.join("other.proto");
+        std::fs::write(
+            &other,
+            "syntax = \"proto3\";\n\npackage x.c;\n\nmessage X {}\n",
+        )
+        .unwrap();
+        workspace.proto_files.push(other);
+        assert!(run(&workspace).is_err());
+    }
+
+    #[test]
+    fn manifest_lists_generated_files() {
+        let test_cfg = create_simple_test_cfg(None);
+        let manifest = test_cfg
+            .workspace
+            .output_dir
+            .parent()
+            .unwrap()
+            .join("proto_types.files");
+        let workspace = WorkspaceOpts {
+            manifest: Some(manifest.clone()),
+            ..test_cfg.workspace.clone()
+        };
+        run_with_opts(test_opts(
+            test_cfg.tonic.clone(),
+            false,
+            Routine::Validate {
+                workspace: workspace.clone(),
+            },
+        ))
+        .unwrap_err();
+        assert!(!manifest.exists());
+        run_with_opts(test_opts(
+            test_cfg.tonic.clone(),
+            false,
+            Routine::Generate {
+                workspace,
+                watch: false,
+            },
+        ))
+        .unwrap();
+        assert_eq!(
+            "proto_types.rs\nproto_types/my_proto.rs\n",
+            std::fs::read_to_string(manifest).unwrap()
+        );
+    }
+
+    #[test]
+    fn include_file_points_at_output_dir() {
+        let test_cfg = create_simple_test_cfg(None);
+        let project_base = test_cfg
+            .workspace
+            .output_dir
+            .parent()
+            .unwrap()
+            .parent()
+            .unwrap();
+        let include_dir = project_base.join("lib");
+        std::fs::create_dir_all(&include_dir).unwrap();
+        let include_file = include_dir.join("generated.rs");
+        run_with_opts(test_opts(
+            test_cfg.tonic.clone(),
+            false,
+            Routine::Generate {
+                workspace: WorkspaceOpts {
+                    include_file: Some(include_file.clone()),
+                    ..test_cfg.workspace.clone()
+                },
+                watch: false,
+            },
+        ))
+        .unwrap();
+        let content = std::fs::read_to_string(include_file).unwrap();
+        assert!(
+            content.starts_with("#[path = \"../src/proto_types\"]\nmod proto_types {\n"),
+            "{content}"
+        );
+        assert!(content.contains("    pub mod my_proto;\n"), "{content}");
+        assert!(
+            content.ends_with("}\npub use self::proto_types::*;\n"),
+            "{content}"
+        );
+    }
+
+    #[test]
+    fn only_splices_into_existing_output() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        let proto_dir = test_cfg.workspace.proto_dirs[0].clone();
+        let write_proto = |name: &str, package: &str, fields: &str| {
+            let path = proto_dir.join(name);
+            std::fs::write(
+                &path,
+                format!(
+                    "syntax = \"proto3\";\n\npackage {package};\n\nmessage Msg {{\n{fields}}}\n"
+                ),
+            )
+            .unwrap();
+            path
+        };
+        let alpha = write_proto("alpha.proto", "alpha", "  int32 a = 1;\n");
+        let beta = write_proto("beta.proto", "beta", "  int32 b = 1;\n");
+        let mut workspace = WorkspaceOpts {
+            proto_files: vec![alpha, beta],
+            ..test_cfg.workspace.clone()
+        };
+        let run = |routine: Routine, only: &[&str]| {
+            run_with_opts(Opts {
+                only: only.iter().map(ToString::to_string).collect(),
+                ..test_opts(test_cfg.tonic.clone(), false, routine)
+            })
+        };
+        let generate = |workspace: &WorkspaceOpts, only: &[&str]| {
+            run(
+                Routine::Generate {
+                    workspace: workspace.clone(),
+                    watch: false,
+                },
+                only,
+            )
+            .unwrap();
+        };
+        generate(&workspace, &[]);
+        let alpha_rs = std::fs::read_to_string(proto_types_dir.join("alpha.rs")).unwrap();
+        write_proto("alpha.proto", "alpha", "  int32 a = 1;\n  int32 c = 2;\n");
+        write_proto("beta.proto", "beta", "  int32 b = 1;\n  int32 c = 2;\n");
+        generate(&workspace, &["beta.proto"]);
+        assert_eq!(
+            alpha_rs,
+            std::fs::read_to_string(proto_types_dir.join("alpha.rs")).unwrap()
+        );
+        let beta_rs = std::fs::read_to_string(proto_types_dir.join("beta.rs")).unwrap();
+        assert!(beta_rs.contains("pub c: i32"), "{beta_rs}");
+        // A new child of a kept module gets declared in it
+        let sub = write_proto("alpha-sub.proto", "alpha.sub", "  int32 s = 1;\n");
+        workspace.proto_files.push(sub);
+        generate(&workspace, &["alpha-sub.proto"]);
+        let spliced = std::fs::read_to_string(proto_types_dir.join("alpha.rs")).unwrap();
+        assert!(spliced.contains("pub mod sub;\n"), "{spliced}");
+        assert!(!spliced.contains("pub c: i32"), "{spliced}");
+        assert_exists_not_empty(&proto_types_dir.join("alpha").join("sub.rs"));
+        run(
+            Routine::Validate {
+                workspace: workspace.clone(),
+            },
+            &["alpha-sub.proto"],
+        )
+        .unwrap();
+        // Spliced output is the same as generating everything
+        write_proto("alpha.proto", "alpha", "  int32 a = 1;\n");
+        run(Routine::Validate { workspace }, &[]).unwrap();
+    }
+
+    #[test]
+    fn only_regenerates_whole_packages() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        let proto_dir = test_cfg.workspace.proto_dirs[0].clone();
+        let write_proto = |name: &str, message: &str, fields: &str| {
+            let path = proto_dir.join(name);
+            std::fs::write(
+                &path,
+                format!(
+                    "syntax = \"proto3\";\n\npackage foo;\n\nmessage {message} {{\n{fields}}}\n"
+                ),
+            )
+            .unwrap();
+            path
+        };
+        let a = write_proto("a.proto", "A", "  int32 a = 1;\n");
+        let b = write_proto("b.proto", "B", "  int32 b = 1;\n");
+        let workspace = WorkspaceOpts {
+            proto_files: vec![a, b],
+            ..test_cfg.workspace.clone()
+        };
+        let generate = |only: &[&str]| {
+            run_with_opts(Opts {
+                only: only.iter().map(ToString::to_string).collect(),
+                ..test_opts(
+                    test_cfg.tonic.clone(),
+                    false,
+                    Routine::Generate {
+                        workspace: workspace.clone(),
+                        watch: false,
+                    },
+                )
+            })
+            .unwrap();
+        };
+        generate(&[]);
+        write_proto("a.proto", "A", "  int32 a = 1;\n  int32 c = 2;\n");
+        generate(&["a.proto"]);
+        let foo = std::fs::read_to_string(proto_types_dir.join("foo.rs")).unwrap();
+        assert!(foo.contains("pub struct A {"), "{foo}");
+        assert!(foo.contains("pub c: i32"), "{foo}");
+        // The unselected file of the package is still generated
+        assert!(foo.contains("pub struct B {"), "{foo}");
+    }
+
+    #[test]
+    fn cached_descriptors_used_while_protos_unchanged() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        let my_proto = test_cfg.workspace.proto_files[0].clone();
+        let descriptors = my_proto.with_file_name("descriptors.binpb");
+        let generate = || {
+            run_with_opts(test_opts(
+                test_cfg.tonic.clone(),
+                false,
+                Routine::Generate {
+                    workspace: WorkspaceOpts {
+                        descriptor_set_path: Some(descriptors.clone()),
+                        ..test_cfg.workspace.clone()
+                    },
+                    watch: false,
+                },
+            ))
+            .unwrap();
+            std::fs::read_to_string(proto_types_dir.join("my_proto.rs")).unwrap()
+        };
+        assert!(generate().contains("pub struct TestMessage {"));
+        // Renamed in the cached descriptors only, which shows up if protoc is skipped
+        let bytes = std::fs::read(&descriptors).unwrap();
+        let mut fds =
+            <prost_types::FileDescriptorSet as prost::Message>::decode(bytes.as_slice()).unwrap();
+        for message in &mut fds.file[0].message_type {
+            if message.name() == "TestMessage" {
+                message.name = Some("CachedMessage".to_string());
+            }
+        }
+        std::fs::write(&descriptors, prost::Message::encode_to_vec(&fds)).unwrap();
+        assert!(generate().contains("pub struct CachedMessage {"));
+        let mut content = std::fs::read_to_string(&my_proto).unwrap();
+        content.push_str("\n// Changed\n");
+        std::fs::write(&my_proto, content).unwrap();
+        assert!(generate().contains("pub struct TestMessage {"));
+    }
+
+    #[test]
+    fn cached_descriptors_keep_services_not_generated() {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        std::fs::create_dir_all(&proto_files_dir).unwrap();
+        let svc = proto_files_dir.join("svc.proto");
+        std::fs::write(
+            &svc,
+            "syntax = \"proto3\";\n\npackage svc;\n\nmessage Req {\n  int32 field = 1;\n}\n\nservice Greeter {\n  rpc Hello(Req) returns (Req);\n}\n",
+        )
+        .unwrap();
+        let output_dir = project_base.path().join("src").join("proto_types");
+        let descriptors = project_base.path().join("descriptors.binpb");
+        let generate = |tonic: TonicOpts| {
+            run_with_opts(test_opts(
+                tonic,
+                false,
+                Routine::Generate {
+                    workspace: WorkspaceOpts {
+                        proto_dirs: vec![proto_files_dir.clone()],
+                        proto_files: vec![svc.clone()],
+                        output_dir: output_dir.clone(),
+                        descriptor_set_path: Some(descriptors.clone()),
+                        ..WorkspaceOpts::default()
+                    },
+                    watch: false,
+                },
+            ))
+            .unwrap();
+            std::fs::read_to_string(output_dir.join("svc.rs")).unwrap()
+        };
+        assert!(!generate(TonicOpts::default()).contains("greeter_server"));
+        let bytes = std::fs::read(&descriptors).unwrap();
+        let fds =
+            <prost_types::FileDescriptorSet as prost::Message>::decode(bytes.as_slice()).unwrap();
+        assert_eq!(fds.file[0].service.len(), 1);
+        // Generated from the cached descriptors, as the protos are unchanged
+        let with_server = generate(TonicOpts {
+            build_server: true,
+            ..TonicOpts::default()
+        });
+        assert!(
+            with_server.contains("pub mod greeter_server {"),
+            "{with_server}"
+        );
+    }
+
+    #[test]
+    fn openapi_in_output_dir_is_diffed() {
+        let test_cfg = create_simple_test_cfg(None);
+        let openapi = test_cfg.workspace.output_dir.join("openapi.json");
+        let workspace = WorkspaceOpts {
+            openapi: Some(openapi.clone()),
+            ..test_cfg.workspace.clone()
+        };
+        run_with_opts(test_opts(
+            test_cfg.tonic.clone(),
+            false,
+            Routine::Generate {
+                workspace: workspace.clone(),
+                watch: false,
+            },
+        ))
+        .unwrap();
+        let spec = std::fs::read_to_string(&openapi).unwrap();
+        assert!(spec.contains("\"my_proto.TestMessage\": {"), "{spec}");
+        run_with_opts(test_opts(
+            test_cfg.tonic.clone(),
+            false,
+            Routine::Validate {
+                workspace: workspace.clone(),
+            },
+        ))
+        .unwrap();
+        std::fs::write(&openapi, "{}").unwrap();
+        run_with_opts(test_opts(
+            test_cfg.tonic.clone(),
+            false,
+            Routine::Validate { workspace },
+        ))
+        .unwrap_err();
+    }
+
+    #[test]
+    fn openapi_describes_services_not_generated() {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        std::fs::create_dir_all(&proto_files_dir).unwrap();
+        let svc = proto_files_dir.join("svc.proto");
+        std::fs::write(
+            &svc,
+            "syntax = \"proto3\";\n\npackage svc;\n\nmessage Req {\n  int32 field = 1;\n}\n\nservice Greeter {\n  rpc Hello(Req) returns (Req);\n}\n",
+        )
+        .unwrap();
+        let openapi = project_base.path().join("openapi.json");
+        // Neither a client nor a server is built by default
+        run_with_opts(test_opts(
+            TonicOpts::default(),
+            false,
+            Routine::Generate {
+                workspace: WorkspaceOpts {
+                    proto_dirs: vec![proto_files_dir],
+                    proto_files: vec![svc],
+                    output_dir: project_base.path().join("src").join("proto_types"),
+                    openapi: Some(openapi.clone()),
+                    ..WorkspaceOpts::default()
+                },
+                watch: false,
+            },
+        ))
+        .unwrap();
+        let spec = std::fs::read_to_string(&openapi).unwrap();
+        assert!(spec.contains("\"/svc.Greeter/Hello\": {"), "{spec}");
+    }
+
+    #[test]
+    fn json_schema_written_per_top_level_message() {
+        let test_cfg = create_simple_test_cfg(None);
+        let schema_dir = test_cfg
+            .workspace
+            .output_dir
+            .parent()
+            .unwrap()
+            .join("schema");
+        run_with_opts(test_opts(
+            TonicOpts {
+                derive_serde: true,
+                serde_rename_all: Some("camelCase".to_string()),
+                ..TonicOpts::default()
+            },
+            false,
+            Routine::Generate {
+                workspace: WorkspaceOpts {
+                    json_schema: Some(schema_dir.clone()),
+                    ..test_cfg.workspace.clone()
+                },
+                watch: false,
+            },
+        ))
+        .unwrap();
+        let schema =
+            std::fs::read_to_string(schema_dir.join("my_proto.TestMessage.schema.json")).unwrap();
+        assert!(schema.contains(r##""$ref": "#/$defs/my_proto.TestMessage""##));
+        assert!(
+            schema.contains("\"my_proto.MyNestedMessage\": {"),
+            "{schema}"
+        );
+        assert!(schema.contains("\"fieldOne\": {"), "{schema}");
+        assert!(schema_dir
+            .join("my_proto.MyNestedMessage.schema.json")
+            .exists());
+    }
+
+    #[test]
+    fn long_version_includes_generator_versions() {
+        let version = Opts::command().render_long_version();
+        assert!(version.contains("\ntonic-build ^0."), "{version}");
+        assert!(version.contains("\nprost-build ^0."), "{version}");
+    }
+
+    #[test]
+    fn archive_contains_generated_tree() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        let archive = test_cfg.workspace.proto_dirs[0]
+            .parent()
+            .unwrap()
+            .join("proto_types.zip");
+        run_with_opts(test_opts(
+            test_cfg.tonic.clone(),
+            true,
+            Routine::Generate {
+                workspace: WorkspaceOpts {
+                    archive: Some(archive.clone()),
+                    ..test_cfg.workspace.clone()
+                },
+                watch: false,
+            },
+        ))
+        .unwrap();
+        assert!(!proto_types_dir.exists());
+        let mut zip = zip::ZipArchive::new(std::fs::File::open(archive).unwrap()).unwrap();
+        assert_eq!(
+            vec!["proto_types.rs", "proto_types/my_proto.rs"],
+            zip.file_names()
+                .collect::<std::collections::BTreeSet<_>>()
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+        let mut content = String::new();
+        std::io::Read::read_to_string(&mut zip.by_name("proto_types.rs").unwrap(), &mut content)
+            .unwrap();
+        assert!(content.contains("pub mod my_proto;"), "{content}");
+    }
+
+    #[test]
+    fn conversions_generated_for_mapped_messages() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        let mapping = test_cfg.workspace.proto_dirs[0].join("conversions.txt");
+        let run = |mapping_content: &str| {
+            std::fs::write(&mapping, mapping_content).unwrap();
+            run_with_opts(Opts {
+                conversions: Some(mapping.clone()),
+                ..test_opts(
+                    test_cfg.tonic.clone(),
+                    true,
+                    Routine::Generate {
+                        workspace: test_cfg.workspace.clone(),
+                        watch: false,
+                    },
+                )
+            })
+        };
+        // The nested message field can't be converted without a mapping for its type
+        run(".my_proto.TestMessage = crate::domain::Test\n").unwrap_err();
+        assert!(!proto_types_dir.exists());
+        run(".my_proto.TestMessage = crate::domain::Test\nmy_proto.MyNestedMessage = crate::domain::Nested\n")
+            .unwrap();
+        let content = std::fs::read_to_string(proto_types_dir.join("my_proto.rs")).unwrap();
+        assert!(content.contains("pub mod conversions {"), "{content}");
+        assert!(
+            content.contains(
+                "impl ::core::convert::From<super::TestMessage> for crate::domain::Test {"
+            ),
+            "{content}"
+        );
+        assert!(content.contains(
+            "my_very_long_field_hopefully_we_can_get_a_format_trigger_off_this_bad_boi: value\n"
+        ), "{content}");
+    }
+
+    #[test]
+    fn builders_generated_for_targeted_messages() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        let tonic = TonicOpts {
+            builders: vec![".my_proto.TestMessage".to_string()],
+            ..test_cfg.tonic.clone()
+        };
+        run_with_opts(test_opts(
+            tonic,
+            false,
+            Routine::Generate {
+                workspace: test_cfg.workspace.clone(),
+                watch: false,
+            },
+        ))
+        .unwrap();
+        let content = std::fs::read_to_string(proto_types_dir.join("my_proto.rs")).unwrap();
+        assert!(content.contains("pub mod builders {"), "{content}");
+        assert!(
+            content.contains("pub struct TestMessageBuilder(super::TestMessage);"),
+            "{content}"
+        );
+        assert!(!content.contains("MyNestedMessageBuilder"), "{content}");
+        assert!(content.contains(
+            "self.0.my_very_long_field_hopefully_we_can_get_a_format_trigger_off_this_bad_boi = Some(value.into());"
+        ), "{content}");
+    }
+
+    #[test]
+    fn default_constructors_fill_optional_fields() {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        std::fs::create_dir_all(&proto_files_dir).unwrap();
+        let settings = proto_files_dir.join("settings.proto");
+        std::fs::write(
+            &settings,
+            r#"syntax = "proto2";
+
+package settings;
+
+enum Mode {
+  MODE_OFF = 0;
+  MODE_ON = 1;
+}
+
+message Settings {
+  optional int32 retries = 1 [default = 3];
+  optional string name = 2 [default = "anon"];
+  optional Mode mode = 3 [default = MODE_ON];
+  optional double ratio = 4;
+  required int32 id = 5;
+}
+"#,
+        )
+        .unwrap();
+        let proto_types_dir = project_base.path().join("src").join("proto_types");
+        let tonic = TonicOpts {
+            default_impl: vec![".settings.Settings".to_string()],
+            ..TonicOpts::default()
+        };
+        run_with_opts(test_opts(
+            tonic,
+            true,
+            Routine::Generate {
+                workspace: WorkspaceOpts {
+                    proto_dirs: vec![proto_files_dir],
+                    proto_files: vec![settings],
+                    output_dir: proto_types_dir.clone(),
+                    ..WorkspaceOpts::default()
+                },
+                watch: false,
+            },
+        ))
+        .unwrap();
+        let content = std::fs::read_to_string(proto_types_dir.join("settings.rs")).unwrap();
+        assert!(content.contains("pub mod defaults {"), "{content}");
+        assert!(content.contains("impl super::Settings {"), "{content}");
+        assert!(content.contains("retries: Some(3),"), "{content}");
+        assert!(
+            content.contains("name: Some(::prost::alloc::string::String::from(\"anon\")),"),
+            "{content}"
+        );
+        assert!(content.contains("mode: Some(1),"), "{content}");
+        assert!(content.contains("ratio: Some(0.0_f64),"), "{content}");
+        assert!(!content.contains("id: Some("), "{content}");
+    }
+
+    #[test]
+    fn keyword_packages_use_raw_identifiers() {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        std::fs::create_dir_all(&proto_files_dir).unwrap();
+        let top = proto_files_dir.join("top.proto");
+        std::fs::write(
+            &top,
+            "syntax = \"proto3\";\n\npackage match;\n\nmessage Top {\n  int32 field = 1;\n}\n",
+        )
+        .unwrap();
+        let nested = proto_files_dir.join("nested.proto");
+        std::fs::write(
+            &nested,
+            "syntax = \"proto3\";\n\npackage match.type;\n\nmessage Nested {\n  int32 field = 1;\n}\n",
+        )
+        .unwrap();
+        let proto_types_dir = project_base.path().join("src").join("proto_types");
+        let workspace = WorkspaceOpts {
+            proto_dirs: vec![proto_files_dir],
+            proto_files: vec![top, nested],
+            output_dir: proto_types_dir.clone(),
+            ..WorkspaceOpts::default()
+        };
+        let opts = |routine| Opts {
+            prepend_header: false,
+            ..test_opts(TonicOpts::default(), true, routine)
+        };
+        run_with_opts(opts(Routine::Generate {
+            workspace: workspace.clone(),
+            watch: false,
+        }))
+        .unwrap();
+        let top_mod = std::fs::read_to_string(proto_types_dir.with_extension("rs")).unwrap();
+        assert!(top_mod.ends_with("\npub mod r#match;\n"), "{top_mod}");
+        let match_mod = std::fs::read_to_string(proto_types_dir.join("match.rs")).unwrap();
+        assert!(match_mod.starts_with("pub mod r#type;\n"), "{match_mod}");
+        assert!(match_mod.contains("pub struct Top {"), "{match_mod}");
+        let type_mod =
+            std::fs::read_to_string(proto_types_dir.join("match").join("type.rs")).unwrap();
+        assert!(type_mod.contains("pub struct Nested {"), "{type_mod}");
+        run_with_opts(opts(Routine::Validate { workspace })).unwrap();
+    }
+
+    #[test]
+    fn numbered_package_segments() {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        std::fs::create_dir_all(&proto_files_dir).unwrap();
+        let versioned = proto_files_dir.join("versioned.proto");
+        std::fs::write(
+            &versioned,
+            "syntax = \"proto3\";\n\npackage v2.foo;\n\nmessage Foo {\n  int32 field = 1;\n}\n",
+        )
+        .unwrap();
+        let proto_types_dir = project_base.path().join("src").join("proto_types");
+        run_with_opts(Opts {
+            prepend_header: false,
+            ..test_opts(
+                TonicOpts::default(),
+                false,
+                Routine::Generate {
+                    workspace: WorkspaceOpts {
+                        proto_dirs: vec![proto_files_dir],
+                        proto_files: vec![versioned],
+                        output_dir: proto_types_dir.clone(),
+                        ..WorkspaceOpts::default()
+                    },
+                    watch: false,
+                },
+            )
+        })
+        .unwrap();
+        let top_mod = std::fs::read_to_string(proto_types_dir.with_extension("rs")).unwrap();
+        assert!(top_mod.ends_with("\npub mod v2;\n"), "{top_mod}");
+        assert_eq!(
+            "pub mod foo;\n",
+            std::fs::read_to_string(proto_types_dir.join("v2.rs")).unwrap()
+        );
+        assert!(proto_types_dir.join("v2").join("foo.rs").is_file());
+    }
+
+    const PROTO2_FIXTURE: &str = r#"syntax = "proto2";
+
+package legacy;
+
+message Legacy {
+  required int32 id = 1;
+  optional string name = 2;
+  repeated group Entry = 3 {
+    required string key = 4;
+    optional int64 value = 5;
+  }
+}
+"#;
+
+    #[test]
+    fn proto2_required_fields_and_groups() {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        std::fs::create_dir_all(&proto_files_dir).unwrap();
+        let legacy = proto_files_dir.join("legacy.proto");
+        std::fs::write(&legacy, PROTO2_FIXTURE).unwrap();
+        let proto_types_dir = project_base.path().join("src").join("proto_types");
+        let workspace = WorkspaceOpts {
+            proto_dirs: vec![proto_files_dir.clone()],
+            proto_files: vec![legacy.clone()],
+            output_dir: proto_types_dir.clone(),
+            ..WorkspaceOpts::default()
+        };
+        run_with_opts(test_opts(
+            TonicOpts::default(),
+            true,
+            Routine::Generate {
+                workspace: workspace.clone(),
+                watch: false,
+            },
+        ))
+        .unwrap();
+        let content = std::fs::read_to_string(proto_types_dir.join("legacy.rs")).unwrap();
+        assert!(content.contains("#[prost(int32, required, tag = \"1\")]"));
+        assert!(content.contains("pub id: i32,"));
+        assert!(content.contains("#[prost(group, repeated, tag = \"3\")]"));
+        assert!(content.contains("pub struct Entry {"));
+        run_with_opts(test_opts(
+            TonicOpts::default(),
+            true,
+            Routine::Validate {
+                workspace: workspace.clone(),
+            },
+        ))
+        .unwrap();
+        // The same constructs in a proto3 file get a hint about proto2
+        std::fs::write(
+            &legacy,
+            "syntax = \"proto3\";\n\npackage legacy;\n\nmessage Legacy {\n  required int32 id = 1;\n}\n\nservice Registry {\n  rpc Get(Legacy) returns (Legacy);\n}\n",
+        )
+        .unwrap();
+        // Without a client or server, the default, protoc runs before prost does to strip
+        // the service
+        for omit_services in [false, true] {
+            let err = run_ws(
+                workspace.clone(),
+                configure_tonic(&TonicOpts::default()),
+                configure_prost(&TonicOpts::default()),
+                &GenOptions {
+                    omit_services,
+                    ..GenOptions::default()
+                },
+                "",
+            )
+            .unwrap_err();
+            assert!(
+                err.contains("`required` fields are a proto2 construct"),
+                "{err}"
+            );
+        }
+    }
+
+    #[test]
+    fn well_known_includes_resolve_imports() {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        std::fs::create_dir_all(&proto_files_dir).unwrap();
+        let timed = proto_files_dir.join("timed.proto");
+        std::fs::write(
+            &timed,
+            "syntax = \"proto3\";\n\npackage timed;\n\nimport \"google/protobuf/timestamp.proto\";\nimport \"google/protobuf/wrappers.proto\";\n\nmessage Timed {\n  google.protobuf.Timestamp at = 1;\n  google.protobuf.StringValue note = 2;\n}\n",
+        )
+        .unwrap();
+        let proto_types_dir = project_base.path().join("src").join("proto_types");
+        run_with_opts(test_opts(
+            TonicOpts::default(),
+            false,
+            Routine::Generate {
+                workspace: WorkspaceOpts {
+                    proto_dirs: vec![proto_files_dir],
+                    proto_files: vec![timed],
+                    output_dir: proto_types_dir.clone(),
+                    with_well_known_includes: true,
+                    ..WorkspaceOpts::default()
+                },
+                watch: false,
+            },
+        ))
+        .unwrap();
+        let content = std::fs::read_to_string(proto_types_dir.join("timed.rs")).unwrap();
+        assert!(content.contains("::prost_types::Timestamp"), "{content}");
+    }
+
+    #[test]
+    fn derive_serde_attributes_messages_enums_and_oneofs() {
+        let test_cfg = create_simple_test_cfg(None);
+        std::fs::write(
+            &test_cfg.workspace.proto_files[0],
+            "syntax = \"proto3\";\n\npackage my_proto;\n\nenum Color {\n  COLOR_UNSPECIFIED = 0;\n  COLOR_DARK_RED = 1;\n}\n\nmessage Paint {\n  Color paint_color = 1;\n  oneof finish {\n    string matte_name = 2;\n    int32 gloss_level = 3;\n  }\n}\n",
+        )
+        .unwrap();
+        run_with_opts(test_opts(
+            TonicOpts {
+                derive_serde: true,
+                serde_rename_all: Some("camelCase".to_string()),
+                ..test_cfg.tonic.clone()
+            },
+            false,
+            Routine::Generate {
+                workspace: test_cfg.workspace.clone(),
+                watch: false,
+            },
+        ))
+        .unwrap();
+        let content =
+            std::fs::read_to_string(test_cfg.workspace.output_dir.join("my_proto.rs")).unwrap();
+        for item in [
+            "pub struct Paint {",
+            "pub enum Color {",
+            "pub enum Finish {",
+        ] {
+            let start = content
+                .find(item)
+                .unwrap_or_else(|| panic!("{item} missing from {content}"));
+            // The attributes between the previous item and this one
+            let attributes = &content[content[..start].rfind('}').unwrap_or(0)..start];
+            assert!(
+                attributes.contains("#[derive(serde::Serialize, serde::Deserialize)]"),
+                "{item} in {content}"
+            );
+            assert!(
+                attributes.contains("#[serde(rename_all = \"camelCase\")]"),
+                "{item} in {content}"
+            );
+        }
+    }
+
+    #[test]
+    fn serde_well_known_fields_use_helpers() {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        std::fs::create_dir_all(&proto_files_dir).unwrap();
+        let timed = proto_files_dir.join("timed.proto");
+        std::fs::write(
+            &timed,
+            "syntax = \"proto3\";\n\npackage timed;\n\nimport \"google/protobuf/duration.proto\";\nimport \"google/protobuf/timestamp.proto\";\n\nmessage Timed {\n  google.protobuf.Timestamp at = 1;\n  repeated google.protobuf.Duration laps = 2;\n}\n",
+        )
+        .unwrap();
+        let proto_types_dir = project_base.path().join("src").join("proto_types");
+        let tonic = TonicOpts {
+            derive_serde: true,
+            serde_well_known: true,
+            ..TonicOpts::default()
+        };
+        run_with_opts(test_opts(
+            tonic,
+            false,
+            Routine::Generate {
+                workspace: WorkspaceOpts {
+                    proto_dirs: vec![proto_files_dir],
+                    proto_files: vec![timed],
+                    output_dir: proto_types_dir.clone(),
+                    with_well_known_includes: true,
+                    ..WorkspaceOpts::default()
+                },
+                watch: false,
+            },
+        ))
+        .unwrap();
+        let content = std::fs::read_to_string(proto_types_dir.join("timed.rs")).unwrap();
+        assert!(
+            content.contains("#[serde(default, with = \"self::serde_well_known::optional\")]"),
+            "{content}"
+        );
+        assert!(
+            content.contains("#[serde(default, with = \"self::serde_well_known::repeated\")]"),
+            "{content}"
+        );
+        assert!(content.contains("pub mod serde_well_known {"), "{content}");
+    }
+
+    #[test]
+    fn generates_from_image() {
+        let test_cfg = create_simple_test_cfg(None);
+        let image = test_cfg.workspace.proto_dirs[0].join("image.binpb");
+        let protoc = std::process::Command::new(prost_build::protoc_from_env())
+            .arg("--include_imports")
+            .arg("--include_source_info")
+            .arg("-o")
+            .arg(&image)
+            .arg("-I")
+            .arg(&test_cfg.workspace.proto_dirs[0])
+            .args(&test_cfg.workspace.proto_files)
+            .status()
+            .unwrap();
+        assert!(protoc.success());
+        run_with_opts(test_opts(
+            test_cfg.tonic.clone(),
+            true,
+            Routine::Generate {
+                workspace: test_cfg.workspace.clone(),
+                watch: false,
+            },
+        ))
+        .unwrap();
+        // Same output as from the proto files
+        run_with_opts(test_opts(
+            test_cfg.tonic.clone(),
+            true,
+            Routine::Validate {
+                workspace: WorkspaceOpts {
+                    proto_dirs: vec![],
+                    proto_files: vec![],
+                    from_image: Some(image),
+                    ..test_cfg.workspace.clone()
+                },
+            },
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn missing_proto_inputs_are_reported() {
+        let test_cfg = create_simple_test_cfg(None);
+        let missing_file = test_cfg.workspace.proto_dirs[0].join("missing.proto");
+        let err = run_ws(
+            WorkspaceOpts {
+                proto_files: vec![missing_file.clone()],
+                ..test_cfg.workspace.clone()
+            },
+            configure_tonic(&test_cfg.tonic),
+            configure_prost(&test_cfg.tonic),
+            &GenOptions::default(),
+            "",
+        )
+        .unwrap_err();
+        assert!(err.contains(&format!("{missing_file:?}")), "{err}");
+        let missing_dir = test_cfg.workspace.proto_dirs[0].join("missing");
+        let err = run_ws(
+            WorkspaceOpts {
+                proto_dirs: vec![missing_dir.clone()],
+                ..test_cfg.workspace.clone()
+            },
+            configure_tonic(&test_cfg.tonic),
+            configure_prost(&test_cfg.tonic),
+            &GenOptions::default(),
+            "",
+        )
+        .unwrap_err();
+        assert!(err.contains(&format!("{missing_dir:?}")), "{err}");
+    }
+
+    #[test]
+    fn include_dirs_adds_proto_file_parents() {
+        let dirs = include_dirs(
+            vec![
+                PathBuf::from("proto"),
+                PathBuf::from("deps"),
+                PathBuf::from("proto"),
+            ],
+            &[
+                PathBuf::from("proto/my.proto"),
+                PathBuf::from("other/nested/other.proto"),
+                PathBuf::from("top.proto"),
+            ],
+        );
+        assert_eq!(
+            vec![
+                PathBuf::from("proto"),
+                PathBuf::from("deps"),
+                PathBuf::from("other/nested"),
+                PathBuf::from("."),
+            ],
+            dirs
+        );
+    }
+
+    #[test]
+    fn generates_without_proto_file_dir_in_proto_dirs() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        let opts = test_opts(
+            test_cfg.tonic.clone(),
+            false,
+            Routine::Generate {
+                workspace: WorkspaceOpts {
+                    proto_dirs: vec![],
+                    ..test_cfg.workspace.clone()
+                },
+                watch: false,
+            },
+        );
+        run_with_opts(opts).unwrap();
+        assert_exists_not_empty(&proto_types_dir.join("my_proto.rs"));
+    }
+
+    #[test]
+    fn relative_output_dir_places_mod_file_next_to_it() {
+        let test_cfg = create_simple_test_cfg(None);
+        let cwd = tempfile::tempdir().unwrap();
+        assert_eq!(cwd.path(), resolve_output_dir(cwd.path(), Path::new(".")));
+        assert_eq!(
+            cwd.path().join("generated"),
+            resolve_output_dir(cwd.path(), Path::new("nested/../generated"))
+        );
+        let output_dir = resolve_output_dir(cwd.path(), Path::new("generated"));
+        assert_eq!(cwd.path().join("generated"), output_dir);
+        let opts = test_opts(
+            test_cfg.tonic.clone(),
+            false,
+            Routine::Generate {
+                workspace: WorkspaceOpts {
+                    output_dir,
+                    ..test_cfg.workspace.clone()
+                },
+                watch: false,
+            },
+        );
+        run_with_opts(opts).unwrap();
+        assert_exists_not_empty(&cwd.path().join("generated.rs"));
+        assert_exists_not_empty(&cwd.path().join("generated").join("my_proto.rs"));
+    }
+
+    #[test]
+    fn ignored_files_survive_generate() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        let generate = || {
+            test_opts(
+                test_cfg.tonic.clone(),
+                false,
+                Routine::Generate {
+                    workspace: test_cfg.workspace.clone(),
+                    watch: false,
+                },
+            )
+        };
+        run_with_opts(generate()).unwrap();
+        let edited = proto_types_dir.join("my_proto.rs");
+        std::fs::write(&edited, "// Hand edited\n").unwrap();
+        std::fs::write(proto_types_dir.join(".protogenignore"), "my_proto.rs\n").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&edited, std::fs::Permissions::from_mode(0o600)).unwrap();
+        }
+        let modified = std::fs::metadata(&edited).unwrap().modified().unwrap();
+        run_with_opts(test_opts(
+            test_cfg.tonic.clone(),
+            false,
+            Routine::Validate {
+                workspace: test_cfg.workspace.clone(),
+            },
+        ))
+        .unwrap();
+        // Force a diff so that the output dir gets rewritten
+        write_stale_module(&proto_types_dir.with_extension("rs"), &proto_types_dir);
+        run_with_opts(generate()).unwrap();
+        assert!(!proto_types_dir.join("stale.rs").exists());
+        assert!(!proto_types_dir.join("stale").exists());
+        assert_eq!(
+            "// Hand edited\n",
+            std::fs::read_to_string(&edited).unwrap()
+        );
+        assert_exists_not_empty(&proto_types_dir.join(".protogenignore"));
+        // Left in place rather than rewritten
+        let metadata = std::fs::metadata(&edited).unwrap();
+        assert_eq!(modified, metadata.modified().unwrap());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(0o600, metadata.permissions().mode() & 0o7777);
+        }
+    }
+
+    #[test]
+    fn ignore_flag_tolerates_drift() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        run_with_opts(test_opts(
+            test_cfg.tonic.clone(),
+            false,
+            Routine::Generate {
+                workspace: test_cfg.workspace.clone(),
+                watch: false,
+            },
+        ))
+        .unwrap();
+        std::fs::write(proto_types_dir.join("my_proto.rs"), "// Hand edited\n").unwrap();
+        let validate = |ignore: Vec<String>| {
+            run_with_opts(Opts {
+                ignore,
+                ..test_opts(
+                    test_cfg.tonic.clone(),
+                    false,
+                    Routine::Validate {
+                        workspace: test_cfg.workspace.clone(),
+                    },
+                )
+            })
+        };
+        assert_eq!(Err(1), validate(vec![]));
+        validate(vec!["my_*.rs".to_string()]).unwrap();
+    }
+
+    /// Leaves a module from an earlier generation in the output dir, declared by its module
+    /// file, so that the next `generate` finds a diff and removes it
+    fn write_stale_module(mod_file: &Path, output_dir: &Path) {
+        let mut content = std::fs::read_to_string(mod_file).unwrap_or_default();
+        content.push_str("pub mod stale;\n");
+        std::fs::write(mod_file, content).unwrap();
+        std::fs::write(output_dir.join("stale.rs"), "pub mod nested;\n").unwrap();
+        std::fs::create_dir_all(output_dir.join("stale")).unwrap();
+        std::fs::write(output_dir.join("stale").join("nested.rs"), "// Stale\n").unwrap();
+    }
+
+    #[test]
+    fn generate_keeps_foreign_files_without_force() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        let generate = |force: bool| {
+            run_with_opts(Opts {
+                force,
+                ..test_opts(
+                    test_cfg.tonic.clone(),
+                    false,
+                    Routine::Generate {
+                        workspace: test_cfg.workspace.clone(),
+                        watch: false,
+                    },
+                )
+            })
+        };
+        generate(false).unwrap();
+        // Left over from a renamed package, declared by the module file of the earlier run
+        std::fs::write(proto_types_dir.join("old_proto.rs"), "pub struct Old {}\n").unwrap();
+        let mod_file = proto_types_dir.with_extension("rs");
+        let mut mod_content = std::fs::read_to_string(&mod_file).unwrap();
+        mod_content.push_str("pub mod old_proto;\n");
+        std::fs::write(&mod_file, mod_content).unwrap();
+        generate(false).unwrap();
+        assert!(!proto_types_dir.join("old_proto.rs").exists());
+        // Hand written, even if it uses generated code
+        let glue = proto_types_dir.join("glue.rs");
+        std::fs::write(&glue, "use tonic::Status;\n").unwrap();
+        let notes = proto_types_dir.join("notes.md");
+        std::fs::write(&notes, "Hand written\n").unwrap();
+        assert_eq!(Err(1), generate(false));
+        assert!(glue.exists());
+        assert!(notes.exists());
+        generate(true).unwrap();
+        assert!(!glue.exists());
+        assert!(!notes.exists());
+    }
+
+    #[test]
+    fn drift_exit_code_only_for_diffs() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        run_with_opts(test_opts(
+            test_cfg.tonic.clone(),
+            false,
+            Routine::Generate {
+                workspace: test_cfg.workspace.clone(),
+                watch: false,
+            },
+        ))
+        .unwrap();
+        std::fs::write(proto_types_dir.join("my_proto.rs"), "// Hand edited\n").unwrap();
+        let validate = |workspace: WorkspaceOpts| {
+            run_with_opts(Opts {
+                drift_exit_code: 2,
+                ..test_opts(
+                    test_cfg.tonic.clone(),
+                    false,
+                    Routine::Validate { workspace },
+                )
+            })
+        };
+        assert_eq!(Err(2), validate(test_cfg.workspace.clone()));
+        let missing = WorkspaceOpts {
+            proto_files: vec![proto_types_dir.join("missing.proto")],
+            ..test_cfg.workspace.clone()
+        };
+        assert_eq!(Err(1), validate(missing));
+    }
+
+    /// Contents of all files under `dir`, keyed by their path relative to it
+    fn read_tree(dir: &Path) -> std::collections::BTreeMap<PathBuf, Vec<u8>> {
+        let mut files = std::collections::BTreeMap::new();
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(current) = pending.pop() {
+            for entry in std::fs::read_dir(&current).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    pending.push(path);
+                } else {
+                    let content = std::fs::read(&path).unwrap();
+                    files.insert(path.strip_prefix(dir).unwrap().to_path_buf(), content);
+                }
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn generation_is_reproducible() {
+        let test_cfg = create_simple_test_cfg(None);
+        let tonic = TonicOpts {
+            builders: vec![".".to_string()],
+            ..test_cfg.tonic.clone()
+        };
+        let generate = |output_dir: PathBuf| {
+            run_with_opts(test_opts(
+                tonic.clone(),
+                true,
+                Routine::Generate {
+                    workspace: WorkspaceOpts {
+                        output_dir: output_dir.clone(),
+                        ..test_cfg.workspace.clone()
+                    },
+                    watch: false,
+                },
+            ))
+            .unwrap();
+            let mut files = read_tree(&output_dir);
+            let top_mod = std::fs::read(output_dir.with_extension("rs")).unwrap();
+            files.insert(PathBuf::from("..top"), top_mod);
+            files
+        };
+        let first = generate(test_cfg.workspace.output_dir.clone());
+        let second = generate(test_cfg.workspace.output_dir.with_file_name("again"));
+        assert!(first.contains_key(Path::new("my_proto.rs")));
+        assert_eq!(first, second);
+        assert_eq!(
+            Ok(()),
+            run_with_opts(test_opts(
+                tonic.clone(),
+                true,
+                Routine::Validate {
+                    workspace: test_cfg.workspace.clone(),
+                },
+            ))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_output_dir_is_kept() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        let project_base = proto_types_dir.parent().unwrap().parent().unwrap();
+        let real_dir = project_base.join("real_types");
+        std::fs::create_dir_all(&real_dir).unwrap();
+        std::fs::create_dir_all(proto_types_dir.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink(&real_dir, &proto_types_dir).unwrap();
+        write_stale_module(&proto_types_dir.with_extension("rs"), &proto_types_dir);
+        let run =
+            |routine: Routine| run_with_opts(test_opts(test_cfg.tonic.clone(), false, routine));
+        run(Routine::Generate {
+            workspace: test_cfg.workspace.clone(),
+            watch: false,
+        })
+        .unwrap();
+        let link = std::fs::symlink_metadata(&proto_types_dir).unwrap();
+        assert!(link.file_type().is_symlink());
+        assert!(real_dir.join("my_proto.rs").is_file());
+        assert!(!real_dir.join("stale").exists());
+        assert!(!real_dir.join("stale.rs").exists());
+        assert!(proto_types_dir.with_extension("rs").is_file());
+        assert!(!project_base.join("real_types.rs").exists());
+        assert_eq!(
+            Ok(()),
+            run(Routine::Validate {
+                workspace: test_cfg.workspace.clone(),
+            })
+        );
+    }
+
+    #[test]
+    fn list_leaves_output_dir_untouched() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        run_with_opts(test_opts(
+            test_cfg.tonic.clone(),
+            false,
+            Routine::List {
+                workspace: test_cfg.workspace.clone(),
+                top_module: false,
+            },
+        ))
+        .unwrap();
+        assert!(!proto_types_dir.exists());
+        assert!(!proto_types_dir.with_extension("rs").exists());
+    }
+
+    #[test]
+    fn top_level_module_computed_without_writing() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        let tmp = tempfile::tempdir().unwrap();
+        let ws = ProtoWorkspace {
+            proto_dirs: test_cfg.workspace.proto_dirs.clone(),
+            proto_files: test_cfg.workspace.proto_files.clone(),
+            tmp_dir: tmp.path().to_path_buf(),
+            output_dir: proto_types_dir.clone(),
+            package_outputs: vec![],
+            image: None,
+            openapi: None,
+            archive: None,
+            mod_file: None,
+        };
+        let content = gen::top_level_module(
+            &ws,
+            configure_tonic(&test_cfg.tonic),
+            configure_prost(&test_cfg.tonic),
+            &GenOptions::default(),
+        )
+        .unwrap();
+        assert!(content.ends_with("\npub mod my_proto;\n"), "{content}");
+        run_with_opts(test_opts(
+            test_cfg.tonic.clone(),
+            true,
+            Routine::List {
+                workspace: test_cfg.workspace.clone(),
+                top_module: true,
+            },
+        ))
+        .unwrap();
+        assert!(!proto_types_dir.exists());
+        assert!(!proto_types_dir.with_extension("rs").exists());
+    }
+
+    #[test]
+    fn build_script_writes_into_output_dir() {
+        let test_cfg = create_simple_test_cfg(None);
+        let out_dir = test_cfg.workspace.output_dir.with_file_name("out");
+        std::fs::create_dir_all(&out_dir).unwrap();
+        std::fs::write(out_dir.join("other.txt"), "kept").unwrap();
+        run_with_opts(Opts {
+            build_script: true,
+            prepend_header: false,
+            ..test_opts(
+                test_cfg.tonic.clone(),
+                false,
+                Routine::Generate {
+                    workspace: WorkspaceOpts {
+                        output_dir: out_dir.clone(),
+                        ..test_cfg.workspace.clone()
+                    },
+                    watch: false,
+                },
+            )
+        })
+        .unwrap();
+        assert_eq!(
+            "#[allow(clippy::doc_markdown, clippy::use_self)]\npub mod my_proto;\n",
+            std::fs::read_to_string(out_dir.join("mod.rs")).unwrap()
+        );
+        assert!(out_dir.join("my_proto.rs").is_file());
+        assert!(out_dir.join("other.txt").is_file());
+        assert!(!out_dir.with_extension("rs").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn written_files_get_file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        run_with_opts(Opts {
+            file_mode: 0o600,
+            ..test_opts(
+                test_cfg.tonic.clone(),
+                false,
+                Routine::Generate {
+                    workspace: test_cfg.workspace.clone(),
+                    watch: false,
+                },
+            )
+        })
+        .unwrap();
+        for file in [
+            proto_types_dir.join("my_proto.rs"),
+            proto_types_dir.with_extension("rs"),
+        ] {
+            let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+            assert_eq!(0o600, mode & 0o7777, "{file:?}");
+        }
+        let default = Opts::try_parse_from(["proto-gen", "validate", "-o", "out"]).unwrap();
+        assert_eq!(0o644, default.file_mode);
+    }
+
+    #[test]
+    fn stdout_requires_single_module() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        let stdout_opts = GenOptions {
+            stdout: true,
+            ..GenOptions::default()
+        };
+        let run = |workspace| {
+            run_ws(
+                workspace,
+                configure_tonic(&test_cfg.tonic),
+                configure_prost(&test_cfg.tonic),
+                &stdout_opts,
+                "",
+            )
+        };
+        run(test_cfg.workspace.clone()).unwrap();
+        assert!(!proto_types_dir.exists());
+        let other_proto = test_cfg.workspace.proto_dirs[0].join("other.proto");
+        std::fs::write(
+            &other_proto,
+            "syntax = \"proto3\";\n\npackage other;\n\nmessage Other {\n  int32 field = 1;\n}\n",
+        )
+        .unwrap();
+        let mut workspace = test_cfg.workspace.clone();
+        workspace.proto_files.push(other_proto);
+        let err = run(workspace).unwrap_err();
+        assert!(
+            err.contains("--stdout only supports a single output module"),
+            "{err}"
+        );
+        assert!(!proto_types_dir.exists());
+    }
+
+    #[test]
+    fn custom_sections_survive_generate() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        let opts = |routine| test_opts(test_cfg.tonic.clone(), true, routine);
+        let generate = || {
+            opts(Routine::Generate {
+                workspace: test_cfg.workspace.clone(),
+                watch: false,
+            })
+        };
+        run_with_opts(generate()).unwrap();
+        let custom =
+            "// proto-gen:begin-custom\nimpl Helper for Thing {}\n// proto-gen:end-custom\n";
+        for file in [
+            proto_types_dir.with_extension("rs"),
+            proto_types_dir.join("my_proto.rs"),
+        ] {
+            let mut content = std::fs::read_to_string(&file).unwrap();
+            content.push_str(custom);
+            std::fs::write(&file, content).unwrap();
+        }
+        run_with_opts(opts(Routine::Validate {
+            workspace: test_cfg.workspace.clone(),
+        }))
+        .unwrap();
+        // Force a diff so that the output dir gets rewritten
+        write_stale_module(&proto_types_dir.with_extension("rs"), &proto_types_dir);
+        run_with_opts(generate()).unwrap();
+        assert!(!proto_types_dir.join("stale.rs").exists());
+        for file in [
+            proto_types_dir.with_extension("rs"),
+            proto_types_dir.join("my_proto.rs"),
+        ] {
+            let content = std::fs::read_to_string(&file).unwrap();
+            assert_eq!(1, content.matches(custom).count(), "{file:?}");
+        }
+    }
+
+    #[test]
+    fn rustfmt_version_recorded_and_checked() {
+        let test_cfg = create_simple_test_cfg(None);
+        let version_file = test_cfg
+            .workspace
+            .output_dir
+            .with_extension("rustfmt-version");
+        let opts = |routine| Opts {
+            format_check_pinned: true,
+            ..test_opts(test_cfg.tonic.clone(), true, routine)
+        };
+        run_with_opts(opts(Routine::Generate {
+            workspace: test_cfg.workspace.clone(),
+            watch: false,
+        }))
+        .unwrap();
+        let recorded = std::fs::read_to_string(&version_file).unwrap();
+        assert!(recorded.starts_with("rustfmt "), "{recorded}");
+        // Another version only warns, the code is still compared as is
+        std::fs::write(&version_file, "rustfmt 0.0.0-stable\n").unwrap();
+        run_with_opts(opts(Routine::Validate {
+            workspace: test_cfg.workspace.clone(),
+        }))
+        .unwrap();
+        assert_eq!(
+            "rustfmt 0.0.0-stable\n",
+            std::fs::read_to_string(&version_file).unwrap()
+        );
+    }
+
+    #[test]
+    fn mod_file_written_inside_output_dir() {
+        let test_cfg = create_simple_test_cfg(None);
+        let src = test_cfg
+            .workspace
+            .output_dir
+            .parent()
+            .unwrap()
+            .to_path_buf();
+        let lib = src.join("lib.rs");
+        let workspace = WorkspaceOpts {
+            output_dir: src.clone(),
+            mod_file: Some(lib.clone()),
+            ..test_cfg.workspace.clone()
+        };
+        let opts = |routine| test_opts(test_cfg.tonic.clone(), true, routine);
+        let generate = || {
+            opts(Routine::Generate {
+                workspace: workspace.clone(),
+                watch: false,
+            })
+        };
+        run_with_opts(generate()).unwrap();
+        assert_exists_not_empty(&src.join("my_proto.rs"));
+        assert!(!src.with_extension("rs").exists());
+        let custom =
+            "// proto-gen:begin-custom\npub mod hand_written {}\n// proto-gen:end-custom\n";
+        let mut content = std::fs::read_to_string(&lib).unwrap();
+        assert!(content.contains("pub mod my_proto;\n"), "{content}");
+        content.push_str(custom);
+        std::fs::write(&lib, content).unwrap();
+        run_with_opts(opts(Routine::Validate {
+            workspace: workspace.clone(),
+        }))
+        .unwrap();
+        // Force a diff so that the output dir gets cleaned and rewritten
+        write_stale_module(&lib, &src);
+        run_with_opts(generate()).unwrap();
+        assert!(!src.join("stale.rs").exists());
+        let content = std::fs::read_to_string(&lib).unwrap();
+        assert_eq!(1, content.matches(custom).count(), "{content}");
+        std::fs::write(&lib, "pub mod my_proto;\n").unwrap();
+        assert!(run_with_opts(opts(Routine::Validate { workspace })).is_err());
+    }
+
+    #[test]
+    fn packageless_protos_use_default_package_filename() {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        std::fs::create_dir_all(&proto_files_dir).unwrap();
+        let packageless = proto_files_dir.join("packageless.proto");
+        std::fs::write(
+            &packageless,
+            "syntax = \"proto3\";\n\nmessage Loose {\n  int32 field = 1;\n}\n",
+        )
+        .unwrap();
+        let packaged = proto_files_dir.join("packaged.proto");
+        std::fs::write(
+            &packaged,
+            "syntax = \"proto3\";\n\npackage packaged;\n\nmessage Tight {\n  int32 field = 1;\n}\n",
+        )
+        .unwrap();
+        let proto_types_dir = project_base.path().join("src").join("proto_types");
+        let opts = test_opts(
+            TonicOpts {
+                default_package_filename: Some("common".to_string()),
+                ..TonicOpts::default()
+            },
+            false,
+            Routine::Generate {
+                workspace: WorkspaceOpts {
+                    proto_dirs: vec![proto_files_dir],
+                    proto_files: vec![packageless, packaged],
+                    output_dir: proto_types_dir.clone(),
+                    ..WorkspaceOpts::default()
+                },
+                watch: false,
+            },
+        );
+        run_with_opts(opts).unwrap();
+        let common = std::fs::read_to_string(proto_types_dir.join("common.rs")).unwrap();
+        assert!(common.contains("pub struct Loose"));
+        assert_exists_not_empty(&proto_types_dir.join("packaged.rs"));
+        let top_mod = std::fs::read_to_string(proto_types_dir.with_extension("rs")).unwrap();
+        assert!(top_mod.contains("pub mod common;\npub mod packaged;\n"));
+    }
+
+    #[test]
+    fn disable_comments_regex_matches_proto_paths() {
+        let generate = |regex: &str| {
+            let test_cfg = create_simple_test_cfg(None);
+            let opts = test_opts(
+                TonicOpts {
+                    disable_comments_regex: vec![Regex::new(regex).unwrap()],
+                    ..test_cfg.tonic.clone()
+                },
+                false,
+                Routine::Generate {
+                    workspace: test_cfg.workspace.clone(),
+                    watch: false,
+                },
+            );
+            run_with_opts(opts).unwrap();
+            std::fs::read_to_string(test_cfg.workspace.output_dir.join("my_proto.rs")).unwrap()
+        };
+        let content = generate(r"\.field_one$");
+        assert!(content.contains("/// My comment"));
+        assert!(!content.contains("/// My field comment!"));
+        let content = generate(r"^\.my_proto$");
+        assert!(!content.contains("/// My comment"));
+        assert!(!content.contains("/// My field comment!"));
+    }
+
+    #[test]
+    fn retain_comments_overrides_disabled_parent() {
+        let generate = |retain: &str| {
+            let test_cfg = create_simple_test_cfg(None);
+            let opts = test_opts(
+                TonicOpts {
+                    disable_comments: vec![".".to_string()],
+                    retain_comments: vec![retain.to_string()],
+                    ..test_cfg.tonic.clone()
+                },
+                false,
+                Routine::Generate {
+                    workspace: test_cfg.workspace.clone(),
+                    watch: false,
+                },
+            );
+            run_with_opts(opts).unwrap();
+            std::fs::read_to_string(test_cfg.workspace.output_dir.join("my_proto.rs")).unwrap()
+        };
+        // The message is the field's parent, so its comment is kept too
+        let content = generate(".my_proto.TestMessage.field_one");
+        assert!(content.contains("/// My comment"), "{content}");
+        assert!(content.contains("/// My field comment!"), "{content}");
+        let content = generate(".my_proto.MyNestedMessage");
+        assert!(!content.contains("/// My comment"), "{content}");
+        assert!(!content.contains("/// My field comment!"), "{content}");
+    }
+
+    fn generate_with_module_docs(disable_comments: Vec<String>) -> (String, String) {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        std::fs::create_dir_all(&proto_files_dir).unwrap();
+        let documented = proto_files_dir.join("documented.proto");
+        std::fs::write(
+            &documented,
+            "syntax = \"proto3\";\n\n// Types for the outer\n// inner package\npackage outer.inner;\n\nmessage Inner {\n  int32 field = 1;\n}\n",
+        )
+        .unwrap();
+        let proto_types_dir = project_base.path().join("src").join("proto_types");
+        let opts = Opts {
+            module_docs: true,
+            prepend_header: false,
+            ..test_opts(
+                TonicOpts {
+                    disable_comments,
+                    ..TonicOpts::default()
+                },
+                false,
+                Routine::Generate {
+                    workspace: WorkspaceOpts {
+                        proto_dirs: vec![proto_files_dir],
+                        proto_files: vec![documented],
+                        output_dir: proto_types_dir.clone(),
+                        ..WorkspaceOpts::default()
+                    },
+                    watch: false,
+                },
+            )
+        };
+        run_with_opts(opts).unwrap();
+        (
+            std::fs::read_to_string(proto_types_dir.join("outer.rs")).unwrap(),
+            std::fs::read_to_string(proto_types_dir.join("outer").join("inner.rs")).unwrap(),
+        )
+    }
+
+    #[test]
+    fn module_docs_from_package_comments() {
+        let (outer, inner) = generate_with_module_docs(vec![]);
+        assert_eq!("pub mod inner;\n", outer);
+        assert!(
+            inner.starts_with("//! Types for the outer\n//! inner package\n"),
+            "{inner}"
+        );
+        let (_, inner) = generate_with_module_docs(vec![".outer".to_string()]);
+        assert!(!inner.contains("//!"), "{inner}");
+    }
+
+    #[test]
+    fn keep_empty_modules_writes_empty_packages() {
+        let test_cfg = create_simple_test_cfg(None);
+        let empty_proto = test_cfg.workspace.proto_dirs[0].join("empty.proto");
+        std::fs::write(&empty_proto, "syntax = \"proto3\";\n\npackage empty_pkg;\n").unwrap();
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        let generate = |keep_empty_modules: bool| {
+            let mut workspace = test_cfg.workspace.clone();
+            workspace.proto_files.push(empty_proto.clone());
+            run_with_opts(Opts {
+                keep_empty_modules,
+                prepend_header: false,
+                ..test_opts(
+                    test_cfg.tonic.clone(),
+                    false,
+                    Routine::Generate {
+                        workspace,
+                        watch: false,
+                    },
+                )
+            })
+            .unwrap();
+            std::fs::read_to_string(proto_types_dir.with_extension("rs")).unwrap()
+        };
+        let top_mod = generate(false);
+        assert!(!top_mod.contains("pub mod empty_pkg;"), "{top_mod}");
+        assert!(!proto_types_dir.join("empty_pkg.rs").exists());
+        let top_mod = generate(true);
+        assert!(top_mod.contains("pub mod empty_pkg;"), "{top_mod}");
+        assert_eq!(
+            "",
+            std::fs::read_to_string(proto_types_dir.join("empty_pkg.rs")).unwrap()
+        );
+    }
+
+    #[test]
+    fn module_visibility_applies_at_every_level() {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        std::fs::create_dir_all(&proto_files_dir).unwrap();
+        let nested = proto_files_dir.join("nested.proto");
+        std::fs::write(
+            &nested,
+            "syntax = \"proto3\";\n\npackage outer.inner;\n\nmessage Inner {\n  int32 field = 1;\n}\n",
+        )
+        .unwrap();
+        let proto_types_dir = project_base.path().join("src").join("proto_types");
+        let opts = Opts {
+            module_visibility: ModuleVisibility::PubCrate,
+            prepend_header: false,
+            ..test_opts(
+                TonicOpts::default(),
+                false,
+                Routine::Generate {
+                    workspace: WorkspaceOpts {
+                        proto_dirs: vec![proto_files_dir],
+                        proto_files: vec![nested],
+                        output_dir: proto_types_dir.clone(),
+                        ..WorkspaceOpts::default()
+                    },
+                    watch: false,
+                },
+            )
+        };
+        run_with_opts(opts).unwrap();
+        let top_mod = std::fs::read_to_string(proto_types_dir.with_extension("rs")).unwrap();
+        assert!(top_mod.ends_with("\npub(crate) mod outer;\n"), "{top_mod}");
+        assert_eq!(
+            "pub(crate) mod inner;\n",
+            std::fs::read_to_string(proto_types_dir.join("outer.rs")).unwrap()
+        );
+    }
+
+    #[test]
+    fn wrap_module_nests_output() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        let opts = |routine| Opts {
+            wrap_module: Some("proto".to_string()),
+            ..test_opts(test_cfg.tonic.clone(), true, routine)
+        };
+        run_with_opts(opts(Routine::Generate {
+            workspace: test_cfg.workspace.clone(),
+            watch: false,
+        }))
+        .unwrap();
+        let top_mod = std::fs::read_to_string(proto_types_dir.with_extension("rs")).unwrap();
+        assert!(
+            top_mod.ends_with("pub mod proto {\n    pub mod my_proto;\n}\n"),
+            "{top_mod}"
+        );
+        assert!(proto_types_dir.join("proto").join("my_proto.rs").is_file());
+        run_with_opts(opts(Routine::Validate {
+            workspace: test_cfg.workspace.clone(),
+        }))
+        .unwrap();
+        // Output without the extra layer no longer matches
+        assert_eq!(
+            Err(1),
+            run_with_opts(test_opts(
+                test_cfg.tonic.clone(),
+                true,
+                Routine::Validate {
+                    workspace: test_cfg.workspace.clone(),
+                },
+            ))
+        );
+    }
+
+    #[test]
+    fn no_clippy_allows_keeps_toplevel_attribute() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        run_with_opts(Opts {
+            no_clippy_allows: true,
+            toplevel_attribute: Some("#![allow(clippy::pedantic)]".to_string()),
+            prepend_header: false,
+            ..test_opts(
+                test_cfg.tonic.clone(),
+                false,
+                Routine::Generate {
+                    workspace: test_cfg.workspace.clone(),
+                    watch: false,
+                },
+            )
+        })
+        .unwrap();
+        assert_eq!(
+            "#![allow(clippy::pedantic)]\npub mod my_proto;\n",
+            std::fs::read_to_string(proto_types_dir.with_extension("rs")).unwrap()
+        );
+    }
+
+    #[test]
+    fn allow_missing_docs_in_module_files() {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        std::fs::create_dir_all(&proto_files_dir).unwrap();
+        let proto = proto_files_dir.join("nested.proto");
+        std::fs::write(
+            &proto,
+            "syntax = \"proto3\";\n\npackage imports.nested;\n\nmessage Msg {\n  int32 field = 1;\n}\n",
+        )
+        .unwrap();
+        let proto_types_dir = project_base.path().join("src").join("proto_types");
+        run_with_opts(Opts {
+            allow_missing_docs: true,
+            prepend_header: false,
+            ..test_opts(
+                TonicOpts::default(),
+                false,
+                Routine::Generate {
+                    workspace: WorkspaceOpts {
+                        proto_dirs: vec![proto_files_dir],
+                        proto_files: vec![proto],
+                        output_dir: proto_types_dir.clone(),
+                        ..WorkspaceOpts::default()
+                    },
+                    watch: false,
+                },
+            )
+        })
+        .unwrap();
+        assert_eq!(
+            "#![allow(clippy::doc_markdown, clippy::use_self)]\n#![allow(missing_docs)]\npub mod imports;\n",
+            std::fs::read_to_string(proto_types_dir.with_extension("rs")).unwrap()
+        );
+        assert_eq!(
+            "#![allow(missing_docs)]\npub mod nested;\n",
+            std::fs::read_to_string(proto_types_dir.join("imports.rs")).unwrap()
+        );
+        let nested = std::fs::read_to_string(proto_types_dir.join("imports").join("nested.rs"));
+        assert!(!nested.unwrap().contains("missing_docs"));
+    }
+
+    #[test]
+    fn split_services_moves_service_modules() {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        std::fs::create_dir_all(&proto_files_dir).unwrap();
+        let service = proto_files_dir.join("service.proto");
+        std::fs::write(
+            &service,
+            "syntax = \"proto3\";\n\npackage svc;\n\nmessage Req {\n  int32 field = 1;\n}\n\nservice Greeter {\n  rpc Hello(Req) returns (Req);\n}\n",
+        )
+        .unwrap();
+        let proto_types_dir = project_base.path().join("src").join("proto_types");
+        let workspace = WorkspaceOpts {
+            proto_dirs: vec![proto_files_dir],
+            proto_files: vec![service],
+            output_dir: proto_types_dir.clone(),
+            ..WorkspaceOpts::default()
+        };
+        let opts = |routine| Opts {
+            split_services: true,
+            prepend_header: false,
+            ..test_opts(
+                TonicOpts {
+                    build_client: true,
+                    build_server: true,
+                    ..TonicOpts::default()
+                },
+                true,
+                routine,
+            )
+        };
+        run_with_opts(opts(Routine::Generate {
+            workspace: workspace.clone(),
+            watch: false,
+        }))
+        .unwrap();
+        let svc = std::fs::read_to_string(proto_types_dir.join("svc.rs")).unwrap();
+        assert!(
+            svc.starts_with("pub mod svc_service;\npub use svc_service::*;\n"),
+            "{svc}"
+        );
+        assert!(svc.contains("pub struct Req {"), "{svc}");
+        assert!(!svc.contains("greeter_client"), "{svc}");
+        let services =
+            std::fs::read_to_string(proto_types_dir.join("svc").join("svc_service.rs")).unwrap();
+        assert!(services.contains("pub mod greeter_client {"), "{services}");
+        assert!(services.contains("pub mod greeter_server {"), "{services}");
+        assert!(services.contains("super::super::Req"), "{services}");
+        run_with_opts(opts(Routine::Validate { workspace })).unwrap();
+    }
+
+    #[test]
+    fn codec_path_replaces_prost_codec() {
+        let test_cfg = create_simple_test_cfg(None);
+        std::fs::write(
+            &test_cfg.workspace.proto_files[0],
+            "syntax = \"proto3\";\n\npackage my_proto;\n\nmessage Req {\n  int32 field = 1;\n}\n\nservice Greeter {\n  rpc Hello(Req) returns (Req);\n}\n",
+        )
+        .unwrap();
+        run_with_opts(test_opts(
+            TonicOpts {
+                build_client: true,
+                build_server: true,
+                codec_path: Some("crate::codec::JsonCodec".to_string()),
+                ..TonicOpts::default()
+            },
+            false,
+            Routine::Generate {
+                workspace: test_cfg.workspace.clone(),
+                watch: false,
+            },
+        ))
+        .unwrap();
+        let content =
+            std::fs::read_to_string(test_cfg.workspace.output_dir.join("my_proto.rs")).unwrap();
+        assert_eq!(
+            2,
+            content
+                .matches("let codec = crate::codec::JsonCodec::default();")
+                .count(),
+            "{content}"
+        );
+        assert!(!content.contains("ProstCodec"), "{content}");
+    }
+
+    #[test]
+    fn grpc_web_rejects_client_streaming() {
+        let test_cfg = create_simple_test_cfg(None);
+        let opts = || {
+            test_opts(
+                TonicOpts {
+                    build_client: true,
+                    grpc_web: true,
+                    ..TonicOpts::default()
+                },
+                false,
+                Routine::Generate {
+                    workspace: test_cfg.workspace.clone(),
+                    watch: false,
+                },
+            )
+        };
+        let service = |rpc: &str| {
+            std::fs::write(
+                &test_cfg.workspace.proto_files[0],
+                format!("syntax = \"proto3\";\n\npackage my_proto;\n\nmessage Req {{\n  int32 field = 1;\n}}\n\nservice Greeter {{\n  {rpc}\n}}\n"),
+            )
+            .unwrap();
+        };
+        service("rpc Hello(Req) returns (stream Req);");
+        run_with_opts(opts()).unwrap();
+        service("rpc Hello(stream Req) returns (Req);");
+        run_with_opts(opts()).unwrap_err();
+        assert!(
+            Opts::try_parse_from(["proto-gen", "--grpc-web", "generate", "-o", "out"]).is_err()
+        );
+    }
+
+    #[test]
+    fn services_omitted_without_client_and_server() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_dir = &test_cfg.workspace.proto_dirs[0];
+        std::fs::write(
+            &test_cfg.workspace.proto_files[0],
+            "syntax = \"proto3\";\n\npackage my_proto;\n\n// Kept\nmessage Req {\n  int32 field = 1;\n}\n\nservice Greeter {\n  rpc Hello(Req) returns (Req);\n}\n",
+        )
+        .unwrap();
+        // A package with only services generates nothing
+        let api = proto_dir.join("api.proto");
+        std::fs::write(
+            &api,
+            "syntax = \"proto3\";\n\npackage api;\n\nimport \"my-proto.proto\";\n\nservice Api {\n  rpc Hello(my_proto.Req) returns (my_proto.Req);\n}\n",
+        )
+        .unwrap();
+        let mut workspace = test_cfg.workspace.clone();
+        workspace.proto_files.push(api);
+        run_with_opts(test_opts(
+            TonicOpts::default(),
+            false,
+            Routine::Generate {
+                workspace,
+                watch: false,
+            },
+        ))
+        .unwrap();
+        let output_dir = &test_cfg.workspace.output_dir;
+        let content = std::fs::read_to_string(output_dir.join("my_proto.rs")).unwrap();
+        assert!(content.contains("/// Kept\n"), "{content}");
+        assert!(!content.contains("greeter"), "{content}");
+        assert!(!output_dir.join("api.rs").exists());
+        let mod_file = std::fs::read_to_string(output_dir.with_extension("rs")).unwrap();
+        assert!(mod_file.ends_with("\npub mod my_proto;\n"), "{mod_file}");
+    }
+
+    #[test]
+    fn no_std_uses_btree_maps() {
+        let test_cfg = create_simple_test_cfg(None);
+        let map_proto = test_cfg.workspace.proto_dirs[0].join("my-proto.proto");
+        std::fs::write(
+            &map_proto,
+            "syntax = \"proto3\";\n\npackage my_proto;\n\nmessage Mapped {\n  map<string, int32> entries = 1;\n  bytes data = 2;\n  repeated string names = 3;\n  oneof choice {\n    string text = 4;\n    Mapped nested = 5;\n  }\n}\n",
+        )
+        .unwrap();
+        // Builds the output in a `#![no_std]` crate, so nothing from `std` slips through
+        run_with_opts(Opts {
+            check_compiles: true,
+            ..test_opts(
+                TonicOpts {
+                    no_std: true,
+                    ..test_cfg.tonic.clone()
+                },
+                false,
+                Routine::Generate {
+                    workspace: test_cfg.workspace.clone(),
+                    watch: false,
+                },
+            )
+        })
+        .unwrap();
+        let content =
+            std::fs::read_to_string(test_cfg.workspace.output_dir.join("my_proto.rs")).unwrap();
+        assert!(
+            content.contains("::prost::alloc::collections::BTreeMap<"),
+            "{content}"
+        );
+        assert!(!content.contains("::std::"), "{content}");
+        assert!(Opts::try_parse_from([
+            "proto-gen",
+            "--no-std",
+            "--build-server",
+            "generate",
+            "-o",
+            "out"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn bool_flags_from_env() {
+        let parse = |format: &str, prepend_header: &str| {
+            std::env::set_var("PROTO_GEN_FORMAT", format);
+            std::env::set_var("PROTO_GEN_PREPEND_HEADER", prepend_header);
+            let opts = Opts::try_parse_from(["proto-gen", "validate", "-o", "out"]);
+            std::env::remove_var("PROTO_GEN_FORMAT");
+            std::env::remove_var("PROTO_GEN_PREPEND_HEADER");
+            let opts = opts.unwrap();
+            (opts.format, opts.prepend_header)
+        };
+        assert_eq!((true, false), parse("1", "0"));
+        assert_eq!((true, false), parse("yes", "no"));
+        assert_eq!((false, true), parse("false", "true"));
+    }
+
+    #[test]
+    fn proto_dirs_with_commas_given_as_is() {
+        let opts = Opts::try_parse_from([
+            "proto-gen",
+            "validate",
+            "-d",
+            "protos,v1",
+            "-d",
+            "deps",
+            "-o",
+            "out",
+        ])
+        .unwrap();
+        let Routine::Validate { workspace } = opts.routine else {
+            panic!("Expected validate");
+        };
+        assert_eq!(
+            vec![PathBuf::from("protos,v1"), PathBuf::from("deps")],
+            workspace.proto_dirs
+        );
+    }
+
+    #[test]
+    fn derive_arbitrary_on_targeted_type() {
+        let test_cfg = create_simple_test_cfg(None);
+        run_with_opts(test_opts(
+            TonicOpts {
+                derive_arbitrary: vec![".my_proto.TestMessage".to_string()],
+                ..test_cfg.tonic.clone()
+            },
+            false,
+            Routine::Generate {
+                workspace: test_cfg.workspace.clone(),
+                watch: false,
+            },
+        ))
+        .unwrap();
+        let content =
+            std::fs::read_to_string(test_cfg.workspace.output_dir.join("my_proto.rs")).unwrap();
+        let attribute = "#[cfg_attr(test, derive(arbitrary::Arbitrary))]";
+        assert_eq!(1, content.matches(attribute).count(), "{content}");
+        let (before, _) = content.split_once("pub struct TestMessage").unwrap();
+        let (_, before_test_message) = before.rsplit_once("pub struct").unwrap();
+        assert!(before_test_message.contains(attribute), "{content}");
+    }
+
+    #[test]
+    fn message_attribute_only_on_messages() {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        let shapes_proto = proto_files_dir.join("shapes.proto");
+        std::fs::create_dir_all(&proto_files_dir).unwrap();
+        std::fs::write(
+            &shapes_proto,
+            r#"syntax = "proto3";
+
+package shapes;
+
+enum Kind {
+  KIND_SQUARE = 0;
+}
+
+message Shape {
+  Kind kind = 1;
+}"#,
+        )
+        .unwrap();
+        let proto_types_dir = project_base.path().join("src").join("proto_types");
+        run_with_opts(test_opts(
+            TonicOpts {
+                message_attributes: vec![(".".to_string(), "#[derive(Hash)]".to_string())],
+                ..TonicOpts::default()
+            },
+            false,
+            Routine::Generate {
+                workspace: WorkspaceOpts {
+                    proto_dirs: vec![proto_files_dir],
+                    proto_files: vec![shapes_proto],
+                    output_dir: proto_types_dir.clone(),
+                    ..WorkspaceOpts::default()
+                },
+                watch: false,
+            },
+        ))
+        .unwrap();
+        let content = std::fs::read_to_string(proto_types_dir.join("shapes.rs")).unwrap();
+        assert_eq!(1, content.matches("#[derive(Hash)]").count(), "{content}");
+        let (before_shape, _) = content.split_once("pub struct Shape").unwrap();
+        let (_, shape_attributes) = before_shape.rsplit_once("\n\n").unwrap();
+        assert!(shape_attributes.contains("#[derive(Hash)]"), "{content}");
+    }
+
+    #[test]
+    fn boxed_only_targeted_fields() {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        let sizes_proto = proto_files_dir.join("sizes.proto");
+        std::fs::create_dir_all(&proto_files_dir).unwrap();
+        std::fs::write(
+            &sizes_proto,
+            r#"syntax = "proto3";
+
+package sizes;
+
+message Large {
+  bytes payload = 1;
+}
+
+message Parent {
+  Large boxed_large = 1;
+  Large inline_large = 2;
+}"#,
+        )
+        .unwrap();
+        let proto_types_dir = project_base.path().join("src").join("proto_types");
+        run_with_opts(test_opts(
+            TonicOpts {
+                boxed: vec![".sizes.Parent.boxed_large".to_string()],
+                ..TonicOpts::default()
+            },
+            false,
+            Routine::Generate {
+                workspace: WorkspaceOpts {
+                    proto_dirs: vec![proto_files_dir],
+                    proto_files: vec![sizes_proto],
+                    output_dir: proto_types_dir.clone(),
+                    ..WorkspaceOpts::default()
+                },
+                watch: false,
+            },
+        ))
+        .unwrap();
+        let content = std::fs::read_to_string(proto_types_dir.join("sizes.rs")).unwrap();
+        assert!(
+            content.contains(
+                "pub boxed_large: ::core::option::Option<::prost::alloc::boxed::Box<Large>>,"
+            ),
+            "{content}"
+        );
+        assert!(
+            content.contains("pub inline_large: ::core::option::Option<Large>,"),
+            "{content}"
+        );
+    }
+
+    #[test]
+    fn module_names_are_validated() {
+        assert_eq!(Ok("proto".to_string()), parse_module_name("proto"));
+        assert_eq!(Ok("_v2".to_string()), parse_module_name("_v2"));
+        assert!(parse_module_name("").is_err());
+        assert!(parse_module_name("_").is_err());
+        assert!(parse_module_name("2proto").is_err());
+        assert!(parse_module_name("my-proto").is_err());
+    }
+
+    fn generate_color_enum(tonic: TonicOpts) -> String {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        let color_proto = proto_files_dir.join("color.proto");
+        let color_proto_content = r#"syntax = "proto3";
+
+package colors;
+
+enum Color {
+  COLOR_RED = 0;
+}"#;
+        std::fs::create_dir_all(&proto_files_dir).unwrap();
+        std::fs::write(&color_proto, color_proto_content).unwrap();
+        let proto_types_dir = project_base.path().join("src").join("proto_types");
+        let opts = Opts {
+            prepend_header: false,
+            ..test_opts(
+                tonic,
+                false,
+                Routine::Generate {
+                    workspace: WorkspaceOpts {
+                        proto_dirs: vec![proto_files_dir],
+                        proto_files: vec![color_proto],
+                        tmp_dir: None,
+                        output_dir: proto_types_dir.clone(),
+                        ..WorkspaceOpts::default()
+                    },
+                    watch: false,
+                },
+            )
+        };
+        run_with_opts(opts).unwrap();
+        std::fs::read_to_string(proto_types_dir.join("colors.rs")).unwrap()
+    }
+
+    #[test]
+    fn generate_retains_enum_prefix() {
+        let stripped = generate_color_enum(TonicOpts::default());
+        assert!(stripped.contains("Red = 0"));
+        assert!(!stripped.contains("ColorRed = 0"));
+        let retained = generate_color_enum(TonicOpts {
+            retain_enum_prefix: true,
+            ..TonicOpts::default()
+        });
+        assert!(retained.contains("ColorRed = 0"));
+    }
+
+    #[test]
+    fn generate_minimal_enums() {
+        let full = generate_color_enum(TonicOpts::default());
+        assert!(full.contains("pub fn as_str_name("));
+        let minimal = generate_color_enum(TonicOpts {
+            minimal_enums: vec![".colors.Color".to_string()],
+            ..TonicOpts::default()
+        });
+        assert!(!minimal.contains("as_str_name"));
+        assert!(!minimal.contains("from_str_name"));
+        assert!(!minimal.contains(gen::MINIMAL_ENUM_ATTRIBUTE));
+        assert!(minimal.contains("::prost::Enumeration)]"));
+        assert!(minimal.contains("#[repr(i32)]"));
+        assert!(minimal.contains("Red = 0"));
+    }
+
+    #[test]
+    fn generated_marker_on_top_of_all_files() {
+        let test_cfg = create_simple_test_cfg(None);
+        let proto_types_dir = test_cfg.workspace.output_dir.clone();
+        let opts = Opts {
+            generated_marker: true,
+            ..test_opts(
+                test_cfg.tonic.clone(),
+                false,
+                Routine::Generate {
+                    workspace: test_cfg.workspace.clone(),
+                    watch: false,
+                },
+            )
+        };
+        run_with_opts(opts).unwrap();
+        for file in [
+            proto_types_dir.join("my_proto.rs"),
+            proto_types_dir.with_extension("rs"),
+        ] {
+            let content = std::fs::read_to_string(&file).unwrap();
+            assert!(
+                content.starts_with(&format!("{}// Generated with", gen::GENERATED_MARKER)),
+                "Missing marker in {file:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn module_header_only_on_module_files() {
+        let project_base = tempfile::tempdir().unwrap();
+        let proto_files_dir = project_base.path().join("proto");
+        let my_proto = proto_files_dir.join("nested.proto");
+        let nested_proto_content = r#"syntax = "proto3";
+
+package outer.inner;
+
+message InnerMessage {
+  int32 field = 1;
+}"#;
+        std::fs::create_dir_all(&proto_files_dir).unwrap();
+        std::fs::write(&my_proto, nested_proto_content).unwrap();
+        let module_header_file = project_base.path().join("module-header.txt");
+        std::fs::write(&module_header_file, "// Module header\n").unwrap();
+        let proto_types_dir = project_base.path().join("src").join("proto_types");
+        let opts = Opts {
+            module_header_file: Some(module_header_file),
+            ..test_opts(
+                TonicOpts::default(),
+                false,
+                Routine::Generate {
+                    workspace: WorkspaceOpts {
+                        proto_dirs: vec![proto_files_dir],
+                        proto_files: vec![my_proto],
+                        tmp_dir: None,
+                        output_dir: proto_types_dir.clone(),
+                        ..WorkspaceOpts::default()
+                    },
+                    watch: false,
+                },
+            )
+        };
+        run_with_opts(opts).unwrap();
+        let module_file = std::fs::read_to_string(proto_types_dir.join("outer.rs")).unwrap();
+        assert!(module_file.starts_with("// Module header\npub mod inner;"));
+        let node_file =
+            std::fs::read_to_string(proto_types_dir.join("outer").join("inner.rs")).unwrap();
+        assert!(node_file.starts_with("// Generated with"));
     }
 
     fn assert_exists_not_empty(path: &Path) {
@@ -0,0 +1,76 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cache::collect_with_ext;
+use crate::gen::{module_file, ProtoWorkspace};
+
+/// Every generated file currently on disk, the `.rs` files in each output dir and its module
+/// file, sorted.
+pub(crate) fn output_files(ws: &ProtoWorkspace) -> Result<Vec<PathBuf>, String> {
+    let mut outputs = vec![];
+    let package_output_dirs = ws.package_outputs.iter().map(|(_, dir)| dir);
+    for output_dir in std::iter::once(&ws.output_dir).chain(package_output_dirs) {
+        collect_with_ext(output_dir, "rs", &mut outputs)?;
+        let mod_file = module_file(ws, output_dir)?;
+        if mod_file.is_file() {
+            outputs.push(mod_file);
+        }
+    }
+    outputs.sort();
+    Ok(outputs)
+}
+
+/// Writes the generated files to `manifest`, one per line. Paths are relative to the
+/// directory containing the module file of `--output-dir`, or absolute for package
+/// outputs outside of it.
+pub(crate) fn write(manifest: &Path, ws: &ProtoWorkspace) -> Result<(), String> {
+    let root = ws.output_dir.parent().ok_or_else(|| {
+        format!(
+            "Failed to find parent for output dir {:?} to write manifest",
+            ws.output_dir
+        )
+    })?;
+    let outputs = output_files(ws)?;
+    fs::write(manifest, format_manifest(root, &outputs))
+        .map_err(|e| format!("Failed to write manifest {manifest:?} \n{e}"))
+}
+
+fn format_manifest(root: &Path, outputs: &[PathBuf]) -> String {
+    let mut lines = outputs
+        .iter()
+        .map(|output| {
+            output
+                .strip_prefix(root)
+                .unwrap_or(output)
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect::<Vec<_>>();
+    lines.sort();
+    let mut content = lines.join("\n");
+    if !content.is_empty() {
+        content.push('\n');
+    }
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::manifest::format_manifest;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn paths_relative_to_module_file_dir() {
+        let root = Path::new("/project/src");
+        let outputs = [
+            PathBuf::from("/project/src/proto_types/my_proto.rs"),
+            PathBuf::from("/project/src/proto_types.rs"),
+            PathBuf::from("/elsewhere/imports.rs"),
+        ];
+        assert_eq!(
+            "/elsewhere/imports.rs\nproto_types.rs\nproto_types/my_proto.rs\n",
+            format_manifest(root, &outputs)
+        );
+        assert_eq!("", format_manifest(root, &[]));
+    }
+}
@@ -0,0 +1,329 @@
+//! `OpenAPI` description of the gRPC services in a file descriptor set, for gateways
+//! exposing them as JSON over HTTP
+use std::collections::HashMap;
+
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, FieldDescriptorProto, FileDescriptorSet};
+
+use crate::json::Json;
+
+/// Renders a minimal `OpenAPI` 3 document with each RPC as a `POST /<package>.<Service>/<Method>`
+/// endpoint and a schema per message and enum, following the proto3 JSON mapping
+#[must_use]
+pub fn render(fds: &FileDescriptorSet) -> String {
+    let messages = messages_by_name(fds);
+    let mut paths = vec![];
+    for file in &fds.file {
+        let prefix = package_prefix(file.package());
+        for service in &file.service {
+            for method in &service.method {
+                let mut operation = vec![
+                    (
+                        "operationId",
+                        Json::str(format!("{prefix}{}_{}", service.name(), method.name())),
+                    ),
+                    ("tags", Json::Arr(vec![Json::str(service.name())])),
+                ];
+                if method.client_streaming() || method.server_streaming() {
+                    operation.push((
+                        "description",
+                        Json::str(streaming_description(
+                            method.client_streaming(),
+                            method.server_streaming(),
+                        )),
+                    ));
+                }
+                operation.push((
+                    "requestBody",
+                    Json::obj([
+                        ("required", Json::Bool(true)),
+                        ("content", json_content(method.input_type())),
+                    ]),
+                ));
+                operation.push((
+                    "responses",
+                    Json::obj([(
+                        "200",
+                        Json::obj([
+                            ("description", Json::str("OK")),
+                            ("content", json_content(method.output_type())),
+                        ]),
+                    )]),
+                ));
+                paths.push((
+                    format!("/{prefix}{}/{}", service.name(), method.name()),
+                    Json::obj([("post", Json::obj(operation))]),
+                ));
+            }
+        }
+    }
+    let mut schemas = vec![];
+    for file in &fds.file {
+        let prefix = package_prefix(file.package());
+        for message in &file.message_type {
+            push_message_schemas(&mut schemas, &messages, &prefix, message);
+        }
+        for enum_type in &file.enum_type {
+            schemas.push((
+                format!("{prefix}{}", enum_type.name()),
+                enum_schema(enum_type),
+            ));
+        }
+    }
+    Json::obj([
+        ("openapi", Json::str("3.0.3")),
+        (
+            "info",
+            Json::obj([
+                ("title", Json::str("gRPC services")),
+                ("version", Json::str("0.0.0")),
+            ]),
+        ),
+        ("paths", Json::obj(paths)),
+        ("components", Json::obj([("schemas", Json::obj(schemas))])),
+    ])
+    .pretty()
+}
+
+fn streaming_description(client: bool, server: bool) -> &'static str {
+    match (client, server) {
+        (true, true) => "Bidirectional streaming RPC",
+        (true, false) => "Client streaming RPC",
+        _ => "Server streaming RPC",
+    }
+}
+
+fn json_content(type_name: &str) -> Json {
+    Json::obj([(
+        "application/json",
+        Json::obj([("schema", schema_ref(type_name))]),
+    )])
+}
+
+/// Messages keyed by their fully qualified name with a leading `.`, as used in field types
+pub(crate) fn messages_by_name(fds: &FileDescriptorSet) -> HashMap<String, &DescriptorProto> {
+    fn insert<'a>(
+        messages: &mut HashMap<String, &'a DescriptorProto>,
+        prefix: &str,
+        message: &'a DescriptorProto,
+    ) {
+        let name = format!("{prefix}{}", message.name());
+        for nested in &message.nested_type {
+            insert(messages, &format!("{name}."), nested);
+        }
+        messages.insert(name, message);
+    }
+    let mut messages = HashMap::new();
+    for file in &fds.file {
+        let prefix = format!(".{}", package_prefix(file.package()));
+        for message in &file.message_type {
+            insert(&mut messages, &prefix, message);
+        }
+    }
+    messages
+}
+
+fn push_message_schemas(
+    schemas: &mut Vec<(String, Json)>,
+    messages: &HashMap<String, &DescriptorProto>,
+    prefix: &str,
+    message: &DescriptorProto,
+) {
+    if is_map_entry(message) {
+        return;
+    }
+    let name = format!("{prefix}{}", message.name());
+    let properties = message
+        .field
+        .iter()
+        .map(|field| (json_name(field), field_schema(field, messages, schema_ref)))
+        .collect::<Vec<_>>();
+    schemas.push((
+        name.clone(),
+        Json::obj([
+            ("type", Json::str("object")),
+            ("properties", Json::Obj(properties)),
+        ]),
+    ));
+    let nested_prefix = format!("{name}.");
+    for nested in &message.nested_type {
+        push_message_schemas(schemas, messages, &nested_prefix, nested);
+    }
+    for enum_type in &message.enum_type {
+        schemas.push((
+            format!("{nested_prefix}{}", enum_type.name()),
+            enum_schema(enum_type),
+        ));
+    }
+}
+
+pub(crate) fn is_map_entry(message: &DescriptorProto) -> bool {
+    message
+        .options
+        .as_ref()
+        .is_some_and(prost_types::MessageOptions::map_entry)
+}
+
+fn schema_ref(type_name: &str) -> Json {
+    Json::obj([(
+        "$ref",
+        Json::str(format!(
+            "#/components/schemas/{}",
+            type_name.trim_start_matches('.')
+        )),
+    )])
+}
+
+/// Enums are written as the names of their values
+pub(crate) fn enum_schema(enum_type: &prost_types::EnumDescriptorProto) -> Json {
+    Json::obj([
+        ("type", Json::str("string")),
+        (
+            "enum",
+            Json::Arr(
+                enum_type
+                    .value
+                    .iter()
+                    .map(|value| Json::str(value.name()))
+                    .collect(),
+            ),
+        ),
+    ])
+}
+
+/// Schema of a field, repeated fields become arrays and map fields objects,
+/// references to messages and enums are made by `reference`
+pub(crate) fn field_schema(
+    field: &FieldDescriptorProto,
+    messages: &HashMap<String, &DescriptorProto>,
+    reference: fn(&str) -> Json,
+) -> Json {
+    if field.label() == Label::Repeated {
+        let map_entry = messages
+            .get(field.type_name())
+            .filter(|entry| is_map_entry(entry));
+        if let Some(value) =
+            map_entry.and_then(|entry| entry.field.iter().find(|f| f.number() == 2))
+        {
+            return Json::obj([
+                ("type", Json::str("object")),
+                ("additionalProperties", single_schema(value, reference)),
+            ]);
+        }
+        return Json::obj([
+            ("type", Json::str("array")),
+            ("items", single_schema(field, reference)),
+        ]);
+    }
+    single_schema(field, reference)
+}
+
+fn single_schema(field: &FieldDescriptorProto, reference: fn(&str) -> Json) -> Json {
+    let (json_type, format) = match field.r#type() {
+        Type::Message | Type::Group | Type::Enum => return reference(field.type_name()),
+        Type::Double => ("number", Some("double")),
+        Type::Float => ("number", Some("float")),
+        Type::Int32 | Type::Sint32 | Type::Sfixed32 => ("integer", Some("int32")),
+        Type::Uint32 | Type::Fixed32 => ("integer", Some("uint32")),
+        // 64 bit integers are strings in the proto3 JSON mapping
+        Type::Int64 | Type::Sint64 | Type::Sfixed64 => ("string", Some("int64")),
+        Type::Uint64 | Type::Fixed64 => ("string", Some("uint64")),
+        Type::Bool => ("boolean", None),
+        Type::String => ("string", None),
+        Type::Bytes => ("string", Some("byte")),
+    };
+    let mut schema = vec![("type", Json::str(json_type))];
+    if let Some(format) = format {
+        schema.push(("format", Json::str(format)));
+    }
+    Json::obj(schema)
+}
+
+/// The name of the field in the proto3 JSON mapping, lower camel case unless set explicitly
+pub(crate) fn json_name(field: &FieldDescriptorProto) -> String {
+    if let Some(json_name) = &field.json_name {
+        return json_name.clone();
+    }
+    let mut name = String::with_capacity(field.name().len());
+    let mut upper = false;
+    for c in field.name().chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            name.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            name.push(c);
+        }
+    }
+    name
+}
+
+/// `package.` or an empty prefix for files without a package
+fn package_prefix(package: &str) -> String {
+    if package.is_empty() {
+        String::new()
+    } else {
+        format!("{package}.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::openapi::{json_name, render};
+    use prost_types::field_descriptor_proto::{Label, Type};
+    use prost_types::{
+        DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+        MethodDescriptorProto, ServiceDescriptorProto,
+    };
+
+    #[test]
+    fn renders_services_as_post_endpoints() {
+        let field = |name: &str, r#type: Type, type_name: Option<&str>| FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(1),
+            label: Some(Label::Optional as i32),
+            r#type: Some(r#type as i32),
+            type_name: type_name.map(str::to_string),
+            ..FieldDescriptorProto::default()
+        };
+        let fds = FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                package: Some("my.pkg".to_string()),
+                message_type: vec![
+                    DescriptorProto {
+                        name: Some("Request".to_string()),
+                        field: vec![field("user_id", Type::Int64, None)],
+                        ..DescriptorProto::default()
+                    },
+                    DescriptorProto {
+                        name: Some("Reply".to_string()),
+                        field: vec![field("request", Type::Message, Some(".my.pkg.Request"))],
+                        ..DescriptorProto::default()
+                    },
+                ],
+                service: vec![ServiceDescriptorProto {
+                    name: Some("Greeter".to_string()),
+                    method: vec![MethodDescriptorProto {
+                        name: Some("Greet".to_string()),
+                        input_type: Some(".my.pkg.Request".to_string()),
+                        output_type: Some(".my.pkg.Reply".to_string()),
+                        ..MethodDescriptorProto::default()
+                    }],
+                    ..ServiceDescriptorProto::default()
+                }],
+                ..FileDescriptorProto::default()
+            }],
+        };
+        let spec = render(&fds);
+        assert!(spec.contains(r#""/my.pkg.Greeter/Greet": {"#), "{spec}");
+        assert!(spec.contains(r##""$ref": "#/components/schemas/my.pkg.Request""##));
+        assert!(spec.contains(
+            r#""userId": {
+            "type": "string",
+            "format": "int64"
+          }"#
+        ));
+        assert_eq!("userId", json_name(&field("user_id", Type::Int64, None)));
+    }
+}
@@ -0,0 +1,72 @@
+//! Records the `rustfmt` version the generated code was formatted with, so that formatting
+//! differences from another version are pointed out when validating
+use std::ffi::OsString;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use crate::gen::{paint, GenOptions, ProtoWorkspace, YELLOW};
+
+/// Extension of the file next to the module file of `--output-dir` holding the version
+const VERSION_EXT: &str = ".rustfmt-version";
+
+/// Writes the local `rustfmt` version when generating, otherwise warns if it differs from
+/// the recorded one. `diff` is the number of differences found against the output dir.
+pub(crate) fn pin(ws: &ProtoWorkspace, gen_opts: &GenOptions, diff: usize) -> Result<(), String> {
+    let version = rustfmt_version()?;
+    let path = version_file(ws);
+    if gen_opts.commit {
+        return fs::write(&path, format!("{version}\n"))
+            .map_err(|e| format!("Failed to write rustfmt version to {path:?} \n{e}"));
+    }
+    let recorded = match fs::read_to_string(&path) {
+        Ok(recorded) => recorded,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            if gen_opts.verbosity.is_verbose() {
+                println!("No rustfmt version recorded at {path:?} to compare against");
+            }
+            return Ok(());
+        }
+        Err(e) => return Err(format!("Failed to read rustfmt version {path:?} \n{e}")),
+    };
+    let recorded = recorded.trim();
+    if recorded != version {
+        let cause = if diff > 0 {
+            format!("the {diff} diffs found may be caused by formatting rather than proto changes")
+        } else {
+            "formatting may differ from the committed code".to_string()
+        };
+        eprintln!(
+            "{} the code was generated with {recorded:?} but {version:?} is used here, {cause}",
+            paint(
+                "Warning: rustfmt version mismatch,",
+                YELLOW,
+                gen_opts.color.enabled()
+            )
+        );
+    }
+    Ok(())
+}
+
+/// `<output dir>.rustfmt-version`, next to the module file
+fn version_file(ws: &ProtoWorkspace) -> PathBuf {
+    let mut path = OsString::from(&ws.output_dir);
+    path.push(VERSION_EXT);
+    PathBuf::from(path)
+}
+
+/// The version `rustfmt --version` prints, such as `rustfmt 1.7.0-stable (129f3b99 2024-06-10)`
+fn rustfmt_version() -> Result<String, String> {
+    let out = std::process::Command::new("rustfmt")
+        .arg("--version")
+        .output()
+        .map_err(|e| format!("Failed to get rustfmt version, failed to launch rustfmt \n{e}"))?;
+    if !out.status.success() {
+        return Err(format!(
+            "Failed to get rustfmt version, rustfmt returned error status {} with stderr {:?}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr)
+        ));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
@@ -0,0 +1,304 @@
+//! Serde field attributes for well-known types, which have no serde impls of their own
+use std::collections::BTreeMap;
+
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, FileDescriptorSet};
+
+use crate::openapi::is_map_entry;
+
+/// Module appended to each package with well-known fields, the field attributes point at its
+/// `single`, `optional` and `repeated` modules depending on how prost generates the field.
+/// Timestamps are RFC 3339 strings like `1972-01-01T10:00:20.021Z`, durations ISO 8601
+/// strings in seconds like `PT1.5S`.
+const HELPER_MODULE: &str = r#"/// Serde helpers for well-known types, timestamps as RFC 3339 and durations as ISO 8601 strings
+pub mod serde_well_known {
+    use ::prost::alloc::format;
+    use ::prost::alloc::string::String;
+
+    /// A well-known type serialized as a string
+    pub trait WellKnown: Sized {
+        /// The string this value is serialized as
+        fn to_str(&self) -> String;
+        /// Parses the string this value is serialized as
+        fn parse(value: &str) -> Result<Self, String>;
+    }
+
+    impl WellKnown for ::prost_types::Timestamp {
+        fn to_str(&self) -> String {
+            format!("{self}")
+        }
+
+        fn parse(value: &str) -> Result<Self, String> {
+            value
+                .parse()
+                .map_err(|e| format!("Invalid RFC 3339 timestamp {value:?}: {e}"))
+        }
+    }
+
+    impl WellKnown for ::prost_types::Duration {
+        fn to_str(&self) -> String {
+            let mut duration = self.clone();
+            duration.normalize();
+            let sign = if duration.seconds < 0 || duration.nanos < 0 { "-" } else { "" };
+            let seconds = duration.seconds.unsigned_abs();
+            let nanos = duration.nanos.unsigned_abs();
+            if nanos == 0 {
+                format!("{sign}PT{seconds}S")
+            } else {
+                let fraction = format!("{nanos:09}");
+                format!("{sign}PT{seconds}.{}S", fraction.trim_end_matches('0'))
+            }
+        }
+
+        fn parse(value: &str) -> Result<Self, String> {
+            let (sign, unsigned) = value
+                .strip_prefix('-')
+                .map_or(("", value), |unsigned| ("-", unsigned));
+            let seconds = unsigned
+                .strip_prefix("PT")
+                .and_then(|seconds| seconds.strip_suffix('S'))
+                .ok_or_else(|| {
+                    format!("Invalid ISO 8601 duration {value:?}, expected seconds like PT1.5S")
+                })?;
+            format!("{sign}{seconds}s")
+                .parse()
+                .map_err(|e| format!("Invalid ISO 8601 duration {value:?}: {e}"))
+        }
+    }
+
+    struct AsStr<'a, T>(&'a T);
+
+    impl<T: WellKnown> ::serde::Serialize for AsStr<'_, T> {
+        fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(&self.0.to_str())
+        }
+    }
+
+    struct Parsed<T>(T);
+
+    impl<'de, T: WellKnown> ::serde::Deserialize<'de> for Parsed<T> {
+        fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let value = <String as ::serde::Deserialize>::deserialize(deserializer)?;
+            T::parse(&value).map(Parsed).map_err(::serde::de::Error::custom)
+        }
+    }
+
+    /// Fields in a oneof
+    pub mod single {
+        use super::{AsStr, Parsed, WellKnown};
+
+        pub fn serialize<T: WellKnown, S: ::serde::Serializer>(
+            value: &T,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            ::serde::Serialize::serialize(&AsStr(value), serializer)
+        }
+
+        pub fn deserialize<'de, T: WellKnown, D: ::serde::Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<T, D::Error> {
+            <Parsed<T> as ::serde::Deserialize>::deserialize(deserializer).map(|parsed| parsed.0)
+        }
+    }
+
+    /// Singular message fields, which prost generates as an `Option`
+    pub mod optional {
+        use super::{AsStr, Parsed, WellKnown};
+
+        pub fn serialize<T: WellKnown, S: ::serde::Serializer>(
+            value: &Option<T>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            ::serde::Serialize::serialize(&value.as_ref().map(AsStr), serializer)
+        }
+
+        pub fn deserialize<'de, T: WellKnown, D: ::serde::Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<T>, D::Error> {
+            <Option<Parsed<T>> as ::serde::Deserialize>::deserialize(deserializer)
+                .map(|parsed| parsed.map(|parsed| parsed.0))
+        }
+    }
+
+    /// Repeated fields
+    pub mod repeated {
+        use ::prost::alloc::vec::Vec;
+
+        use super::{AsStr, Parsed, WellKnown};
+
+        pub fn serialize<T: WellKnown, S: ::serde::Serializer>(
+            values: &[T],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(values.iter().map(AsStr))
+        }
+
+        pub fn deserialize<'de, T: WellKnown, D: ::serde::Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<T>, D::Error> {
+            <Vec<Parsed<T>> as ::serde::Deserialize>::deserialize(deserializer)
+                .map(|parsed| parsed.into_iter().map(|parsed| parsed.0).collect())
+        }
+    }
+}
+"#;
+
+/// The well-known types the helper module handles
+const WELL_KNOWN_TYPES: [&str; 2] = [".google.protobuf.Timestamp", ".google.protobuf.Duration"];
+
+/// Prost field attribute paths and the `#[serde(with)]` attributes for every field with a
+/// well-known type, pointing at the helper module of the field's package
+pub(crate) fn field_attributes(fds: &FileDescriptorSet) -> Vec<(String, String)> {
+    let mut attributes = vec![];
+    for file in &fds.file {
+        let prefix = if file.package().is_empty() {
+            String::new()
+        } else {
+            format!(".{}", file.package())
+        };
+        for message in &file.message_type {
+            message_attributes(&mut attributes, &prefix, message, 0);
+        }
+    }
+    attributes
+}
+
+/// Attributes for the fields of `message` and its nested messages, `depth` being how many
+/// modules below the package module the message is
+fn message_attributes(
+    attributes: &mut Vec<(String, String)>,
+    prefix: &str,
+    message: &DescriptorProto,
+    depth: usize,
+) {
+    let message_path = format!("{prefix}.{}", message.name());
+    for field in &message.field {
+        if field.r#type() != Type::Message || !WELL_KNOWN_TYPES.contains(&field.type_name()) {
+            continue;
+        }
+        let oneof = field
+            .oneof_index
+            .filter(|_| !field.proto3_optional())
+            .and_then(|index| message.oneof_decl.get(usize::try_from(index).ok()?));
+        let (path, depth, with) = if let Some(oneof) = oneof {
+            // Oneof variants are in an enum in the message's module
+            let path = format!("{message_path}.{}.{}", oneof.name(), field.name());
+            (path, depth + 1, "single")
+        } else if field.label() == Label::Repeated {
+            (
+                format!("{message_path}.{}", field.name()),
+                depth,
+                "repeated",
+            )
+        } else {
+            (
+                format!("{message_path}.{}", field.name()),
+                depth,
+                "optional",
+            )
+        };
+        let module = if depth == 0 {
+            "self::".to_string()
+        } else {
+            "super::".repeat(depth)
+        };
+        let default = if with == "single" { "" } else { "default, " };
+        attributes.push((
+            path,
+            format!("#[serde({default}with = \"{module}serde_well_known::{with}\")]"),
+        ));
+    }
+    for nested in &message.nested_type {
+        if !is_map_entry(nested) {
+            message_attributes(attributes, &message_path, nested, depth + 1);
+        }
+    }
+}
+
+/// The helper module keyed by each package that has fields with a well-known type
+pub(crate) fn generate(fds: &FileDescriptorSet) -> BTreeMap<String, String> {
+    let mut modules = BTreeMap::new();
+    for file in &fds.file {
+        let mut attributes = vec![];
+        for message in &file.message_type {
+            message_attributes(&mut attributes, "", message, 0);
+        }
+        if !attributes.is_empty() {
+            modules.insert(file.package().to_string(), HELPER_MODULE.to_string());
+        }
+    }
+    modules
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::serde_well_known::message_attributes;
+    use prost_types::field_descriptor_proto::{Label, Type};
+    use prost_types::{DescriptorProto, FieldDescriptorProto, OneofDescriptorProto};
+
+    fn field(
+        name: &str,
+        type_name: &str,
+        label: Label,
+        oneof_index: Option<i32>,
+    ) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            r#type: Some(Type::Message.into()),
+            type_name: Some(type_name.to_string()),
+            label: Some(label.into()),
+            oneof_index,
+            ..FieldDescriptorProto::default()
+        }
+    }
+
+    #[test]
+    fn attributes_point_at_package_helpers() {
+        let message = DescriptorProto {
+            name: Some("Event".to_string()),
+            field: vec![
+                field("at", ".google.protobuf.Timestamp", Label::Optional, None),
+                field("other", ".my.Other", Label::Optional, None),
+                field(
+                    "took",
+                    ".google.protobuf.Duration",
+                    Label::Optional,
+                    Some(0),
+                ),
+            ],
+            nested_type: vec![DescriptorProto {
+                name: Some("Inner".to_string()),
+                field: vec![field(
+                    "laps",
+                    ".google.protobuf.Duration",
+                    Label::Repeated,
+                    None,
+                )],
+                ..DescriptorProto::default()
+            }],
+            oneof_decl: vec![OneofDescriptorProto {
+                name: Some("timing".to_string()),
+                ..OneofDescriptorProto::default()
+            }],
+            ..DescriptorProto::default()
+        };
+        let mut attributes = vec![];
+        message_attributes(&mut attributes, ".my", &message, 0);
+        let expect = [
+            (
+                ".my.Event.at",
+                "#[serde(default, with = \"self::serde_well_known::optional\")]",
+            ),
+            (
+                ".my.Event.timing.took",
+                "#[serde(with = \"super::serde_well_known::single\")]",
+            ),
+            (
+                ".my.Event.Inner.laps",
+                "#[serde(default, with = \"super::serde_well_known::repeated\")]",
+            ),
+        ]
+        .map(|(path, attribute)| (path.to_string(), attribute.to_string()));
+        assert_eq!(expect.to_vec(), attributes);
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use prost_types::FileDescriptorSet;
+
+use crate::gen::{module_ident, ProtoWorkspace};
+use crate::json::Json;
+
+/// Writes a JSON object mapping each generated package file to the proto files declaring its
+/// package, by their names in the file descriptor set. Paths are relative to the directory
+/// containing the module file of `--output-dir` like in the manifest, protos without a
+/// generated file, such as well known types, are left out.
+pub(crate) fn write(
+    source_map: &Path,
+    ws: &ProtoWorkspace,
+    fds: &FileDescriptorSet,
+    wrap_module: Option<&str>,
+    flatten_packages: bool,
+) -> Result<(), String> {
+    let root = ws.output_dir.parent().ok_or_else(|| {
+        format!(
+            "Failed to find parent for output dir {:?} to write source map",
+            ws.output_dir
+        )
+    })?;
+    let mut sources = BTreeMap::<PathBuf, Vec<&str>>::new();
+    for file in &fds.file {
+        let output = package_file(ws, file.package(), wrap_module, flatten_packages);
+        if output.is_file() {
+            sources.entry(output).or_default().push(file.name());
+        }
+    }
+    fs::write(source_map, format_source_map(root, sources).pretty())
+        .map_err(|e| format!("Failed to write source map {source_map:?} \n{e}"))
+}
+
+/// The file generated for `package`, in the output dir it's routed to
+fn package_file(
+    ws: &ProtoWorkspace,
+    package: &str,
+    wrap_module: Option<&str>,
+    flatten_packages: bool,
+) -> PathBuf {
+    let top = package.split('.').next().unwrap_or(package);
+    let mut path = ws
+        .package_outputs
+        .iter()
+        .find(|(routed, _)| routed == top)
+        .map_or(&ws.output_dir, |(_, dir)| dir)
+        .clone();
+    path.extend(wrap_module);
+    let segments = if flatten_packages {
+        package.rsplit('.').take(1).collect::<Vec<_>>()
+    } else {
+        package.split('.').collect()
+    };
+    for segment in segments {
+        let ident = module_ident(segment);
+        path.push(ident.strip_prefix("r#").unwrap_or(&ident));
+    }
+    path.set_extension("rs");
+    path
+}
+
+fn format_source_map(root: &Path, sources: BTreeMap<PathBuf, Vec<&str>>) -> Json {
+    Json::obj(sources.into_iter().map(|(output, mut protos)| {
+        protos.sort_unstable();
+        protos.dedup();
+        let output = output.strip_prefix(root).unwrap_or(&output);
+        (
+            output.to_string_lossy().into_owned(),
+            Json::Arr(protos.into_iter().map(Json::str).collect()),
+        )
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::source_map::format_source_map;
+    use std::collections::BTreeMap;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn outputs_map_to_sorted_protos() {
+        let sources = BTreeMap::from([
+            (
+                PathBuf::from("/project/src/proto_types/my_proto.rs"),
+                vec!["my/b.proto", "my/a.proto"],
+            ),
+            (
+                PathBuf::from("/elsewhere/imports.rs"),
+                vec!["imports.proto"],
+            ),
+        ]);
+        let expect = r#"{
+  "/elsewhere/imports.rs": [
+    "imports.proto"
+  ],
+  "proto_types/my_proto.rs": [
+    "my/a.proto",
+    "my/b.proto"
+  ]
+}
+"#;
+        assert_eq!(
+            expect,
+            format_source_map(Path::new("/project/src"), sources).pretty()
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::gen::{has_ext, Verbosity};
+
+/// Editors often write a file in several steps, wait this long for things to settle
+/// before regenerating.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Watch the given proto files and directories, calling `regenerate` whenever a proto or one
+/// of the watched files, such as an image or a list of proto files, changes.
+/// Only returns on errors setting up the watcher, errors from `regenerate` are printed and
+/// watching continues.
+pub(crate) fn watch_protos(
+    paths: &[PathBuf],
+    verbosity: Verbosity,
+    regenerate: impl Fn() -> Result<(), String>,
+) -> Result<(), String> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)
+        .map_err(|e| format!("Failed to create file watcher \n{e}"))?;
+    for path in paths {
+        let mode = if path.is_dir() {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+        watcher
+            .watch(path, mode)
+            .map_err(|e| format!("Failed to watch {path:?} \n{e}"))?;
+    }
+    if !verbosity.is_quiet() {
+        println!("Watching {} paths for proto changes", paths.len());
+    }
+    // Events may name the files by their absolute path
+    let files = paths
+        .iter()
+        .filter(|path| !path.is_dir())
+        .flat_map(|path| [path.clone(), path.canonicalize().unwrap_or_default()])
+        .collect::<Vec<_>>();
+    while let Ok(event) = rx.recv() {
+        let mut changed = is_proto_change(event, &files);
+        // Drain anything arriving in quick succession so a burst of writes regenerates once
+        while let Ok(event) = rx.recv_timeout(DEBOUNCE) {
+            changed |= is_proto_change(event, &files);
+        }
+        if changed {
+            if !verbosity.is_quiet() {
+                println!("Protos changed, regenerating");
+            }
+            if let Err(err) = regenerate() {
+                eprintln!("Failed to regenerate \n{err}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether the event changed a proto in a watched dir or one of the watched `files`
+fn is_proto_change(event: notify::Result<notify::Event>, files: &[PathBuf]) -> bool {
+    let is_watched = |path: &Path| {
+        has_ext(path, "proto")
+            || files.iter().any(|file| file == path)
+            || path.canonicalize().is_ok_and(|path| files.contains(&path))
+    };
+    match event {
+        Ok(event) => {
+            !matches!(event.kind, EventKind::Access(_))
+                && event.paths.iter().any(|path| is_watched(path))
+        }
+        Err(e) => {
+            eprintln!("Error while watching protos \n{e}");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::watch::is_proto_change;
+    use notify::event::{AccessKind, ModifyKind};
+    use notify::{Event, EventKind};
+    use std::path::PathBuf;
+
+    #[test]
+    fn protos_and_watched_files_are_changes() {
+        let files = [PathBuf::from("image.binpb"), PathBuf::from("protos.txt")];
+        let change = |path: &str| {
+            let event = Event::new(EventKind::Modify(ModifyKind::Any)).add_path(path.into());
+            is_proto_change(Ok(event), &files)
+        };
+        assert!(change("proto/my.proto"));
+        assert!(change("image.binpb"));
+        assert!(change("protos.txt"));
+        assert!(!change("proto/notes.md"));
+        let access = Event::new(EventKind::Access(AccessKind::Any)).add_path("image.binpb".into());
+        assert!(!is_proto_change(Ok(access), &files));
+    }
+}
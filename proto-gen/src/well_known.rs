@@ -0,0 +1,41 @@
+//! The most commonly imported well-known types, for protoc installs that don't ship them
+use std::fs;
+
+use tempfile::TempDir;
+
+/// Paths relative to the include dir, with their content
+const WELL_KNOWN_PROTOS: [(&str, &str); 4] = [
+    (
+        "google/protobuf/duration.proto",
+        include_str!("../well-known/google/protobuf/duration.proto"),
+    ),
+    (
+        "google/protobuf/empty.proto",
+        include_str!("../well-known/google/protobuf/empty.proto"),
+    ),
+    (
+        "google/protobuf/timestamp.proto",
+        include_str!("../well-known/google/protobuf/timestamp.proto"),
+    ),
+    (
+        "google/protobuf/wrappers.proto",
+        include_str!("../well-known/google/protobuf/wrappers.proto"),
+    ),
+];
+
+/// Writes the bundled well-known protos to a temporary include dir
+pub(crate) fn write_includes() -> Result<TempDir, String> {
+    let dir = tempfile::tempdir()
+        .map_err(|e| format!("Failed to create tempdir for well-known includes \n{e}"))?;
+    for (name, content) in WELL_KNOWN_PROTOS {
+        let path = dir.path().join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                format!("Failed to create dir for well-known include {path:?} \n{e}")
+            })?;
+        }
+        fs::write(&path, content)
+            .map_err(|e| format!("Failed to write well-known include {path:?} \n{e}"))?;
+    }
+    Ok(dir)
+}